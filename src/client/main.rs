@@ -1,8 +1,13 @@
-use crustyring::error::Result;
+use std::env;
+
+use crustyring::decode_id;
+use crustyring::error::{Error, Result};
 
 use crustyring::dht::service::DhtNodeService;
 
-use crustyring::rpc::dht::{OperationType, Query};
+use crustyring::rpc::dht::dht_node_client::DhtNodeClient;
+use crustyring::rpc::dht::{Consistency, MigrationLimits, OperationType, Query, QueryResult};
+use crustyring::rpc::registry::Node;
 use rand::Rng;
 use tonic::Request;
 
@@ -10,7 +15,33 @@ use tonic::Request;
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let mut registry_client = DhtNodeService::try_connect_registry().await?;
+    // Node addresses given as arguments are asked for the members of the network
+    // instead of the registry. With --trace the path each query took is printed, and
+    // --consistency sets how many replicas must answer each query.
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let trace = match args.iter().position(|arg| arg == "--trace") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let consistency = match args.iter().position(|arg| arg == "--consistency") {
+        Some(i) if i + 1 < args.len() => {
+            let level = args.remove(i + 1);
+            args.remove(i);
+            parse_consistency(&level)?
+        }
+        Some(_) => return Err(Error::Parse("missing consistency level".into())),
+        None => Consistency::One,
+    };
+    let node_addrs = &args[..];
+
+    let mut registry_client = if node_addrs.is_empty() {
+        Some(DhtNodeService::try_connect_registry().await?)
+    } else {
+        None
+    };
 
     println!("Enter DHT query (Get, Set or Delete).\n  Type exit to quit.");
 
@@ -23,12 +54,30 @@ async fn main() -> Result<()> {
         let words = input.trim().split(' ').collect::<Vec<&str>>();
         let operation = words[0].to_uppercase();
 
-        let nodes = registry_client
-            .get_connected_nodes(Request::new(()))
-            .await?;
-        let nodes = &nodes.get_ref().nodes;
-        let node = &nodes[rand::thread_rng().gen_range(0..nodes.len())];
-        let mut dht = DhtNodeService::try_connect_node(node).await?;
+        let mut dht = match registry_client.as_mut() {
+            Some(registry_client) => {
+                let nodes = registry_client
+                    .get_connected_nodes(Request::new(()))
+                    .await?;
+                let nodes = &nodes.get_ref().nodes;
+                let node = &nodes[rand::thread_rng().gen_range(0..nodes.len())];
+                DhtNodeService::try_connect_node(node).await?
+            }
+            None => {
+                let addr = &node_addrs[rand::thread_rng().gen_range(0..node_addrs.len())];
+                let members = DhtNodeClient::connect(addr.clone())
+                    .await?
+                    .get_members(Request::new(()))
+                    .await?;
+                let members = &members.get_ref().members;
+                let member = &members[rand::thread_rng().gen_range(0..members.len())];
+                DhtNodeService::try_connect_node(&Node {
+                    id: member.id.clone(),
+                    addr: member.addr.clone(),
+                })
+                .await?
+            }
+        };
 
         match &operation[..] {
             "SET" => {
@@ -38,12 +87,27 @@ async fn main() -> Result<()> {
                 }
                 let key = words[1].to_string();
                 let value = words[2].to_string();
+                // A context printed by GET replaces the values it was printed with.
+                let context = match words.get(3) {
+                    Some(context) => match decode_hex(context) {
+                        Ok(context) => Some(context),
+                        Err(err) => {
+                            println!("{}", err);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
                 let request = Request::new(Query {
                     ty: OperationType::Set.into(),
                     key: key.as_bytes().to_vec(),
                     value: Some(value.as_bytes().to_vec()),
+                    trace,
+                    consistency: consistency.into(),
+                    context,
                 });
                 let result = dht.query_dht(request).await?;
+                print_path(result.get_ref());
                 match &result.get_ref().error {
                     Some(err) => {
                         println!("Error: {}", err);
@@ -68,8 +132,12 @@ async fn main() -> Result<()> {
                     ty: OperationType::Delete.into(),
                     key: key.as_bytes().to_vec(),
                     value: None,
+                    trace,
+                    consistency: consistency.into(),
+                    context: None,
                 });
                 let result = dht.query_dht(request).await?;
+                print_path(result.get_ref());
                 match &result.get_ref().error {
                     Some(err) => {
                         println!("Error: {}", err);
@@ -96,22 +164,102 @@ async fn main() -> Result<()> {
                     ty: OperationType::Get.into(),
                     key: key.as_bytes().to_vec(),
                     value: None,
+                    trace,
+                    consistency: consistency.into(),
+                    context: None,
                 });
                 let result = dht.query_dht(request).await?;
+                print_path(result.get_ref());
                 match &result.get_ref().error {
                     Some(err) => {
                         println!("Error: {}", err);
                     }
-                    None => match result.get_ref().value.as_ref() {
-                        Some(val) => {
-                            println!("Value is: {}", String::from_utf8(val.clone()).unwrap())
+                    None => {
+                        let siblings = &result.get_ref().siblings;
+                        match result.get_ref().value.as_ref() {
+                            Some(_) if siblings.len() > 1 => println!(
+                                "Values are: {}",
+                                siblings
+                                    .iter()
+                                    .map(|val| String::from_utf8_lossy(val))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                            Some(val) => {
+                                println!("Value is: {}", String::from_utf8(val.clone()).unwrap())
+                            }
+                            None => println!("Key not present"),
                         }
-                        None => println!("Key not present"),
-                    },
+                        if let Some(context) = &result.get_ref().context {
+                            println!("Context: {}", encode_hex(context));
+                        }
+                    }
                 }
             }
+            "THROTTLE" => {
+                // Limits the rate a node sends keys to other nodes at when they move.
+                if words.len() < 4 {
+                    println!("You must provide a node address, bytes/s and keys/s for THROTTLE.");
+                    continue;
+                }
+                let (Ok(bytes_per_sec), Ok(entries_per_sec)) = (words[2].parse(), words[3].parse())
+                else {
+                    println!("Rates must be numbers, 0 for unlimited.");
+                    continue;
+                };
+                let limits = DhtNodeClient::connect(words[1].to_string())
+                    .await?
+                    .set_migration_limits(Request::new(MigrationLimits {
+                        bytes_per_sec,
+                        entries_per_sec,
+                    }))
+                    .await?;
+                let limits = limits.get_ref();
+                println!(
+                    "Migrating at up to {} bytes/s and {} keys/s",
+                    limits.bytes_per_sec, limits.entries_per_sec
+                );
+            }
             "EXIT" => return Ok(()),
             _ => println!("invalid entry"),
         };
     }
 }
+
+fn parse_consistency(level: &str) -> Result<Consistency> {
+    match level.to_lowercase().as_str() {
+        "one" => Ok(Consistency::One),
+        "quorum" => Ok(Consistency::Quorum),
+        "all" => Ok(Consistency::All),
+        _ => Err(Error::Parse(format!("unknown consistency level {}", level))),
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::Parse(format!("invalid context {}", hex));
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(hex.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+fn print_path(result: &QueryResult) {
+    if result.path.is_empty() {
+        return;
+    }
+    let hops = result
+        .path
+        .iter()
+        .map(|hop| match decode_id(&hop.id) {
+            Ok(id) => format!("#{:x} ({}us)", id, hop.latency_micros),
+            Err(_) => format!("? ({}us)", hop.latency_micros),
+        })
+        .collect::<Vec<_>>();
+    println!("Path: {}", hops.join(" -> "));
+}
@@ -0,0 +1,347 @@
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+use crate::hash::{generate_hash, KeyHash};
+use crate::rpc::dht::MigrationLimits;
+use crate::rpc::registry::ClusterInfo;
+use crate::{HashRing, Placement, RingId};
+
+use super::versioning::Versioning;
+
+/// Number of tokens a node gets per unit of capacity.
+pub const DEFAULT_TOKENS: u32 = 8;
+pub const DEFAULT_CAPACITY: f64 = 1.0;
+/// Number of times a request may be forwarded before it is rejected.
+pub const DEFAULT_MAX_HOPS: u32 = 64;
+/// Number of nodes holding each key, the owner included.
+pub const DEFAULT_REPLICATION: u32 = 1;
+
+/// How the nodes holding a key keep their copies in step, chosen when the cluster is
+/// created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplicationMode {
+    /// The owner applies writes and copies them to the other holders.
+    #[default]
+    Owner,
+    /// The holders of each range form a Raft group, making reads and writes linearizable.
+    Raft,
+    /// Writes go down the chain of holders from the owner, and reads are served by the last
+    /// of them.
+    Chain,
+}
+
+impl ReplicationMode {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "owner" => Ok(ReplicationMode::Owner),
+            "raft" => Ok(ReplicationMode::Raft),
+            "chain" => Ok(ReplicationMode::Chain),
+            _ => Err(Error::Parse(format!("unknown replication mode {}", name))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReplicationMode::Owner => "owner",
+            ReplicationMode::Raft => "raft",
+            ReplicationMode::Chain => "chain",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Tokens per unit of capacity (`NODE_TOKENS`).
+    pub tokens: u32,
+    /// Capacity weight of the node relative to the others (`NODE_CAPACITY`).
+    pub capacity: f64,
+    /// Id requested by the operator (`NODE_ID`, in hex) or derived from a stable name
+    /// (`NODE_NAME`).
+    pub id: Option<RingId>,
+    /// File the node's id is kept in across restarts (`NODE_ID_FILE`).
+    pub id_file: Option<PathBuf>,
+    /// File the writes kept for unreachable replicas are persisted to (`NODE_HINTS_FILE`).
+    pub hints_file: Option<PathBuf>,
+    /// File the Raft groups of the node record their terms, votes and logs to
+    /// (`NODE_RAFT_FILE`), required by Raft replication.
+    pub raft_file: Option<PathBuf>,
+    /// Hops a request may take before it is rejected (`NODE_MAX_HOPS`).
+    pub max_hops: u32,
+    /// Hash function placing keys on the ring (`NODE_KEY_HASH`, with the SipHash cluster
+    /// key in `NODE_HASH_KEY`).
+    pub key_hash: KeyHash,
+    /// How keys are assigned to nodes (`NODE_PLACEMENT`).
+    pub placement: Placement,
+    /// Number of nodes holding each key, the owner included (`NODE_REPLICATION`).
+    pub replication: u32,
+    /// How writes to the same key are ordered (`NODE_VERSIONING`).
+    pub versioning: Versioning,
+    /// How the holders of a key keep their copies in step (`NODE_REPLICATION_MODE`).
+    pub replication_mode: ReplicationMode,
+    /// Rates keys are migrated to other nodes at, 0 for unlimited
+    /// (`NODE_MIGRATION_BYTES_PER_SEC` and `NODE_MIGRATION_ENTRIES_PER_SEC`).
+    pub migration_limits: MigrationLimits,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            tokens: DEFAULT_TOKENS,
+            capacity: DEFAULT_CAPACITY,
+            id: None,
+            id_file: None,
+            hints_file: None,
+            raft_file: None,
+            max_hops: DEFAULT_MAX_HOPS,
+            key_hash: KeyHash::default(),
+            placement: Placement::default(),
+            replication: DEFAULT_REPLICATION,
+            versioning: Versioning::default(),
+            replication_mode: ReplicationMode::default(),
+            migration_limits: MigrationLimits::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn from_env() -> Result<Self> {
+        let mut config = NodeConfig::default();
+
+        if let Ok(tokens) = std::env::var("NODE_TOKENS") {
+            config.tokens = tokens
+                .parse()
+                .map_err(|_| Error::Parse(format!("invalid NODE_TOKENS value {}", tokens)))?;
+        }
+        if let Ok(capacity) = std::env::var("NODE_CAPACITY") {
+            config.capacity = capacity
+                .parse()
+                .map_err(|_| Error::Parse(format!("invalid NODE_CAPACITY value {}", capacity)))?;
+        }
+        if let Ok(max_hops) = std::env::var("NODE_MAX_HOPS") {
+            config.max_hops = max_hops
+                .parse()
+                .map_err(|_| Error::Parse(format!("invalid NODE_MAX_HOPS value {}", max_hops)))?;
+        }
+        if config.capacity <= 0.0 {
+            return Err(Error::Config("NODE_CAPACITY must be positive".into()));
+        }
+        if let Ok(id) = std::env::var("NODE_ID") {
+            config.id = Some(parse_id(&id)?);
+        } else if let Ok(name) = std::env::var("NODE_NAME") {
+            config.id = Some(generate_hash(name.as_bytes())?);
+        }
+        config.id_file = std::env::var("NODE_ID_FILE").ok().map(PathBuf::from);
+        config.hints_file = std::env::var("NODE_HINTS_FILE").ok().map(PathBuf::from);
+        config.raft_file = std::env::var("NODE_RAFT_FILE").ok().map(PathBuf::from);
+        if let Ok(key_hash) = std::env::var("NODE_KEY_HASH") {
+            let key = std::env::var("NODE_HASH_KEY").ok();
+            config.key_hash = KeyHash::parse(&key_hash, key.as_deref())?;
+        }
+        if let Ok(placement) = std::env::var("NODE_PLACEMENT") {
+            config.placement = Placement::parse(&placement)?;
+        }
+        if let Ok(replication) = std::env::var("NODE_REPLICATION") {
+            config.replication = replication.parse().map_err(|_| {
+                Error::Parse(format!("invalid NODE_REPLICATION value {}", replication))
+            })?;
+        }
+        if config.replication == 0 {
+            return Err(Error::Config("NODE_REPLICATION must be positive".into()));
+        }
+        if let Ok(versioning) = std::env::var("NODE_VERSIONING") {
+            config.versioning = Versioning::parse(&versioning)?;
+        }
+        if let Ok(mode) = std::env::var("NODE_REPLICATION_MODE") {
+            config.replication_mode = ReplicationMode::parse(&mode)?;
+        }
+        if let Ok(bytes) = std::env::var("NODE_MIGRATION_BYTES_PER_SEC") {
+            config.migration_limits.bytes_per_sec = bytes.parse().map_err(|_| {
+                Error::Parse(format!(
+                    "invalid NODE_MIGRATION_BYTES_PER_SEC value {}",
+                    bytes
+                ))
+            })?;
+        }
+        if let Ok(entries) = std::env::var("NODE_MIGRATION_ENTRIES_PER_SEC") {
+            config.migration_limits.entries_per_sec = entries.parse().map_err(|_| {
+                Error::Parse(format!(
+                    "invalid NODE_MIGRATION_ENTRIES_PER_SEC value {}",
+                    entries
+                ))
+            })?;
+        }
+        if config.replication_mode == ReplicationMode::Chain && config.placement != Placement::Ring
+        {
+            return Err(Error::Config(
+                "Chain replication requires ring placement.".into(),
+            ));
+        }
+        if config.replication_mode == ReplicationMode::Raft {
+            if config.placement != Placement::Ring {
+                return Err(Error::Config(
+                    "Raft replication requires ring placement.".into(),
+                ));
+            }
+            if config.versioning != Versioning::Timestamp {
+                return Err(Error::Config(
+                    "Raft replication requires timestamp versioning.".into(),
+                ));
+            }
+            // Members forgetting their votes and logs on restart could elect two leaders.
+            if config.raft_file.is_none() {
+                return Err(Error::Config(
+                    "Raft replication requires NODE_RAFT_FILE.".into(),
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the id the node should take, if it has one: the requested id,
+    /// or else the one stored in the id file from a previous run.
+    pub fn identity(&self) -> Result<Option<RingId>> {
+        if self.id.is_some() {
+            return Ok(self.id);
+        }
+        match self.read_id_file()? {
+            Some(contents) => Ok(Some(parse_id(contents.lines().next().unwrap_or(""))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the tokens stored in the id file from a previous run, which the node keeps
+    /// so that tokens moved by the rebalancer stay where they were moved to. They are only
+    /// kept as long as there are as many as the node is configured to have.
+    pub fn persisted_tokens(&self) -> Result<Option<Vec<RingId>>> {
+        let tokens = match self.read_id_file()? {
+            Some(contents) => match contents.lines().nth(1) {
+                Some(line) => line
+                    .split_whitespace()
+                    .map(parse_id)
+                    .collect::<Result<Vec<_>>>()?,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let count = HashRing::weighted_tokens(self.tokens.max(1), self.capacity) as usize;
+        Ok(Some(tokens).filter(|tokens| tokens.len() == count))
+    }
+
+    fn read_id_file(&self) -> Result<Option<String>> {
+        match &self.id_file {
+            Some(path) if path.exists() => Ok(Some(std::fs::read_to_string(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the settings the node needs the rest of the cluster to share.
+    pub fn cluster_info(&self) -> Result<ClusterInfo> {
+        Ok(ClusterInfo {
+            ring_bits: RingId::BITS,
+            key_hash: self.key_hash.name().into(),
+            key_hash_fingerprint: self.key_hash.fingerprint()?,
+            placement: self.placement.name().into(),
+            replication: self.replication,
+            versioning: self.versioning.name().into(),
+            replication_mode: self.replication_mode.name().into(),
+        })
+    }
+
+    /// Stores the id the node was given, followed by its tokens, in the id file if there
+    /// is one.
+    pub fn persist_identity(&self, id: RingId, tokens: &[RingId]) -> Result<()> {
+        if let Some(path) = &self.id_file {
+            let tokens: Vec<String> = tokens.iter().map(|token| format!("{:x}", token)).collect();
+            std::fs::write(path, format!("{:x}\n{}\n", id, tokens.join(" ")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless the node's cluster settings match those of the cluster it joins.
+pub fn check_cluster_info(node: &ClusterInfo, cluster: &ClusterInfo) -> Result<()> {
+    if node.ring_bits != cluster.ring_bits {
+        return Err(Error::Config(format!(
+            "Ring is {} bits wide but the cluster's is {} bits wide.",
+            node.ring_bits, cluster.ring_bits
+        )));
+    }
+    if node.key_hash != cluster.key_hash {
+        return Err(Error::Config(format!(
+            "Keys are hashed with {} but the cluster hashes them with {}.",
+            node.key_hash, cluster.key_hash
+        )));
+    }
+    if node.key_hash_fingerprint != cluster.key_hash_fingerprint {
+        return Err(Error::Config("Hash key differs from the cluster's.".into()));
+    }
+    if node.placement != cluster.placement {
+        return Err(Error::Config(format!(
+            "Keys are placed by {} but the cluster places them by {}.",
+            node.placement, cluster.placement
+        )));
+    }
+    if node.replication != cluster.replication {
+        return Err(Error::Config(format!(
+            "Keys are replicated {} times but the cluster replicates them {} times.",
+            node.replication, cluster.replication
+        )));
+    }
+    if node.versioning != cluster.versioning {
+        return Err(Error::Config(format!(
+            "Writes are versioned by {} but the cluster versions them by {}.",
+            node.versioning, cluster.versioning
+        )));
+    }
+    if node.replication_mode != cluster.replication_mode {
+        return Err(Error::Config(format!(
+            "Keys are replicated in {} mode but the cluster replicates them in {} mode.",
+            node.replication_mode, cluster.replication_mode
+        )));
+    }
+    Ok(())
+}
+
+fn parse_id(id: &str) -> Result<RingId> {
+    let trimmed = id.trim();
+    let digits = trimmed.strip_prefix("0x").unwrap_or(trimmed);
+    RingId::from_str_radix(digits, 16)
+        .map_err(|_| Error::Parse(format!("invalid node id {}", trimmed)))
+}
+
+#[test]
+fn test_parse_id() -> Result<()> {
+    assert_eq!(parse_id("0x69")?, 0x69);
+    assert_eq!(parse_id("ff\n")?, 0xff);
+    assert!(parse_id("node").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_identity_from_id_file() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("crustyring-id-{}", std::process::id()));
+    let config = NodeConfig {
+        id_file: Some(path.clone()),
+        ..NodeConfig::default()
+    };
+    assert_eq!(config.identity()?, None);
+    assert_eq!(config.persisted_tokens()?, None);
+
+    let tokens: Vec<RingId> = (0..DEFAULT_TOKENS as RingId).map(|i| i << 8).collect();
+    config.persist_identity(0xdead, &tokens)?;
+    let identity = config.identity();
+    let persisted = config.persisted_tokens();
+    // Tokens are regenerated if the node is configured with a different number of them.
+    let other = NodeConfig {
+        tokens: 2,
+        ..config.clone()
+    }
+    .persisted_tokens();
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(identity?, Some(0xdead));
+    assert_eq!(persisted?, Some(tokens));
+    assert_eq!(other?, None);
+    Ok(())
+}
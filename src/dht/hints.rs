@@ -0,0 +1,180 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use log::warn;
+use prost::Message;
+use tokio::sync::Mutex;
+
+use crate::error::Result;
+use crate::rpc::dht::{Hint, ReplicaEntry};
+use crate::{decode_id, encode_id, RingId};
+
+/// Writes that could not be applied to a replica, kept in order until it can be reached
+/// again. If a file is given they are logged to it, surviving restarts of the node.
+#[derive(Debug, Default)]
+pub struct Hints {
+    hints: Mutex<Vec<Hint>>,
+    file: Option<PathBuf>,
+}
+
+/// Drops the `count` oldest hints kept for the target, returning how many were dropped.
+fn drop_oldest(hints: &mut Vec<Hint>, target: &[u8], count: usize) -> usize {
+    let mut removed = 0;
+    hints.retain(|hint| {
+        if removed < count && hint.target == target {
+            removed += 1;
+            return false;
+        }
+        true
+    });
+    removed
+}
+
+impl Hints {
+    /// Loads the hints logged to the file, if there is one, and compacts it to only hold
+    /// the hints still kept.
+    pub fn load(file: Option<PathBuf>) -> Result<Self> {
+        let mut hints = Vec::new();
+        if let Some(path) = file.as_ref().filter(|path| path.exists()) {
+            let bytes = std::fs::read(path)?;
+            let mut buf = bytes.as_slice();
+            while !buf.is_empty() {
+                match Hint::decode_length_delimited(&mut buf) {
+                    Ok(hint) if hint.entry.is_some() => hints.push(hint),
+                    Ok(hint) => {
+                        drop_oldest(&mut hints, &hint.target, hint.removed as usize);
+                    }
+                    // A record cut short by a crash while it was appended.
+                    Err(err) => {
+                        warn!("Ignoring the end of the hints file: {}", err);
+                        break;
+                    }
+                }
+            }
+
+            let compacted: Vec<u8> = hints
+                .iter()
+                .flat_map(|hint| hint.encode_length_delimited_to_vec())
+                .collect();
+            std::fs::write(path, compacted)?;
+        }
+
+        Ok(Hints {
+            hints: Mutex::new(hints),
+            file,
+        })
+    }
+
+    pub async fn add(&self, target: RingId, entry: ReplicaEntry) -> Result<()> {
+        let hint = Hint {
+            target: encode_id(target),
+            entry: Some(entry),
+            removed: 0,
+        };
+        let mut hints = self.hints.lock().await;
+        self.append(&hint, false).await?;
+        hints.push(hint);
+        Ok(())
+    }
+
+    /// Returns the replicas hints are kept for.
+    pub async fn targets(&self) -> Vec<RingId> {
+        let mut targets: Vec<RingId> = self
+            .hints
+            .lock()
+            .await
+            .iter()
+            .filter_map(|hint| decode_id(&hint.target).ok())
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+
+    /// Returns the writes kept for the replica, oldest first.
+    pub async fn get(&self, target: RingId) -> Vec<ReplicaEntry> {
+        let target = encode_id(target);
+        self.hints
+            .lock()
+            .await
+            .iter()
+            .filter(|hint| hint.target == target)
+            .filter_map(|hint| hint.entry.clone())
+            .collect()
+    }
+
+    /// Drops the `count` oldest writes kept for the replica, once they were applied or
+    /// are no longer needed.
+    pub async fn remove(&self, target: RingId, count: usize) -> Result<()> {
+        let target = encode_id(target);
+        let mut hints = self.hints.lock().await;
+        let removed = drop_oldest(&mut hints, &target, count);
+        let record = Hint {
+            target,
+            entry: None,
+            removed: removed as u32,
+        };
+        // Once no hint is left, the log is emptied instead of growing.
+        self.append(&record, hints.is_empty()).await
+    }
+
+    /// Appends the record to the file off the runtime's threads, or truncates the file if
+    /// `truncate` is set.
+    async fn append(&self, record: &Hint, truncate: bool) -> Result<()> {
+        let path = match &self.file {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let bytes = record.encode_length_delimited_to_vec();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            if truncate {
+                std::fs::write(&path, [])?;
+                return Ok(());
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            file.write_all(&bytes)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[tokio::test]
+async fn test_hints_persisted() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("crustyring-hints-{}", std::process::id()));
+    let entry = |key: RingId, value: Option<&[u8]>| ReplicaEntry {
+        key: encode_id(key),
+        value: value.map(|value| value.to_vec()),
+        version: 0,
+        deleted: value.is_none(),
+    };
+
+    let hints = Hints::load(Some(path.clone()))?;
+    hints.add(1, entry(10, Some(b"a"))).await?;
+    hints.add(2, entry(20, Some(b"b"))).await?;
+    hints.add(1, entry(10, None)).await?;
+    hints.remove(1, 1).await?;
+
+    let loaded = Hints::load(Some(path.clone()));
+    let compacted = std::fs::metadata(&path)?.len();
+    let loaded = loaded?;
+    let (targets, first, second) = (
+        loaded.targets().await,
+        loaded.get(1).await,
+        loaded.get(2).await,
+    );
+    loaded.remove(1, 1).await?;
+    loaded.remove(2, 1).await?;
+    let emptied = std::fs::metadata(&path)?.len();
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(targets, vec![1, 2]);
+    assert_eq!(first, vec![entry(10, None)]);
+    assert_eq!(second, vec![entry(20, Some(b"b"))]);
+    assert!(compacted > 0);
+    assert_eq!(emptied, 0);
+    Ok(())
+}
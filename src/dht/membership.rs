@@ -0,0 +1,593 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use rand::seq::SliceRandom;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Request;
+
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::{Gossip, IndirectPing, Member, MemberState};
+use crate::rpc::registry::Node;
+use crate::{decode_id, encode_id, encode_ids, RingId};
+
+/// Interval between two probes of the failure detector.
+const PROTOCOL_PERIOD: Duration = Duration::from_secs(1);
+/// Time to wait for a ping to be acknowledged.
+const PING_TIMEOUT: Duration = Duration::from_millis(300);
+/// Number of members asked to probe a target that did not acknowledge a direct ping.
+const INDIRECT_PINGS: usize = 3;
+/// Time a member stays suspected before it is declared dead.
+const SUSPICION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Multiplier of the log of the cluster size giving how many times an update is gossiped.
+const RETRANSMIT_MULT: usize = 3;
+/// Maximum number of updates piggybacked on a single message.
+const MAX_PIGGYBACK: usize = 8;
+
+#[derive(Debug)]
+struct MemberEntry {
+    member: Member,
+    suspected_at: Option<Instant>,
+    client: Option<DhtNodeClient<Channel>>,
+}
+
+#[derive(Debug)]
+struct Broadcast {
+    member: Member,
+    transmissions: usize,
+}
+
+/// SWIM-style membership: members are probed periodically to detect failures, and joins,
+/// leaves and suspicions are disseminated by piggybacking them on the probe messages.
+#[derive(Debug)]
+pub struct Membership {
+    this: RwLock<Member>,
+    members: RwLock<HashMap<RingId, MemberEntry>>,
+    broadcasts: Mutex<Vec<Broadcast>>,
+    probe_order: Mutex<Vec<RingId>>,
+}
+
+fn state(member: &Member) -> MemberState {
+    MemberState::from_i32(member.state).unwrap_or(MemberState::Alive)
+}
+
+fn is_active(member: &Member) -> bool {
+    matches!(state(member), MemberState::Alive | MemberState::Suspect)
+}
+
+/// Whether an update about a member should replace what is currently known about it.
+fn overrides(update: &Member, current: &Member) -> bool {
+    match (state(update), state(current)) {
+        (update_state, MemberState::Dead | MemberState::Left) => {
+            update_state == MemberState::Alive && update.incarnation > current.incarnation
+        }
+        (MemberState::Alive, _) => update.incarnation > current.incarnation,
+        (MemberState::Suspect, MemberState::Alive) => update.incarnation >= current.incarnation,
+        (MemberState::Suspect, MemberState::Suspect) => update.incarnation > current.incarnation,
+        (MemberState::Dead | MemberState::Left, _) => update.incarnation >= current.incarnation,
+    }
+}
+
+impl Membership {
+    /// Starts the membership of a node. Its incarnation starts at the time it starts at, in
+    /// milliseconds, so a node coming back after it was declared dead or left overrides that.
+    pub fn new(node: &Node, tokens: Vec<RingId>, capacity: f64) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let this = Member {
+            id: node.id.clone(),
+            addr: node.addr.clone(),
+            state: MemberState::Alive.into(),
+            incarnation: started.as_millis() as u64,
+            tokens: encode_ids(&tokens),
+            capacity,
+        };
+
+        Membership {
+            this: RwLock::new(this.clone()),
+            members: RwLock::new(HashMap::new()),
+            broadcasts: Mutex::new(vec![Broadcast {
+                member: this,
+                transmissions: 0,
+            }]),
+            probe_order: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns this node as seen by the other members.
+    pub async fn this(&self) -> Member {
+        self.this.read().await.clone()
+    }
+
+    /// Updates the tokens this node owns, gossiping them with a higher incarnation
+    /// so they override what other members know.
+    pub async fn set_tokens(&self, tokens: Vec<RingId>) {
+        let this = {
+            let mut this = self.this.write().await;
+            this.tokens = encode_ids(&tokens);
+            this.incarnation += 1;
+            this.clone()
+        };
+        self.broadcast(this).await;
+    }
+
+    /// Returns what is known about a member, whatever its state.
+    pub async fn member(&self, id: RingId) -> Option<Member> {
+        self.members
+            .read()
+            .await
+            .get(&id)
+            .map(|entry| entry.member.clone())
+    }
+
+    /// Returns every member believed to be in the network, this node included.
+    pub async fn members(&self) -> Vec<Member> {
+        let mut members = vec![self.this.read().await.clone()];
+        members.extend(
+            self.members
+                .read()
+                .await
+                .values()
+                .filter(|entry| is_active(&entry.member))
+                .map(|entry| entry.member.clone()),
+        );
+        members
+    }
+
+    /// Returns the members not suspected of having failed, this node included.
+    pub async fn alive_members(&self) -> Vec<Member> {
+        let mut members = vec![self.this.read().await.clone()];
+        members.extend(
+            self.members
+                .read()
+                .await
+                .values()
+                .filter(|entry| state(&entry.member) == MemberState::Alive)
+                .map(|entry| entry.member.clone()),
+        );
+        members
+    }
+
+    /// Applies updates received from other members, disseminating the ones that were news.
+    pub async fn merge(&self, updates: Vec<Member>) {
+        for update in updates {
+            if update.id == self.this.read().await.id {
+                self.refute(&update).await;
+                continue;
+            }
+            let id = match decode_id(&update.id) {
+                Ok(id) => id,
+                Err(err) => {
+                    warn!("Ignoring update about member {}: {}", update.addr, err);
+                    continue;
+                }
+            };
+
+            let mut members = self.members.write().await;
+            let changed = match members.get_mut(&id) {
+                Some(entry) if overrides(&update, &entry.member) => {
+                    entry.suspected_at = match state(&update) {
+                        MemberState::Suspect => Some(Instant::now()),
+                        _ => None,
+                    };
+                    if !is_active(&update) {
+                        entry.client = None;
+                    }
+                    entry.member = update.clone();
+                    true
+                }
+                Some(_) => false,
+                None => {
+                    members.insert(
+                        id,
+                        MemberEntry {
+                            member: update.clone(),
+                            suspected_at: match state(&update) {
+                                MemberState::Suspect => Some(Instant::now()),
+                                _ => None,
+                            },
+                            client: None,
+                        },
+                    );
+                    true
+                }
+            };
+            drop(members);
+
+            if changed {
+                info!(
+                    "Member #{:x} is {:?} (incarnation {})",
+                    id,
+                    state(&update),
+                    update.incarnation
+                );
+                self.broadcast(update).await;
+            }
+        }
+    }
+
+    /// Answers suspicions or death declarations about this node by gossiping
+    /// that it is alive with a higher incarnation.
+    async fn refute(&self, update: &Member) {
+        let mut this = self.this.write().await;
+        if state(&this) == MemberState::Left || state(update) == MemberState::Alive {
+            return;
+        }
+        if update.incarnation >= this.incarnation {
+            this.incarnation = update.incarnation + 1;
+            warn!(
+                "Refuting {:?} state with incarnation {}",
+                state(update),
+                this.incarnation
+            );
+            let this = this.clone();
+            self.broadcast(this).await;
+        }
+    }
+
+    async fn broadcast(&self, member: Member) {
+        let mut broadcasts = self.broadcasts.lock().await;
+        broadcasts.retain(|broadcast| broadcast.member.id != member.id);
+        broadcasts.push(Broadcast {
+            member,
+            transmissions: 0,
+        });
+    }
+
+    /// Takes the updates to piggyback on an outgoing message, least gossiped first.
+    async fn piggyback(&self) -> Vec<Member> {
+        let cluster_size = self.members.read().await.len() + 1;
+        let limit = RETRANSMIT_MULT * ((cluster_size + 1) as f64).log2().ceil() as usize;
+
+        let mut broadcasts = self.broadcasts.lock().await;
+        broadcasts.sort_by_key(|broadcast| broadcast.transmissions);
+
+        let updates = broadcasts
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|broadcast| {
+                broadcast.transmissions += 1;
+                broadcast.member.clone()
+            })
+            .collect();
+        broadcasts.retain(|broadcast| broadcast.transmissions < limit);
+
+        updates
+    }
+
+    async fn gossip(&self) -> Gossip {
+        let sender = self.this.read().await.clone();
+        Gossip {
+            sender: Some(sender),
+            updates: self.piggyback().await,
+        }
+    }
+
+    /// Handles a direct ping, returning the acknowledgement with this node's own updates.
+    pub async fn handle_ping(&self, gossip: Gossip) -> Gossip {
+        let mut updates = gossip.updates;
+        if let Some(sender) = gossip.sender {
+            let members = self.members.read().await;
+            let known = decode_id(&sender.id).is_ok_and(|id| members.contains_key(&id));
+            drop(members);
+            if !known {
+                updates.push(sender);
+            }
+        }
+        self.merge(updates).await;
+
+        self.gossip().await
+    }
+
+    /// Handles a request to probe a target on behalf of a member that could not reach it.
+    pub async fn handle_ping_req(&self, request: IndirectPing) -> Result<Gossip> {
+        if let Some(gossip) = request.gossip {
+            self.merge(gossip.updates).await;
+        }
+        let target = request
+            .target
+            .ok_or(Error::Value("Ping target not provided.".into()))?;
+
+        let gossip = self.ping(&target).await?;
+        self.merge(gossip.updates).await;
+
+        Ok(self.gossip().await)
+    }
+
+    pub async fn client(&self, node: &Node) -> Result<DhtNodeClient<Channel>> {
+        let id = decode_id(&node.id)?;
+        if let Some(client) = self
+            .members
+            .read()
+            .await
+            .get(&id)
+            .and_then(|entry| entry.client.clone())
+        {
+            return Ok(client);
+        }
+
+        let channel = Endpoint::from_shared(node.addr.clone())?.connect_lazy();
+        let client = DhtNodeClient::new(channel);
+        if let Some(entry) = self.members.write().await.get_mut(&id) {
+            entry.client = Some(client.clone());
+        }
+        Ok(client)
+    }
+
+    async fn ping(&self, target: &Node) -> Result<Gossip> {
+        let mut client = self.client(target).await?;
+        let gossip = self.gossip().await;
+
+        match tokio::time::timeout(PING_TIMEOUT, client.ping(Request::new(gossip))).await {
+            Ok(response) => Ok(response?.into_inner()),
+            Err(_) => Err(Error::Internal(format!(
+                "Ping to #{:x} timed out.",
+                decode_id(&target.id)?
+            ))),
+        }
+    }
+
+    /// Picks the next member to probe, going round-robin over a shuffled member list.
+    async fn next_target(&self) -> Option<Node> {
+        let members = self.members.read().await;
+        let mut probe_order = self.probe_order.lock().await;
+
+        loop {
+            let id = match probe_order.pop() {
+                Some(id) => id,
+                None => {
+                    *probe_order = members
+                        .values()
+                        .filter(|entry| is_active(&entry.member))
+                        .filter_map(|entry| decode_id(&entry.member.id).ok())
+                        .collect();
+                    if probe_order.is_empty() {
+                        return None;
+                    }
+                    probe_order.shuffle(&mut rand::thread_rng());
+                    continue;
+                }
+            };
+
+            if let Some(entry) = members.get(&id).filter(|entry| is_active(&entry.member)) {
+                return Some(Node {
+                    id: encode_id(id),
+                    addr: entry.member.addr.clone(),
+                });
+            }
+        }
+    }
+
+    async fn probe(&self) {
+        let target = match self.next_target().await {
+            Some(target) => target,
+            None => return,
+        };
+
+        let gossip = match self.ping(&target).await {
+            Ok(gossip) => Some(gossip),
+            Err(_) => self.ping_indirectly(&target).await,
+        };
+
+        match gossip {
+            Some(gossip) => self.merge(gossip.updates).await,
+            None => self.suspect(&target).await,
+        }
+    }
+
+    async fn ping_indirectly(&self, target: &Node) -> Option<Gossip> {
+        let mut helpers: Vec<Node> = self
+            .members
+            .read()
+            .await
+            .values()
+            .filter(|entry| {
+                entry.member.id != target.id && state(&entry.member) == MemberState::Alive
+            })
+            .map(|entry| Node {
+                id: entry.member.id.clone(),
+                addr: entry.member.addr.clone(),
+            })
+            .collect();
+        helpers.shuffle(&mut rand::thread_rng());
+        helpers.truncate(INDIRECT_PINGS);
+
+        let mut requests = JoinSet::new();
+        for helper in helpers {
+            let mut client = match self.client(&helper).await {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            let request = IndirectPing {
+                target: Some(target.clone()),
+                gossip: Some(self.gossip().await),
+            };
+            requests.spawn(async move {
+                tokio::time::timeout(PING_TIMEOUT * 2, client.ping_req(Request::new(request))).await
+            });
+        }
+
+        while let Some(response) = requests.join_next().await {
+            if let Ok(Ok(Ok(gossip))) = response {
+                return Some(gossip.into_inner());
+            }
+        }
+        None
+    }
+
+    async fn suspect(&self, target: &Node) {
+        let id = match decode_id(&target.id) {
+            Ok(id) => id,
+            Err(_) => return,
+        };
+        let member = self
+            .members
+            .read()
+            .await
+            .get(&id)
+            .map(|entry| entry.member.clone());
+
+        if let Some(member) = member.filter(|member| state(member) == MemberState::Alive) {
+            self.merge(vec![Member {
+                state: MemberState::Suspect.into(),
+                ..member
+            }])
+            .await;
+        }
+    }
+
+    /// Declares dead every member that stayed suspected for longer than the suspicion timeout.
+    async fn expire_suspects(&self) {
+        let expired: Vec<Member> = self
+            .members
+            .read()
+            .await
+            .values()
+            .filter(|entry| {
+                state(&entry.member) == MemberState::Suspect
+                    && entry
+                        .suspected_at
+                        .is_some_and(|at| at.elapsed() > SUSPICION_TIMEOUT)
+            })
+            .map(|entry| Member {
+                state: MemberState::Dead.into(),
+                ..entry.member.clone()
+            })
+            .collect();
+
+        self.merge(expired).await;
+    }
+
+    /// Runs the failure detector until the node leaves the network.
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(PROTOCOL_PERIOD);
+        loop {
+            interval.tick().await;
+            if state(&*self.this.read().await) == MemberState::Left {
+                return;
+            }
+            self.probe().await;
+            self.expire_suspects().await;
+        }
+    }
+
+    /// Marks this node as having left and lets a few members know right away.
+    pub async fn leave(&self) {
+        let this = {
+            let mut this = self.this.write().await;
+            this.state = MemberState::Left.into();
+            this.clone()
+        };
+        self.broadcast(this).await;
+
+        let mut members: Vec<Node> = self
+            .members
+            .read()
+            .await
+            .values()
+            .filter(|entry| state(&entry.member) == MemberState::Alive)
+            .map(|entry| Node {
+                id: entry.member.id.clone(),
+                addr: entry.member.addr.clone(),
+            })
+            .collect();
+        members.shuffle(&mut rand::thread_rng());
+
+        for member in members.iter().take(INDIRECT_PINGS) {
+            if let Err(err) = self.ping(member).await {
+                warn!("Failed to notify {} of leave: {}", member.addr, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_node(id: RingId) -> Node {
+    Node {
+        id: encode_id(id),
+        addr: format!("http://127.0.0.1:{}", 50000 + id),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_suspect_declared_dead() {
+    let membership = Membership::new(&test_node(1), vec![1], 1.0);
+    let other = Membership::new(&test_node(2), vec![2], 1.0).this().await;
+    membership.merge(vec![other.clone()]).await;
+
+    membership.suspect(&test_node(2)).await;
+    let member = membership.member(2).await.unwrap();
+    assert_eq!(state(&member), MemberState::Suspect);
+    assert_eq!(membership.members().await.len(), 2);
+    assert_eq!(membership.alive_members().await.len(), 1);
+
+    tokio::time::advance(SUSPICION_TIMEOUT / 2).await;
+    membership.expire_suspects().await;
+    assert_eq!(
+        state(&membership.member(2).await.unwrap()),
+        MemberState::Suspect
+    );
+
+    tokio::time::advance(SUSPICION_TIMEOUT).await;
+    membership.expire_suspects().await;
+    assert_eq!(
+        state(&membership.member(2).await.unwrap()),
+        MemberState::Dead
+    );
+    assert_eq!(membership.members().await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_refute_suspicion() {
+    let membership = Membership::new(&test_node(1), vec![1], 1.0);
+    let this = membership.this().await;
+
+    membership
+        .merge(vec![Member {
+            state: MemberState::Suspect.into(),
+            ..this.clone()
+        }])
+        .await;
+    let refuted = membership.this().await;
+    assert_eq!(state(&refuted), MemberState::Alive);
+    assert_eq!(refuted.incarnation, this.incarnation + 1);
+    assert!(membership.piggyback().await.contains(&refuted));
+
+    // A suspicion older than the refutation is ignored.
+    membership
+        .merge(vec![Member {
+            state: MemberState::Suspect.into(),
+            ..this
+        }])
+        .await;
+    assert_eq!(membership.this().await, refuted);
+}
+
+#[tokio::test]
+async fn test_rejoin_after_restart() {
+    let membership = Membership::new(&test_node(1), vec![1], 1.0);
+    let before = Membership::new(&test_node(2), vec![2], 1.0).this().await;
+    membership
+        .merge(vec![Member {
+            state: MemberState::Dead.into(),
+            ..before.clone()
+        }])
+        .await;
+    assert_eq!(membership.members().await.len(), 1);
+
+    // The restarted node starts over with a higher incarnation, overriding its death.
+    std::thread::sleep(Duration::from_millis(2));
+    let after = Membership::new(&test_node(2), vec![2], 1.0).this().await;
+    assert!(after.incarnation > before.incarnation);
+    membership.merge(vec![after]).await;
+    assert_eq!(
+        state(&membership.member(2).await.unwrap()),
+        MemberState::Alive
+    );
+    assert_eq!(membership.members().await.len(), 2);
+}
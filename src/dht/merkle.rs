@@ -0,0 +1,112 @@
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::error::{Error, Result};
+use crate::RingId;
+
+/// Depth of the trees, keys being spread over 2^DEPTH buckets by their highest bits.
+const DEPTH: u32 = 10;
+const BUCKETS: usize = 1 << DEPTH;
+
+/// Merkle tree over the versions of a set of keys, letting two replicas find the buckets
+/// of keys they disagree on by exchanging a few kilobytes. Hashes are stored root first,
+/// the children of node `i` being `2i + 1` and `2i + 2`. Empty subtrees hash to 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Returns the bucket a key falls in.
+    pub fn bucket(key: RingId) -> usize {
+        (key >> (RingId::BITS - DEPTH)) as usize
+    }
+
+    pub fn build(entries: impl IntoIterator<Item = (RingId, u64)>) -> Self {
+        let mut buckets = vec![Vec::new(); BUCKETS];
+        for (key, version) in entries {
+            buckets[Self::bucket(key)].push((key, version));
+        }
+
+        let mut nodes = vec![0; 2 * BUCKETS - 1];
+        for (bucket, mut entries) in buckets.into_iter().enumerate() {
+            if entries.is_empty() {
+                continue;
+            }
+            entries.sort_unstable();
+            let bytes: Vec<u8> = entries
+                .iter()
+                .flat_map(|(key, version)| {
+                    key.to_be_bytes().into_iter().chain(version.to_be_bytes())
+                })
+                .collect();
+            nodes[BUCKETS - 1 + bucket] = xxh3_64(&bytes);
+        }
+        for i in (0..BUCKETS - 1).rev() {
+            let (left, right) = (nodes[2 * i + 1], nodes[2 * i + 2]);
+            if left != 0 || right != 0 {
+                let bytes: Vec<u8> = left
+                    .to_be_bytes()
+                    .into_iter()
+                    .chain(right.to_be_bytes())
+                    .collect();
+                nodes[i] = xxh3_64(&bytes);
+            }
+        }
+        MerkleTree { nodes }
+    }
+
+    pub fn from_nodes(nodes: Vec<u64>) -> Result<Self> {
+        if nodes.len() != 2 * BUCKETS - 1 {
+            return Err(Error::Parse(format!(
+                "Merkle tree has {} nodes instead of {}.",
+                nodes.len(),
+                2 * BUCKETS - 1
+            )));
+        }
+        Ok(MerkleTree { nodes })
+    }
+
+    pub fn nodes(&self) -> &[u64] {
+        &self.nodes
+    }
+
+    /// Returns the buckets whose keys differ between the trees, descending only into
+    /// subtrees whose hashes differ.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        let mut buckets = Vec::new();
+        let mut pending = vec![0];
+        while let Some(i) = pending.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+            if i >= BUCKETS - 1 {
+                buckets.push(i - (BUCKETS - 1));
+            } else {
+                pending.extend([2 * i + 1, 2 * i + 2]);
+            }
+        }
+        buckets.sort_unstable();
+        buckets
+    }
+}
+
+#[test]
+fn test_merkle_diff() {
+    let low: RingId = 1;
+    let high: RingId = RingId::MAX - 1;
+    let ours = MerkleTree::build([(low, 1), (high, 1)]);
+
+    assert!(ours
+        .diff(&MerkleTree::build([(high, 1), (low, 1)]))
+        .is_empty());
+    assert_eq!(
+        ours.diff(&MerkleTree::build([(low, 2), (high, 1)])),
+        vec![MerkleTree::bucket(low)]
+    );
+    assert_eq!(
+        ours.diff(&MerkleTree::build([(low, 1)])),
+        vec![MerkleTree::bucket(high)]
+    );
+    assert_eq!(ours.diff(&MerkleTree::build([])).len(), 2);
+    assert!(MerkleTree::from_nodes(vec![0; 3]).is_err());
+}
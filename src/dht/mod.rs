@@ -1,2 +1,12 @@
+pub mod config;
+mod hints;
+mod membership;
+mod merkle;
+mod raft;
+mod replication;
 pub mod service;
 mod store;
+mod throttle;
+mod transfer;
+pub mod versioning;
+mod vnode;
@@ -0,0 +1,1465 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use prost::Message;
+use rand::Rng;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use tonic::{Code, Request};
+
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::{
+    AppendRequest, AppendResponse, EncodedQuery, OperationType, QueryResult, RaftEntry,
+    RaftQueryRequest, RaftRecord, ReplicaEntry, VoteRequest, VoteResponse,
+};
+use crate::rpc::registry::Node;
+use crate::{decode_id, decode_ids, encode_id, encode_ids, HashRing, RingId};
+
+use super::membership::Membership;
+use super::replication::replica_entry;
+use super::store::{Store, Versioned};
+
+/// Interval between two checks of the groups for elections and heartbeats due.
+const TICK: Duration = Duration::from_millis(100);
+/// Interval between two appends sent by a leader to each member of its group.
+const HEARTBEAT_PERIOD: Duration = Duration::from_millis(300);
+/// Minimum time a member waits without hearing from a leader before standing for election.
+/// The actual timeout is randomized up to twice as long.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Time a leader keeps serving reads after a majority acknowledged it. Members do not vote
+/// for a new leader while they heard from the current one within the election timeout, so
+/// the lease must be shorter than it.
+const LEASE: Duration = Duration::from_secs(1);
+/// Interval between two comparisons of a group's members with the holders of its token,
+/// and between two moves of the keys that left its range.
+const RECONFIGURE_PERIOD: Duration = Duration::from_secs(1);
+/// Time to wait for a member to answer a vote or an append.
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time a leader waits for a write to be committed.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time a query is retried for while its group has no reachable leader.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of entries sent in a single append.
+const MAX_ENTRIES: usize = 128;
+/// Maximum number of keys a leader moves to other groups at once.
+const MOVE_BATCH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug)]
+struct State {
+    term: u64,
+    voted_for: Option<RingId>,
+    /// Entries of the log, the first one having index 1.
+    log: Vec<RaftEntry>,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<RingId>,
+    /// Members of the group, from the latest configuration entry of the log.
+    config: Vec<RingId>,
+    config_index: u64,
+    next_index: HashMap<RingId, u64>,
+    match_index: HashMap<RingId, u64>,
+    /// When the appends last acknowledged by each member were sent, for the lease.
+    acked: HashMap<RingId, Instant>,
+    heard_at: Option<Instant>,
+    election_deadline: Instant,
+    heartbeat_at: Instant,
+    reconfigured_at: Instant,
+    /// Whether keys that left the group's range are being moved to their new group.
+    moving: bool,
+    /// Queries waiting for the entry at each index to be applied.
+    pending: HashMap<u64, oneshot::Sender<Result<Option<Versioned>>>>,
+}
+
+fn election_deadline() -> Instant {
+    let jitter = rand::thread_rng().gen_range(0..ELECTION_TIMEOUT.as_millis() as u64);
+    Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+impl State {
+    fn new() -> Self {
+        let now = Instant::now();
+        State {
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit: 0,
+            applied: 0,
+            role: Role::Follower,
+            leader: None,
+            config: Vec::new(),
+            config_index: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            acked: HashMap::new(),
+            heard_at: None,
+            election_deadline: election_deadline(),
+            heartbeat_at: now,
+            reconfigured_at: now,
+            moving: false,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            _ => self.log[index as usize - 1].term,
+        }
+    }
+
+    fn majority(&self) -> usize {
+        self.config.len() / 2 + 1
+    }
+
+    /// Takes the members of the group from the latest configuration entry of the log,
+    /// committed or not.
+    fn refresh_config(&mut self) {
+        let latest = self
+            .log
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, entry)| !entry.config.is_empty());
+        (self.config, self.config_index) = match latest {
+            Some((i, entry)) => (decode_ids(&entry.config).unwrap_or_default(), i as u64 + 1),
+            None => (Vec::new(), 0),
+        };
+    }
+
+    fn become_follower(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        // Writes waiting for entries of the lost term may still be committed by the next
+        // leader, so they fail without being retried.
+        self.pending.clear();
+        self.role = Role::Follower;
+        self.leader = None;
+        self.election_deadline = election_deadline();
+    }
+
+    /// Whether a majority of the group acknowledged this leader recently enough that no
+    /// other leader can have been elected since.
+    fn holds_lease(&self, this: RingId) -> bool {
+        let acked = self
+            .config
+            .iter()
+            .filter(|member| {
+                **member == this
+                    || self
+                        .acked
+                        .get(member)
+                        .is_some_and(|at| at.elapsed() < LEASE)
+            })
+            .count();
+        self.role == Role::Leader && acked >= self.majority()
+    }
+
+    /// Returns the record of the term and vote of the group's member, along with its log
+    /// from `from` on unless it is 0.
+    fn record(&self, token: RingId, from: u64) -> RaftRecord {
+        let entries = match from {
+            0 => Vec::new(),
+            _ => self.log[from as usize - 1..].to_vec(),
+        };
+        RaftRecord {
+            group: encode_id(token),
+            term: self.term,
+            voted_for: self.voted_for.map(encode_id),
+            index: from,
+            entries,
+        }
+    }
+}
+
+/// File the groups run on this node record their terms, votes and logs to, synced before
+/// any member relies on them, so that a restarted node keeps the votes it cast and the
+/// entries it acknowledged.
+#[derive(Debug)]
+struct Storage {
+    file: Arc<std::sync::Mutex<File>>,
+}
+
+impl Storage {
+    /// Loads the state of the groups recorded in the file, and compacts it to one record
+    /// per group.
+    fn load(path: &Path) -> Result<(Self, HashMap<RingId, State>)> {
+        let mut states: HashMap<RingId, State> = HashMap::new();
+        if path.exists() {
+            let bytes = std::fs::read(path)?;
+            let mut buf = bytes.as_slice();
+            while !buf.is_empty() {
+                let record = match RaftRecord::decode_length_delimited(&mut buf) {
+                    Ok(record) => record,
+                    // A record cut short by a crash was not synced, so nothing relied on it.
+                    Err(err) => {
+                        warn!("Ignoring the end of the Raft state file: {}", err);
+                        break;
+                    }
+                };
+                let state = states
+                    .entry(decode_id(&record.group)?)
+                    .or_insert_with(State::new);
+                state.term = record.term;
+                state.voted_for = record.voted_for.as_deref().map(decode_id).transpose()?;
+                if record.index > 0 {
+                    state.log.truncate(record.index as usize - 1);
+                    state.log.extend(record.entries);
+                }
+            }
+        }
+        for state in states.values_mut() {
+            state.refresh_config();
+        }
+
+        let compacted: Vec<u8> = states
+            .iter()
+            .flat_map(|(token, state)| {
+                let from = if state.log.is_empty() { 0 } else { 1 };
+                state.record(*token, from).encode_length_delimited_to_vec()
+            })
+            .collect();
+        let temporary = path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&compacted)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)?;
+
+        let file = std::fs::OpenOptions::new().append(true).open(path)?;
+        let storage = Storage {
+            file: Arc::new(std::sync::Mutex::new(file)),
+        };
+        Ok((storage, states))
+    }
+
+    /// Appends the record to the file and syncs it, off the runtime's threads.
+    async fn append(&self, record: RaftRecord) -> Result<()> {
+        let file = self.file.clone();
+        let bytes = record.encode_length_delimited_to_vec();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut file = file.lock()?;
+            file.write_all(&bytes)?;
+            file.sync_data()?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// The Raft group of a token, as run by one of its members. It holds the keys of the range
+/// the token starts, stored apart from the virtual nodes as the state machine the log is
+/// applied to.
+#[derive(Debug)]
+struct Group {
+    token: RingId,
+    state: Mutex<State>,
+    store: Store,
+}
+
+impl Group {
+    fn new(token: RingId, state: State) -> Self {
+        Group {
+            token,
+            state: Mutex::new(state),
+            store: Store::default(),
+        }
+    }
+
+    /// Applies the committed entries not applied yet, answering the queries waiting for them.
+    async fn apply(&self, state: &mut State) {
+        while state.applied < state.commit {
+            state.applied += 1;
+            let entry = &state.log[state.applied as usize - 1];
+            let moved = entry.moved;
+            let mut result = Ok(None);
+            for write in entry.writes.clone() {
+                result = self.apply_write(write, moved).await;
+            }
+            if let Some(sender) = state.pending.remove(&state.applied) {
+                let _ = sender.send(result);
+            }
+        }
+    }
+
+    /// Applies a write to the keys of the group. Keys moved in are merged with the stored
+    /// ones, so that neither the writes made here since nor the deletions are undone.
+    async fn apply_write(&self, write: ReplicaEntry, moved: bool) -> Result<Option<Versioned>> {
+        let key = decode_id(&write.key)?;
+        match (write.value, moved) {
+            (Some(value), false) => Ok(self
+                .store
+                .set(
+                    &key,
+                    Versioned {
+                        value,
+                        version: write.version,
+                    },
+                )
+                .await),
+            (Some(value), true) => {
+                let value = Versioned {
+                    value,
+                    version: write.version,
+                };
+                self.store.merge(&key, value).await?;
+                Ok(None)
+            }
+            (None, false) => match self.store.delete(&key).await? {
+                Some(previous) => Ok(Some(previous)),
+                None => Err(Error::Value("Key not present in database.".into())),
+            },
+            (None, true) => Ok(self.store.forget(&key).await),
+        }
+    }
+}
+
+fn not_leader(token: RingId) -> Error {
+    Error::Unavailable(format!("Not the leader of the group of #{:x}.", token))
+}
+
+/// Reads the outcome of a query from the answer of the member that served it.
+fn outcome(result: QueryResult) -> Result<Option<Versioned>> {
+    match (result.error, result.value) {
+        (Some(error), _) => Err(Error::Value(error)),
+        (None, value) => Ok(value.map(|value| Versioned {
+            value,
+            version: result.version,
+        })),
+    }
+}
+
+/// Linearizable replication: each token on the ring has a Raft group made of the node
+/// holding it and the distinct nodes succeeding it, replicating the keys of the range it
+/// starts. Writes are committed through the group's log, reads are served by its leader
+/// while it holds a lease, and the leader follows changes of the membership by adding or
+/// removing one member of the group at a time. Once a token joins the range of another,
+/// the leader of the latter moves the keys now past the new token to its group, so they
+/// may be missing from reads until they are moved.
+#[derive(Debug)]
+pub struct Raft {
+    this: RingId,
+    factor: usize,
+    membership: Arc<Membership>,
+    groups: RwLock<HashMap<RingId, Arc<Group>>>,
+    storage: Option<Storage>,
+}
+
+impl Raft {
+    /// Starts Raft on this node, with the groups recorded in the state file if one is
+    /// given. Their entries are applied again as they are found committed.
+    pub fn new(
+        this: RingId,
+        factor: u32,
+        membership: Arc<Membership>,
+        file: Option<&Path>,
+    ) -> Result<Self> {
+        let (storage, states) = match file {
+            Some(path) => {
+                let (storage, states) = Storage::load(path)?;
+                (Some(storage), states)
+            }
+            None => (None, HashMap::new()),
+        };
+        if !states.is_empty() {
+            info!("Loaded the state of {} Raft groups.", states.len());
+        }
+        let groups = states
+            .into_iter()
+            .map(|(token, state)| (token, Arc::new(Group::new(token, state))))
+            .collect();
+        Ok(Raft {
+            this,
+            factor: factor as usize,
+            membership,
+            groups: RwLock::new(groups),
+            storage,
+        })
+    }
+
+    /// Starts the groups of the tokens this node takes that have none yet, with this node
+    /// as their only member. The leader adds the other holders of the token afterwards.
+    pub async fn start_groups(&self, tokens: &[RingId]) -> Result<()> {
+        let mut groups = self.groups.write().await;
+        let mut started = 0;
+        for token in tokens {
+            if groups.contains_key(token) {
+                continue;
+            }
+            let mut state = State::new();
+            state.term = 1;
+            state.log.push(RaftEntry {
+                term: 1,
+                writes: Vec::new(),
+                config: encode_ids(&[self.this]),
+                moved: false,
+            });
+            state.refresh_config();
+            state.commit = 1;
+            state.applied = 1;
+            state.role = Role::Leader;
+            state.leader = Some(self.this);
+            if let Some(storage) = &self.storage {
+                storage.append(state.record(*token, 1)).await?;
+            }
+            groups.insert(*token, Arc::new(Group::new(*token, state)));
+            started += 1;
+        }
+        if started > 0 {
+            info!("Started the groups of {} tokens.", started);
+        }
+        Ok(())
+    }
+
+    /// Returns the group of the token run on this node, starting it empty if there is
+    /// none: it learns its log and members from the leader.
+    async fn group(&self, token: RingId) -> Arc<Group> {
+        if let Some(group) = self.groups.read().await.get(&token) {
+            return group.clone();
+        }
+        self.groups
+            .write()
+            .await
+            .entry(token)
+            .or_insert_with(|| Arc::new(Group::new(token, State::new())))
+            .clone()
+    }
+
+    /// Records the term and vote of this member of the group, along with its log from
+    /// `from` on unless it is 0, before anything relies on them.
+    async fn persist(&self, group: &Group, state: &State, from: u64) -> Result<()> {
+        match &self.storage {
+            Some(storage) => storage.append(state.record(group.token, from)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the tokens of the members, mapped to the nodes holding them.
+    async fn ring(&self) -> BTreeMap<RingId, RingId> {
+        let mut ring = BTreeMap::new();
+        for member in self.membership.members().await {
+            if let (Ok(id), Ok(tokens)) = (decode_id(&member.id), decode_ids(&member.tokens)) {
+                ring.extend(tokens.into_iter().map(|token| (token, id)));
+            }
+        }
+        ring
+    }
+
+    /// Returns the token whose group holds the key.
+    async fn token_of(&self, key: RingId) -> Option<RingId> {
+        HashRing::closest_preceding(&self.ring().await, key).map(|(token, _)| token)
+    }
+
+    /// Returns the nodes that should make up the group of the token: the node holding it
+    /// and the distinct nodes succeeding it.
+    async fn holders(&self, token: RingId) -> Vec<RingId> {
+        HashRing::preference_list(&self.ring().await, token, self.factor)
+    }
+
+    async fn client(&self, id: RingId) -> Result<DhtNodeClient<Channel>> {
+        let member = self
+            .membership
+            .member(id)
+            .await
+            .ok_or(Error::Internal(format!("Member #{:x} not found.", id)))?;
+        self.membership
+            .client(&Node {
+                id: member.id,
+                addr: member.addr,
+            })
+            .await
+    }
+
+    /// Drives the groups run on this node: leaders send heartbeats, follow membership
+    /// changes and move the keys that left their range, other members stand for election
+    /// when they stop hearing from a leader.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            let groups: Vec<Arc<Group>> = self.groups.read().await.values().cloned().collect();
+            for group in groups {
+                let mut state = group.state.lock().await;
+                let now = Instant::now();
+                match state.role {
+                    Role::Leader => {
+                        if now >= state.reconfigured_at + RECONFIGURE_PERIOD {
+                            state.reconfigured_at = now;
+                            self.reconfigure(&group, &mut state).await;
+                            if !state.moving {
+                                state.moving = true;
+                                tokio::spawn(self.clone().move_keys(group.clone()));
+                            }
+                        }
+                        if now >= state.heartbeat_at + HEARTBEAT_PERIOD {
+                            state.heartbeat_at = now;
+                            self.replicate(&group, &state);
+                        }
+                    }
+                    _ if now >= state.election_deadline && state.config.contains(&self.this) => {
+                        state.election_deadline = election_deadline();
+                        tokio::spawn(self.clone().elect(group.clone()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Sends the entries each member of the group is missing, or a heartbeat.
+    fn replicate(self: &Arc<Self>, group: &Arc<Group>, state: &State) {
+        for member in &state.config {
+            if *member != self.this {
+                tokio::spawn(self.clone().send_append(group.clone(), *member));
+            }
+        }
+    }
+
+    /// Brings a member's log in line with the leader's, as long as it is behind.
+    async fn send_append(self: Arc<Self>, group: Arc<Group>, member: RingId) {
+        loop {
+            let (request, sent_at) = {
+                let state = group.state.lock().await;
+                if state.role != Role::Leader {
+                    return;
+                }
+                let last = state.last_index();
+                let next = state
+                    .next_index
+                    .get(&member)
+                    .copied()
+                    .unwrap_or(last + 1)
+                    .clamp(1, last + 1);
+                let prev = next - 1;
+                let request = AppendRequest {
+                    group: encode_id(group.token),
+                    term: state.term,
+                    leader: encode_id(self.this),
+                    prev_log_index: prev,
+                    prev_log_term: state.term_at(prev),
+                    entries: state.log[prev as usize..]
+                        .iter()
+                        .take(MAX_ENTRIES)
+                        .cloned()
+                        .collect(),
+                    commit: state.commit,
+                };
+                (request, Instant::now())
+            };
+            let (term, prev, sent) = (
+                request.term,
+                request.prev_log_index,
+                request.entries.len() as u64,
+            );
+
+            let mut client = match self.client(member).await {
+                Ok(client) => client,
+                Err(_) => return,
+            };
+            let response =
+                match tokio::time::timeout(RPC_TIMEOUT, client.raft_append(Request::new(request)))
+                    .await
+                {
+                    Ok(Ok(response)) => response.into_inner(),
+                    _ => return,
+                };
+
+            let mut state = group.state.lock().await;
+            if response.term > state.term {
+                state.become_follower(response.term);
+                return;
+            }
+            if state.role != Role::Leader || state.term != term {
+                return;
+            }
+            if response.success {
+                let matched = prev + sent;
+                let current = state.match_index.entry(member).or_insert(0);
+                *current = matched.max(*current);
+                let next = state.next_index.entry(member).or_insert(0);
+                *next = (matched + 1).max(*next);
+                state.acked.insert(member, sent_at);
+                self.advance_commit(&group, &mut state).await;
+                if matched >= state.last_index() {
+                    return;
+                }
+            } else {
+                let next = state.next_index.get(&member).copied().unwrap_or(prev + 1);
+                let next = next.saturating_sub(1).min(response.match_index + 1).max(1);
+                state.next_index.insert(member, next);
+            }
+        }
+    }
+
+    /// Commits the entries of the current term stored by a majority of the group, along
+    /// with the ones before them. A leader that was removed from the group steps down
+    /// once its removal is committed.
+    async fn advance_commit(&self, group: &Group, state: &mut State) {
+        let mut index = state.last_index();
+        while index > state.commit && state.term_at(index) == state.term {
+            let stored = state
+                .config
+                .iter()
+                .filter(|member| {
+                    **member == self.this
+                        || state
+                            .match_index
+                            .get(member)
+                            .is_some_and(|matched| *matched >= index)
+                })
+                .count();
+            if stored >= state.majority() {
+                state.commit = index;
+                break;
+            }
+            index -= 1;
+        }
+        group.apply(state).await;
+
+        if state.role == Role::Leader
+            && state.config_index <= state.commit
+            && !state.config.contains(&self.this)
+        {
+            info!(
+                "Stepping down as leader of the group of #{:x}, no longer a member.",
+                group.token
+            );
+            let term = state.term;
+            state.become_follower(term);
+        }
+    }
+
+    /// Stands for election as leader of the group.
+    async fn elect(self: Arc<Self>, group: Arc<Group>) {
+        let (request, members, majority) = {
+            let mut state = group.state.lock().await;
+            if state.role == Role::Leader || !state.config.contains(&self.this) {
+                return;
+            }
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(self.this);
+            state.leader = None;
+            if let Err(err) = self.persist(&group, &state, 0).await {
+                warn!("Could not record the vote for #{:x}: {}", group.token, err);
+                let term = state.term;
+                state.become_follower(term);
+                return;
+            }
+            info!(
+                "Standing for election as leader of the group of #{:x} in term {}",
+                group.token, state.term
+            );
+            let request = VoteRequest {
+                group: encode_id(group.token),
+                term: state.term,
+                candidate: encode_id(self.this),
+                last_log_index: state.last_index(),
+                last_log_term: state.term_at(state.last_index()),
+            };
+            let members: Vec<RingId> = state
+                .config
+                .iter()
+                .copied()
+                .filter(|member| *member != self.this)
+                .collect();
+            (request, members, state.majority())
+        };
+        let term = request.term;
+
+        let mut votes = JoinSet::new();
+        for member in members {
+            let mut client = match self.client(member).await {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            let request = request.clone();
+            votes.spawn(async move {
+                tokio::time::timeout(RPC_TIMEOUT, client.raft_vote(Request::new(request))).await
+            });
+        }
+        let mut granted = 1;
+        while granted < majority {
+            let response = match votes.join_next().await {
+                Some(Ok(Ok(Ok(response)))) => response.into_inner(),
+                Some(_) => continue,
+                None => break,
+            };
+            if response.term > term {
+                group.state.lock().await.become_follower(response.term);
+                return;
+            }
+            if response.granted {
+                granted += 1;
+            }
+        }
+
+        let mut state = group.state.lock().await;
+        if granted < majority || state.role != Role::Candidate || state.term != term {
+            return;
+        }
+        info!(
+            "Elected leader of the group of #{:x} in term {}",
+            group.token, state.term
+        );
+        state.role = Role::Leader;
+        state.leader = Some(self.this);
+        let last = state.last_index();
+        state.next_index = state
+            .config
+            .iter()
+            .map(|member| (*member, last + 1))
+            .collect();
+        state.match_index.clear();
+        state.acked.clear();
+        // Entries of earlier terms are only committed along with one of the current term.
+        state.log.push(RaftEntry {
+            term,
+            writes: Vec::new(),
+            config: Vec::new(),
+            moved: false,
+        });
+        if let Err(err) = self.persist(&group, &state, last + 1).await {
+            warn!("Could not record the log of #{:x}: {}", group.token, err);
+            state.log.pop();
+            state.become_follower(term);
+            return;
+        }
+        state.heartbeat_at = Instant::now();
+        self.advance_commit(&group, &mut state).await;
+        self.replicate(&group, &state);
+    }
+
+    /// Adds the first holder of the token missing from the group, or else removes the first
+    /// member that no longer holds it. Only one change is in progress at a time.
+    async fn reconfigure(self: &Arc<Self>, group: &Arc<Group>, state: &mut State) {
+        if state.config_index > state.commit {
+            return;
+        }
+        let holders = self.holders(group.token).await;
+        if holders.is_empty() {
+            return;
+        }
+        let mut config = state.config.clone();
+        if let Some(holder) = holders.iter().find(|holder| !config.contains(holder)) {
+            info!("Adding #{:x} to the group of #{:x}", holder, group.token);
+            config.push(*holder);
+        } else if let Some(i) = config.iter().position(|member| !holders.contains(member)) {
+            info!(
+                "Removing #{:x} from the group of #{:x}",
+                config[i], group.token
+            );
+            config.remove(i);
+        } else {
+            return;
+        }
+
+        let term = state.term;
+        state.log.push(RaftEntry {
+            term,
+            writes: Vec::new(),
+            config: encode_ids(&config),
+            moved: false,
+        });
+        let index = state.last_index();
+        if let Err(err) = self.persist(group, state, index).await {
+            warn!("Could not record the log of #{:x}: {}", group.token, err);
+            state.log.pop();
+            return;
+        }
+        state.refresh_config();
+        self.advance_commit(group, state).await;
+        self.replicate(group, state);
+    }
+
+    /// Moves a batch of the keys the group holds outside the range of its token, as a
+    /// token joined it or its token left the ring, to the groups they now belong to. They
+    /// are dropped from this group once the other group committed them.
+    async fn move_keys(self: Arc<Self>, group: Arc<Group>) {
+        let ring = self.ring().await;
+        let token_of = |key| HashRing::closest_preceding(&ring, key).map(|(token, _)| token);
+        let strays = group
+            .store
+            .get_entries_satisfy(|key| token_of(key).is_some_and(|token| token != group.token))
+            .await;
+
+        let mut batches: HashMap<RingId, Vec<(RingId, Versioned)>> = HashMap::new();
+        for (key, value) in strays.into_iter().take(MOVE_BATCH) {
+            if let Some(token) = token_of(key) {
+                batches.entry(token).or_default().push((key, value));
+            }
+        }
+        for (token, batch) in batches {
+            let moved: Vec<ReplicaEntry> = batch
+                .iter()
+                .map(|(key, value)| replica_entry(*key, Some(value)))
+                .collect();
+            let keys: Vec<ReplicaEntry> = batch
+                .iter()
+                .map(|(key, _)| replica_entry(*key, None))
+                .collect();
+            let count = moved.len();
+            let request = RaftQueryRequest {
+                group: encode_id(token),
+                query: None,
+                forwarded: false,
+                moved,
+            };
+            let result = match self.execute(token, request, true).await {
+                Ok(_) => self.propose(&group, keys, true).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(_) => info!(
+                    "Moved {} keys from the group of #{:x} to the group of #{:x}.",
+                    count, group.token, token
+                ),
+                Err(err) => warn!(
+                    "Could not move keys from the group of #{:x} to the group of #{:x}: {}",
+                    group.token, token, err
+                ),
+            }
+        }
+        group.state.lock().await.moving = false;
+    }
+
+    pub async fn handle_vote(&self, request: VoteRequest) -> Result<VoteResponse> {
+        let group = self.group(decode_id(&request.group)?).await;
+        let candidate = decode_id(&request.candidate)?;
+        let mut state = group.state.lock().await;
+
+        // A member still hearing from a leader does not help depose it.
+        let leader_alive = state.role == Role::Leader
+            || state
+                .heard_at
+                .is_some_and(|at| at.elapsed() < ELECTION_TIMEOUT);
+        if request.term < state.term || leader_alive {
+            return Ok(VoteResponse {
+                term: state.term,
+                granted: false,
+            });
+        }
+        let mut changed = false;
+        if request.term > state.term {
+            state.become_follower(request.term);
+            changed = true;
+        }
+
+        let last = state.last_index();
+        let up_to_date =
+            (request.last_log_term, request.last_log_index) >= (state.term_at(last), last);
+        let granted = up_to_date && state.voted_for.is_none_or(|voted| voted == candidate);
+        if granted {
+            changed |= state.voted_for != Some(candidate);
+            state.voted_for = Some(candidate);
+            state.election_deadline = election_deadline();
+        }
+        if changed {
+            self.persist(&group, &state, 0).await?;
+        }
+        Ok(VoteResponse {
+            term: state.term,
+            granted,
+        })
+    }
+
+    pub async fn handle_append(&self, request: AppendRequest) -> Result<AppendResponse> {
+        let group = self.group(decode_id(&request.group)?).await;
+        let leader = decode_id(&request.leader)?;
+        let mut state = group.state.lock().await;
+
+        if request.term < state.term {
+            return Ok(AppendResponse {
+                term: state.term,
+                success: false,
+                match_index: 0,
+            });
+        }
+        let term = state.term;
+        if request.term > state.term || state.role != Role::Follower {
+            state.become_follower(request.term);
+        }
+        state.leader = Some(leader);
+        state.heard_at = Some(Instant::now());
+        state.election_deadline = election_deadline();
+
+        let prev = request.prev_log_index;
+        if prev > state.last_index() || state.term_at(prev) != request.prev_log_term {
+            if state.term != term {
+                self.persist(&group, &state, 0).await?;
+            }
+            return Ok(AppendResponse {
+                term: state.term,
+                success: false,
+                match_index: state.last_index().min(prev.saturating_sub(1)),
+            });
+        }
+
+        let received = request.entries.len() as u64;
+        let mut changed = 0;
+        for (index, entry) in (prev + 1..).zip(request.entries) {
+            if index <= state.last_index() {
+                if state.term_at(index) == entry.term {
+                    continue;
+                }
+                state.log.truncate(index as usize - 1);
+            }
+            if changed == 0 {
+                changed = index;
+            }
+            state.log.push(entry);
+        }
+        state.refresh_config();
+        if changed > 0 || state.term != term {
+            self.persist(&group, &state, changed).await?;
+        }
+
+        let commit = request.commit.min(prev + received);
+        if commit > state.commit {
+            state.commit = commit;
+            group.apply(&mut state).await;
+        }
+        Ok(AppendResponse {
+            term: state.term,
+            success: true,
+            match_index: prev + received,
+        })
+    }
+
+    /// Executes a query on the group of its key, wherever its leader is, retrying while
+    /// the group has no leader that can be reached.
+    pub async fn query(
+        self: &Arc<Self>,
+        key: RingId,
+        query: EncodedQuery,
+    ) -> Result<Option<Versioned>> {
+        let idempotent = query.ty == OperationType::Get as i32;
+        let request = RaftQueryRequest {
+            group: Vec::new(),
+            query: Some(query),
+            forwarded: false,
+            moved: Vec::new(),
+        };
+        self.execute(key, request, idempotent).await
+    }
+
+    /// Sends a request to the leader of the group of the key, retrying as long as no
+    /// member executed it. Requests that are not `idempotent` are not retried once a
+    /// member may have executed them, failing instead.
+    async fn execute(
+        self: &Arc<Self>,
+        key: RingId,
+        mut request: RaftQueryRequest,
+        idempotent: bool,
+    ) -> Result<Option<Versioned>> {
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut backoff = TICK;
+        loop {
+            let token = self
+                .token_of(key)
+                .await
+                .ok_or(Error::Unavailable("No tokens on the ring.".into()))?;
+            request.group = encode_id(token);
+
+            let mut targets = Vec::new();
+            let group = self.groups.read().await.get(&token).cloned();
+            if let Some(group) = group {
+                let (role, leader) = {
+                    let state = group.state.lock().await;
+                    (state.role, state.leader)
+                };
+                if role == Role::Leader {
+                    match self.serve(&group, &request).await {
+                        Err(Error::Unavailable(_)) => {}
+                        result => return result,
+                    }
+                }
+                targets.extend(leader.filter(|leader| *leader != self.this));
+            }
+            for holder in self.holders(token).await {
+                if holder != self.this && !targets.contains(&holder) {
+                    targets.push(holder);
+                }
+            }
+
+            for target in targets {
+                match self.ask(target, &request).await {
+                    Ok(result) => return outcome(result),
+                    Err(err @ Error::Unavailable(_)) => {
+                        warn!("Group of #{:x} not served by #{:x}: {}", token, target, err)
+                    }
+                    Err(err) if idempotent => {
+                        warn!("Group of #{:x} not served by #{:x}: {}", token, target, err)
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if Instant::now() + backoff > deadline {
+                return Err(Error::Unavailable(format!(
+                    "No leader of the group of #{:x} could be reached.",
+                    token
+                )));
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(1));
+        }
+    }
+
+    /// Sends a request to a member of its group. It fails as unavailable only if the
+    /// member did not execute it.
+    async fn ask(&self, member: RingId, request: &RaftQueryRequest) -> Result<QueryResult> {
+        let mut client = self
+            .client(member)
+            .await
+            .map_err(|err| Error::Unavailable(err.to_string()))?;
+        let response = client.raft_query(Request::new(request.clone()));
+        match tokio::time::timeout(COMMIT_TIMEOUT + RPC_TIMEOUT, response).await {
+            Ok(Ok(response)) => Ok(response.into_inner()),
+            Ok(Err(status)) if status.code() == Code::Unavailable => {
+                Err(Error::Unavailable(status.message().into()))
+            }
+            Ok(Err(status)) => Err(status.into()),
+            Err(_) => Err(Error::Internal(format!(
+                "Query sent to #{:x} timed out, it may still be executed.",
+                member
+            ))),
+        }
+    }
+
+    /// Serves a request sent by another node if this node leads the group, or passes it
+    /// on once to the leader it knows of. Fails as unavailable if neither is possible.
+    pub async fn handle_query(
+        self: &Arc<Self>,
+        request: RaftQueryRequest,
+    ) -> Result<Option<Versioned>> {
+        let token = decode_id(&request.group)?;
+        let group = self.groups.read().await.get(&token).cloned();
+        let group = match group {
+            Some(group) => group,
+            None => return Err(not_leader(token)),
+        };
+        let (role, leader) = {
+            let state = group.state.lock().await;
+            (state.role, state.leader)
+        };
+
+        match leader {
+            _ if role == Role::Leader => self.serve(&group, &request).await,
+            Some(leader) if !request.forwarded && leader != self.this => {
+                let request = RaftQueryRequest {
+                    forwarded: true,
+                    ..request
+                };
+                outcome(self.ask(leader, &request).await?)
+            }
+            _ => Err(not_leader(token)),
+        }
+    }
+
+    /// Executes a request as the leader of the group: writes and moved keys are appended
+    /// to the log and answered once applied, reads are answered from the applied state
+    /// under the lease. Queries for keys outside the group's range are left to the group
+    /// they belong to.
+    async fn serve(
+        self: &Arc<Self>,
+        group: &Arc<Group>,
+        request: &RaftQueryRequest,
+    ) -> Result<Option<Versioned>> {
+        if !request.moved.is_empty() {
+            return self.propose(group, request.moved.clone(), true).await;
+        }
+        let query = request
+            .query
+            .as_ref()
+            .ok_or(Error::Parse("Query not provided.".into()))?;
+        let key = decode_id(&query.key)?;
+        let ty = OperationType::from_i32(query.ty)
+            .ok_or(Error::Parse(format!("Unknown operation {}.", query.ty)))?;
+        if self.token_of(key).await != Some(group.token) {
+            return Err(Error::Unavailable(format!(
+                "Key {:x} is not in the range of #{:x}.",
+                key, group.token
+            )));
+        }
+        info!(
+            "Executing query for key {:x} in the group of #{:x}.",
+            key, group.token
+        );
+
+        if ty == OperationType::Get {
+            return self.read(group, key).await;
+        }
+        let write = match (ty, &query.value) {
+            (OperationType::Set, Some(value)) => {
+                let previous = group.store.get(&key).await;
+                let value = Versioned::new(value.clone(), previous.as_ref())?;
+                replica_entry(key, Some(&value))
+            }
+            (OperationType::Set, None) => return Err(Error::Value("Value not provided.".into())),
+            _ => replica_entry(key, None),
+        };
+        self.propose(group, vec![write], false).await
+    }
+
+    /// Appends writes to the log of the group this node leads, answering once they are
+    /// applied. A write that is not committed in time fails without being undone, as it
+    /// may still be committed.
+    async fn propose(
+        self: &Arc<Self>,
+        group: &Arc<Group>,
+        writes: Vec<ReplicaEntry>,
+        moved: bool,
+    ) -> Result<Option<Versioned>> {
+        let receiver = {
+            let mut state = group.state.lock().await;
+            if state.role != Role::Leader {
+                return Err(not_leader(group.token));
+            }
+            let term = state.term;
+            state.log.push(RaftEntry {
+                term,
+                writes,
+                config: Vec::new(),
+                moved,
+            });
+            let index = state.last_index();
+            if let Err(err) = self.persist(group, &state, index).await {
+                state.log.pop();
+                return Err(err);
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.pending.insert(index, sender);
+            self.advance_commit(group, &mut state).await;
+            self.replicate(group, &state);
+            receiver
+        };
+
+        match tokio::time::timeout(COMMIT_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => Err(Error::Internal(format!(
+                "Write to the group of #{:x} was not committed in time, it may still be.",
+                group.token
+            ))),
+        }
+    }
+
+    /// Reads a key once this node holds the lease and applied an entry of its term, so
+    /// that every write committed before the read is visible.
+    async fn read(&self, group: &Group, key: RingId) -> Result<Option<Versioned>> {
+        let deadline = Instant::now() + LEASE;
+        loop {
+            {
+                let state = group.state.lock().await;
+                if state.role != Role::Leader {
+                    return Err(not_leader(group.token));
+                }
+                if state.holds_lease(self.this)
+                    && state.applied == state.commit
+                    && state.term_at(state.commit) == state.term
+                {
+                    return match group.store.get(&key).await {
+                        Some(value) => Ok(Some(value)),
+                        None => Err(Error::Value("Key not present in database.".into())),
+                    };
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Unavailable(format!(
+                    "Leader of the group of #{:x} does not hold a lease.",
+                    group.token
+                )));
+            }
+            tokio::time::sleep(TICK).await;
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_raft(id: RingId, tokens: Vec<RingId>, file: Option<&Path>) -> Result<Arc<Raft>> {
+    let node = Node {
+        id: encode_id(id),
+        addr: format!("http://0.0.0.0:{}", 50000 + id),
+    };
+    let membership = Arc::new(Membership::new(&node, tokens, 1.0));
+    Ok(Arc::new(Raft::new(id, 3, membership, file)?))
+}
+
+#[cfg(test)]
+fn test_query(ty: OperationType, key: RingId, value: Option<&[u8]>) -> EncodedQuery {
+    EncodedQuery {
+        ty: ty as i32,
+        key: encode_id(key),
+        value: value.map(|value| value.to_vec()),
+        ..EncodedQuery::default()
+    }
+}
+
+#[tokio::test]
+async fn test_append_replaces_conflicting_entries() -> Result<()> {
+    let raft = test_raft(1, vec![1], None)?;
+    let write = |term, value: &[u8]| RaftEntry {
+        term,
+        writes: vec![replica_entry(
+            7,
+            Some(&Versioned {
+                value: value.to_vec(),
+                version: term,
+            }),
+        )],
+        config: Vec::new(),
+        moved: false,
+    };
+    let append = |term, prev_log_index, prev_log_term, entries, commit| AppendRequest {
+        group: encode_id(0),
+        term,
+        leader: encode_id(2),
+        prev_log_index,
+        prev_log_term,
+        entries,
+        commit,
+    };
+
+    let response = raft
+        .handle_append(append(1, 0, 0, vec![write(1, b"a"), write(1, b"b")], 1))
+        .await?;
+    assert!(response.success);
+    assert_eq!(response.match_index, 2);
+
+    // The uncommitted second entry is replaced by the one of a later leader.
+    let response = raft
+        .handle_append(append(2, 1, 1, vec![write(2, b"c")], 2))
+        .await?;
+    assert!(response.success);
+    let group = raft.group(0).await;
+    assert_eq!(group.state.lock().await.log.len(), 2);
+    assert_eq!(group.store.get(&7).await.unwrap().value, b"c".to_vec());
+
+    // Appends of an older term, or that do not follow the log, are rejected.
+    assert!(
+        !raft
+            .handle_append(append(1, 2, 2, vec![], 2))
+            .await?
+            .success
+    );
+    let response = raft.handle_append(append(2, 5, 2, vec![], 2)).await?;
+    assert!(!response.success);
+    assert_eq!(response.match_index, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vote_rules() -> Result<()> {
+    let raft = test_raft(1, vec![1], None)?;
+    let vote = |term, candidate, last_log_index, last_log_term| VoteRequest {
+        group: encode_id(0),
+        term,
+        candidate: encode_id(candidate),
+        last_log_index,
+        last_log_term,
+    };
+    let entry = |term| RaftEntry {
+        term,
+        writes: Vec::new(),
+        config: Vec::new(),
+        moved: false,
+    };
+    raft.group(0).await.state.lock().await.log = vec![entry(1), entry(2)];
+
+    // Candidates with a log behind this member's are turned down.
+    assert!(!raft.handle_vote(vote(3, 2, 5, 1)).await?.granted);
+    assert!(!raft.handle_vote(vote(3, 2, 1, 2)).await?.granted);
+    // A single vote is cast per term.
+    assert!(raft.handle_vote(vote(3, 2, 2, 2)).await?.granted);
+    assert!(raft.handle_vote(vote(3, 2, 2, 2)).await?.granted);
+    assert!(!raft.handle_vote(vote(3, 3, 2, 2)).await?.granted);
+    let response = raft.handle_vote(vote(2, 3, 2, 2)).await?;
+    assert!(!response.granted);
+    assert_eq!(response.term, 3);
+    assert!(raft.handle_vote(vote(4, 3, 2, 2)).await?.granted);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_single_member_group() -> Result<()> {
+    let raft = test_raft(1, vec![0x10], None)?;
+    raft.start_groups(&[0x10]).await?;
+    tokio::spawn(raft.clone().run());
+
+    // The only member of the group commits writes on its own.
+    raft.query(0x20, test_query(OperationType::Set, 0x20, Some(b"a")))
+        .await?;
+    let value = raft
+        .query(0x20, test_query(OperationType::Get, 0x20, None))
+        .await?;
+    assert_eq!(value.map(|value| value.value), Some(b"a".to_vec()));
+    raft.query(0x20, test_query(OperationType::Delete, 0x20, None))
+        .await?;
+    assert!(raft
+        .query(0x20, test_query(OperationType::Get, 0x20, None))
+        .await
+        .is_err());
+
+    // Once it loses its leadership, it stands for election again and wins it.
+    let group = raft.group(0x10).await;
+    group.state.lock().await.become_follower(5);
+    tokio::time::sleep(ELECTION_TIMEOUT * 3).await;
+    let state = group.state.lock().await;
+    assert_eq!(state.role, Role::Leader);
+    assert_eq!(state.term, 6);
+    assert_eq!(state.commit, state.last_index());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_lease_and_commit() -> Result<()> {
+    let raft = test_raft(1, vec![0x10], None)?;
+    raft.start_groups(&[0x10]).await?;
+    let group = raft.group(0x10).await;
+    {
+        let mut state = group.state.lock().await;
+        state.log.push(RaftEntry {
+            term: 1,
+            writes: Vec::new(),
+            config: encode_ids(&[1, 2, 3]),
+            moved: false,
+        });
+        state.refresh_config();
+        state.log.push(RaftEntry {
+            term: 1,
+            writes: vec![replica_entry(
+                0x20,
+                Some(&Versioned {
+                    value: b"a".to_vec(),
+                    version: 1,
+                }),
+            )],
+            config: Vec::new(),
+            moved: false,
+        });
+    }
+
+    // Entries are committed once a majority of the group stored them.
+    let mut state = group.state.lock().await;
+    state.match_index.insert(2, 2);
+    raft.advance_commit(&group, &mut state).await;
+    assert_eq!(state.commit, 2);
+    state.match_index.insert(3, 3);
+    raft.advance_commit(&group, &mut state).await;
+    assert_eq!(state.commit, 3);
+    assert!(group.store.get(&0x20).await.is_some());
+
+    // Reads are served while a majority acknowledged the leader within the lease.
+    assert!(!state.holds_lease(1));
+    state.acked.insert(2, Instant::now());
+    assert!(state.holds_lease(1));
+    drop(state);
+    let value = raft.read(&group, 0x20).await?;
+    assert_eq!(value.map(|value| value.value), Some(b"a".to_vec()));
+    tokio::time::advance(LEASE).await;
+    assert!(!group.state.lock().await.holds_lease(1));
+    assert!(matches!(
+        raft.read(&group, 0x20).await,
+        Err(Error::Unavailable(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconfigure_adds_holders() -> Result<()> {
+    let raft = test_raft(1, vec![0x10], None)?;
+    raft.start_groups(&[0x10]).await?;
+    raft.membership
+        .merge(vec![crate::rpc::dht::Member {
+            id: encode_id(2),
+            addr: "http://0.0.0.0:50002".into(),
+            tokens: encode_ids(&[0x80]),
+            capacity: 1.0,
+            ..Default::default()
+        }])
+        .await;
+    let group = raft.group(0x10).await;
+    let mut state = group.state.lock().await;
+
+    // The successor holding the next token joins the group, one change at a time.
+    raft.reconfigure(&group, &mut state).await;
+    assert_eq!(state.config, vec![1, 2]);
+    assert_eq!(state.config_index, 2);
+    raft.reconfigure(&group, &mut state).await;
+    assert_eq!(state.last_index(), 2);
+
+    // The change is committed once the new member stored it.
+    state.match_index.insert(2, 2);
+    raft.advance_commit(&group, &mut state).await;
+    assert_eq!(state.commit, 2);
+    assert_eq!(state.role, Role::Leader);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_state_persisted() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("crustyring-raft-{}", std::process::id()));
+    let raft = test_raft(1, vec![0x10], Some(&path))?;
+    raft.start_groups(&[0x10]).await?;
+    let vote = VoteRequest {
+        group: encode_id(0x80),
+        term: 4,
+        candidate: encode_id(2),
+        last_log_index: 0,
+        last_log_term: 0,
+    };
+    let granted = raft.handle_vote(vote.clone()).await?.granted;
+    let append = AppendRequest {
+        group: encode_id(0x80),
+        term: 4,
+        leader: encode_id(2),
+        prev_log_index: 0,
+        prev_log_term: 0,
+        entries: vec![RaftEntry {
+            term: 4,
+            writes: Vec::new(),
+            config: encode_ids(&[1, 2]),
+            moved: false,
+        }],
+        commit: 0,
+    };
+    let appended = raft.handle_append(append).await?.success;
+    drop(raft);
+
+    let loaded = test_raft(1, vec![0x10], Some(&path));
+    std::fs::remove_file(&path)?;
+    let loaded = loaded?;
+    let (started, voted) = (loaded.group(0x10).await, loaded.group(0x80).await);
+    let started = started.state.lock().await;
+    let voted = voted.state.lock().await;
+
+    assert!(granted && appended);
+    assert_eq!(started.config, vec![1]);
+    assert_eq!(started.term, 1);
+    assert_eq!(voted.term, 4);
+    assert_eq!(voted.voted_for, Some(2));
+    assert_eq!(voted.config, vec![1, 2]);
+    // Nothing is known to be committed until a leader says so.
+    assert_eq!(voted.commit, 0);
+    Ok(())
+}
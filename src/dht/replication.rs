@@ -0,0 +1,1045 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use tonic::transport::Channel;
+use tonic::{Code, Request};
+
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::{
+    ChainWrite, Consistency, Member, MemberState, MerkleRequest, NodeId, QueryResult, ReplicaEntry,
+};
+use crate::rpc::registry::Node;
+use crate::{
+    decode_id, decode_ids, encode_id, encode_ids, HashRing, Placement, Rendezvous, RingId,
+};
+
+use super::config::ReplicationMode;
+use super::hints::Hints;
+use super::membership::Membership;
+use super::merkle::MerkleTree;
+use super::service::VirtualNodes;
+use super::store::{Store, Versioned};
+use super::throttle::{self, Throttle};
+use super::versioning::Versioning;
+use super::vnode::VirtualNode;
+
+/// Interval between two checks of whether the membership changed and replicas must be
+/// rebuilt, or replicas that hints are kept for came back.
+const SYNC_PERIOD: Duration = Duration::from_secs(1);
+/// Interval between two comparisons of the keys this node owns with their replicas.
+const ANTI_ENTROPY_PERIOD: Duration = Duration::from_secs(10);
+/// Time to wait for a replica to apply writes.
+const REPLICATE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time to wait for a replica to answer a read.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Maximum number of writes passed down a chain at once when replicas are rebuilt.
+const CHAIN_BATCH: usize = 128;
+/// Maximum number of keys copied to a replica at once when replicas are rebuilt or repaired.
+const COPY_BATCH: usize = 128;
+
+/// Builds the write of a key to a replica, or of its deletion in a Raft log if there is
+/// no value.
+pub fn replica_entry(key: RingId, value: Option<&Versioned>) -> ReplicaEntry {
+    ReplicaEntry {
+        key: encode_id(key),
+        value: value.map(|value| value.value.clone()),
+        version: value.map_or(0, |value| value.version),
+        deleted: false,
+    }
+}
+
+/// Builds the deletion of a key on a replica, along with the tombstone it was deleted with.
+pub fn tombstone_entry(key: RingId, deleted: &Versioned) -> ReplicaEntry {
+    ReplicaEntry {
+        deleted: true,
+        ..replica_entry(key, Some(deleted))
+    }
+}
+
+/// Sends writes to a replica, failing if it does not apply them in time.
+async fn send(
+    mut client: DhtNodeClient<Channel>,
+    addr: &str,
+    entries: Vec<ReplicaEntry>,
+) -> Result<()> {
+    let request = client.replicate(tokio_stream::iter(entries));
+    match tokio::time::timeout(REPLICATE_TIMEOUT, request).await {
+        Ok(response) => {
+            response?;
+            Ok(())
+        }
+        Err(_) => Err(Error::Internal(format!(
+            "Replicating to {} timed out.",
+            addr
+        ))),
+    }
+}
+
+/// Reads the copy of a key a replica holds, failing if it does not answer in time.
+async fn read_from(
+    mut client: DhtNodeClient<Channel>,
+    addr: &str,
+    key: RingId,
+) -> Result<QueryResult> {
+    let request = client.read_replica(Request::new(NodeId { id: encode_id(key) }));
+    match tokio::time::timeout(READ_TIMEOUT, request).await {
+        Ok(response) => Ok(response?.into_inner()),
+        Err(_) => Err(Error::Internal(format!("Reading from {} timed out.", addr))),
+    }
+}
+
+/// Returns the virtual node of this node owning the key. None of them does if the key is
+/// past the next neighbor of the closest one, as when the one owning it just left.
+async fn owning_vnode(vnodes: &VirtualNodes, key: RingId) -> Option<Arc<VirtualNode>> {
+    let vnode = {
+        let vnodes = vnodes.read().await;
+        HashRing::closest_preceding(&vnodes, key).map(|(_, vnode)| vnode.clone())?
+    };
+    let next = vnode
+        .neighbors
+        .next
+        .read()
+        .await
+        .as_ref()
+        .map(|next| next.id);
+    match next {
+        Some(next) if next != vnode.id && !HashRing::is_node_key(vnode.id, next, key) => None,
+        _ => Some(vnode),
+    }
+}
+
+/// Members of the network as seen at some point, indexed to find the holders of keys.
+struct View {
+    members: HashMap<RingId, Member>,
+    /// Node each token belongs to, for ring placement.
+    ring: BTreeMap<RingId, RingId>,
+    /// Ids and capacities of the members, for rendezvous placement.
+    nodes: Vec<(RingId, f64)>,
+}
+
+impl View {
+    fn new(members: Vec<Member>) -> Self {
+        let mut view = View {
+            members: HashMap::new(),
+            ring: BTreeMap::new(),
+            nodes: Vec::new(),
+        };
+        for member in members {
+            let (id, tokens) = match (decode_id(&member.id), decode_ids(&member.tokens)) {
+                (Ok(id), Ok(tokens)) => (id, tokens),
+                _ => continue,
+            };
+            view.ring
+                .extend(tokens.into_iter().map(|token| (token, id)));
+            view.nodes.push((id, member.capacity));
+            view.members.insert(id, member);
+        }
+        view
+    }
+
+    /// Returns the members holding the key, owner first.
+    fn holders(&self, placement: Placement, key: RingId, factor: usize) -> Vec<&Member> {
+        let ids = match placement {
+            Placement::Ring => HashRing::preference_list(&self.ring, key, factor),
+            Placement::Rendezvous => Rendezvous::ranked(&self.nodes, key, factor),
+        };
+        ids.iter().filter_map(|id| self.members.get(id)).collect()
+    }
+
+    /// Returns the token following the given one on the ring.
+    fn next_token(&self, token: RingId) -> Option<RingId> {
+        self.ring
+            .range((Bound::Excluded(token), Bound::Unbounded))
+            .next()
+            .or(self.ring.iter().next())
+            .map(|(token, _)| *token)
+    }
+}
+
+/// Keeps copies of each key on the nodes following its owner: the distinct nodes
+/// succeeding it on the ring, or the next highest scoring ones with rendezvous placement.
+#[derive(Debug)]
+pub struct Replication {
+    factor: usize,
+    placement: Placement,
+    mode: ReplicationMode,
+    membership: Arc<Membership>,
+    /// Copies of keys this node holds on behalf of their owners.
+    pub store: Store,
+    /// Writes kept for replicas that could not be reached.
+    hints: Hints,
+    /// Limits the rate replicas are rebuilt and repaired at, shared with key migrations.
+    throttle: Arc<Throttle>,
+}
+
+impl Replication {
+    pub fn new(
+        factor: u32,
+        placement: Placement,
+        mode: ReplicationMode,
+        membership: Arc<Membership>,
+        versioning: Versioning,
+        hints: Hints,
+        throttle: Arc<Throttle>,
+    ) -> Self {
+        Replication {
+            factor: factor as usize,
+            placement,
+            mode,
+            membership,
+            store: Store::new(versioning),
+            hints,
+            throttle,
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    async fn view(&self) -> View {
+        View::new(self.membership.members().await)
+    }
+
+    /// Number of members, the owner included, that must answer a request made with
+    /// the given consistency level.
+    pub fn required(&self, consistency: Consistency) -> usize {
+        match consistency {
+            Consistency::One => 1,
+            Consistency::Quorum => self.factor / 2 + 1,
+            Consistency::All => self.factor,
+        }
+    }
+
+    /// Applies a write made on the owner to the other members holding the key, failing
+    /// unless `required` members, the owner included, acknowledged it. Writes for members
+    /// that are suspected or do not answer are kept as hints, replayed once they are back.
+    ///
+    /// A failed write is not rolled back from the owner and the replicas that applied it,
+    /// since the hints kept may still apply it on the others, and fails as partially
+    /// applied instead.
+    pub async fn replicate(&self, key: RingId, entry: ReplicaEntry, required: usize) -> Result<()> {
+        if self.factor == 1 {
+            return Ok(());
+        }
+        let this = self.membership.this().await;
+        let view = self.view().await;
+        let mut acks = 1;
+        for member in view.holders(self.placement, key, self.factor) {
+            if member.id == this.id {
+                continue;
+            }
+            let result = if member.state == MemberState::Suspect as i32 {
+                Err(Error::Internal("Member is suspected.".into()))
+            } else {
+                self.push(member, vec![entry.clone()]).await
+            };
+            match result {
+                Ok(()) => acks += 1,
+                Err(err) => {
+                    warn!(
+                        "Replicating key {:x} on {} failed, keeping a hint: {}",
+                        key, member.addr, err
+                    );
+                    self.hints
+                        .add(decode_id(&member.id)?, entry.clone())
+                        .await?;
+                }
+            }
+        }
+
+        if acks < required {
+            return Err(Error::PartiallyApplied(format!(
+                "Write of key {:x} was only applied on {} of the {} replicas required.",
+                key, acks, required
+            )));
+        }
+        Ok(())
+    }
+
+    async fn client(&self, member: &Member) -> Result<DhtNodeClient<Channel>> {
+        self.membership
+            .client(&Node {
+                id: member.id.clone(),
+                addr: member.addr.clone(),
+            })
+            .await
+    }
+
+    async fn push(&self, member: &Member, entries: Vec<ReplicaEntry>) -> Result<()> {
+        send(self.client(member).await?, &member.addr, entries).await
+    }
+
+    /// Copies keys to a member in the background of foreground requests: in batches, each
+    /// waiting for the throttle.
+    async fn copy(&self, member: &Member, entries: Vec<ReplicaEntry>) -> Result<()> {
+        for batch in entries.chunks(COPY_BATCH) {
+            let bytes = batch.iter().map(throttle::replica_weight).sum();
+            self.throttle.acquire(batch.len() as u64, bytes).await;
+            self.push(member, batch.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Applies writes received from the owners of the keys, or repairs from the nodes that
+    /// read them, merging them into the copy held.
+    pub async fn apply(&self, entry: ReplicaEntry) -> Result<()> {
+        let key = decode_id(&entry.key)?;
+        let value = Versioned {
+            value: entry
+                .value
+                .ok_or(Error::Parse("Replica entry without value.".into()))?,
+            version: entry.version,
+        };
+        if entry.deleted {
+            self.store.merge_deleted(&key, value).await?;
+        } else {
+            self.store.merge(&key, value).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the key from the members holding it until `required` of them answered and one
+    /// of them had the key, returning the newest version seen, or all the siblings seen with
+    /// vector clocks. Used when the owner cannot be reached, and by the owner, passed as
+    /// `skip` along with its `local` copy since it already answered, to confirm reads with
+    /// the replicas.
+    ///
+    /// Members that answered with another version, or without the key, are sent the one
+    /// returned in the background.
+    pub async fn read(
+        &self,
+        key: RingId,
+        required: usize,
+        skip: Option<RingId>,
+        local: Option<Versioned>,
+    ) -> Result<Option<Versioned>> {
+        let view = self.view().await;
+        let mut answers = skip.is_some() as usize;
+        let mut newest = local;
+        let mut read = Vec::new();
+        for member in view.holders(self.placement, key, self.factor) {
+            if answers >= required && newest.is_some() {
+                break;
+            }
+            if skip.is_some_and(|skip| member.id == encode_id(skip)) {
+                continue;
+            }
+            let client = self.client(member).await?;
+            match read_from(client.clone(), &member.addr, key).await {
+                Ok(result) => {
+                    answers += 1;
+                    // A replica may not have the key yet, in which case the next one is tried.
+                    let version = result.value.is_some().then_some(result.version);
+                    if let Some(value) = result.value {
+                        let value = Versioned {
+                            value,
+                            version: result.version,
+                        };
+                        if let Some(merged) =
+                            self.store.versioning().merge(newest.as_ref(), value)?
+                        {
+                            info!("Read key {:x} from replica {}", key, member.addr);
+                            newest = Some(merged);
+                        }
+                    }
+                    read.push((member.addr.clone(), client, version));
+                }
+                Err(err) => warn!(
+                    "Reading key {:x} from replica {} failed: {}",
+                    key, member.addr, err
+                ),
+            }
+        }
+
+        if answers < required {
+            return Err(Error::Unavailable(format!(
+                "Read of key {:x} was answered by {} of the {} replicas required.",
+                key, answers, required
+            )));
+        }
+
+        if let Some(newest) = &newest {
+            let stale: Vec<_> = read
+                .into_iter()
+                .filter(|(_, _, version)| *version != Some(newest.version))
+                .collect();
+            if !stale.is_empty() {
+                let entry = replica_entry(key, Some(newest));
+                tokio::spawn(async move {
+                    for (addr, client, _) in stale {
+                        match send(client, &addr, vec![entry.clone()]).await {
+                            Ok(()) => info!("Repaired key {:x} on replica {}", key, addr),
+                            Err(err) => {
+                                warn!(
+                                    "Repairing key {:x} on replica {} failed: {}",
+                                    key, addr, err
+                                )
+                            }
+                        }
+                    }
+                });
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Passes writes made on the owner of their keys, the head of the chain of its token
+    /// `head`, down to the nodes succeeding it, returning once the tail of the chain
+    /// applied them.
+    pub async fn write_chain(
+        &self,
+        vnodes: &VirtualNodes,
+        head: RingId,
+        entries: Vec<ReplicaEntry>,
+    ) -> Result<()> {
+        let this = decode_id(&self.membership.this().await.id)?;
+        self.pass_down(vnodes, entries, head, head, vec![this])
+            .await
+    }
+
+    /// Makes a write on behalf of the head of the key's chain when it cannot be reached,
+    /// keeping a hint for it and passing the write down the rest of the chain.
+    pub async fn write_around(
+        &self,
+        vnodes: &VirtualNodes,
+        key: RingId,
+        value: Option<Vec<u8>>,
+        context: Option<&[u8]>,
+    ) -> Result<()> {
+        let this = decode_id(&self.membership.this().await.id)?;
+        let current = self.store.get(&key).await;
+        let deleted = self.store.deleted(&key).await;
+        let versioning = self.store.versioning();
+        let entry = match value {
+            Some(value) => {
+                let written =
+                    versioning.write(this, current.as_ref(), deleted.as_ref(), value, context)?;
+                replica_entry(key, Some(&written))
+            }
+            None => tombstone_entry(key, &versioning.delete(current.as_ref(), deleted.as_ref())?),
+        };
+        let view = self.view().await;
+        let (head, owner) = HashRing::closest_preceding(&view.ring, key)
+            .map(|(token, owner)| (token, *owner))
+            .ok_or(Error::Unavailable("No tokens on the ring.".into()))?;
+        self.hints.add(owner, entry.clone()).await?;
+        self.pass_down(vnodes, vec![entry], head, head, vec![owner])
+            .await
+    }
+
+    /// Applies writes received from the previous member of the chain and passes them on.
+    pub async fn apply_chain(&self, vnodes: &VirtualNodes, write: ChainWrite) -> Result<()> {
+        for entry in &write.entries {
+            self.apply(entry.clone()).await?;
+        }
+        let (head, token) = (decode_id(&write.head)?, decode_id(&write.token)?);
+        self.pass_down(
+            vnodes,
+            write.entries,
+            head,
+            token,
+            decode_ids(&write.chain)?,
+        )
+        .await
+    }
+
+    /// Returns the first token after `token` held by a node not in the chain yet, along
+    /// with that node, or `None` once the chain went round to its head. The virtual nodes
+    /// of this node follow their links to their next neighbor, while the tokens of other
+    /// nodes are stepped over through the ring of the members.
+    async fn successor(
+        &self,
+        vnodes: &VirtualNodes,
+        view: &View,
+        head: RingId,
+        mut token: RingId,
+        chain: &[RingId],
+    ) -> Option<(RingId, RingId)> {
+        for _ in 0..=view.ring.len() {
+            let vnode = vnodes.read().await.get(&token).cloned();
+            let linked = match vnode {
+                Some(vnode) => vnode
+                    .neighbors
+                    .next
+                    .read()
+                    .await
+                    .as_ref()
+                    .map(|next| next.id),
+                None => None,
+            };
+            let next = linked
+                .filter(|next| view.ring.contains_key(next))
+                .or_else(|| view.next_token(token))?;
+            if next == head {
+                return None;
+            }
+            let node = view.ring[&next];
+            if !chain.contains(&node) {
+                return Some((next, node));
+            }
+            token = next;
+        }
+        None
+    }
+
+    /// Returns the nodes making up the chain of the token `head`, the head first.
+    async fn chain(&self, vnodes: &VirtualNodes, view: &View, head: RingId) -> Vec<RingId> {
+        let mut chain: Vec<RingId> = view.ring.get(&head).copied().into_iter().collect();
+        let mut token = head;
+        while chain.len() < self.factor {
+            match self.successor(vnodes, view, head, token, &chain).await {
+                Some((next, node)) => {
+                    chain.push(node);
+                    token = next;
+                }
+                None => break,
+            }
+        }
+        chain
+    }
+
+    /// Passes writes down the chain of `head` from `token`, the token of its last member
+    /// in `chain`, to the next member that can be reached. The members skipped are kept
+    /// hints for and the next one takes their place, but the writes fail as partially
+    /// applied unless the tail of the chain applied them, as they are not rolled back from
+    /// the members that did.
+    async fn pass_down(
+        &self,
+        vnodes: &VirtualNodes,
+        entries: Vec<ReplicaEntry>,
+        head: RingId,
+        mut token: RingId,
+        mut chain: Vec<RingId>,
+    ) -> Result<()> {
+        let this = decode_id(&self.membership.this().await.id)?;
+        let view = self.view().await;
+        let mut skipped = None;
+        while chain.len() < self.factor {
+            let (next, node) = match self.successor(vnodes, &view, head, token, &chain).await {
+                Some(successor) => successor,
+                None => break,
+            };
+            chain.push(node);
+            token = next;
+            // The chain reaches this node again after going around members before it.
+            if node == this {
+                for entry in &entries {
+                    self.apply(entry.clone()).await?;
+                }
+                skipped = None;
+                continue;
+            }
+
+            let write = ChainWrite {
+                entries: entries.clone(),
+                chain: encode_ids(&chain),
+                head: encode_id(head),
+                token: encode_id(next),
+            };
+            let hops = (self.factor - chain.len() + 1) as u32;
+            match self.send_chain(&view, node, write, hops).await {
+                Ok(()) => return Ok(()),
+                Err(err @ Error::PartiallyApplied(_)) => return Err(err),
+                Err(err) => {
+                    warn!(
+                        "Skipping #{:x} in the chain of #{:x}, keeping hints: {}",
+                        node, head, err
+                    );
+                    for entry in &entries {
+                        self.hints.add(node, entry.clone()).await?;
+                    }
+                    skipped = Some(node);
+                }
+            }
+        }
+
+        match skipped {
+            Some(tail) => Err(Error::PartiallyApplied(format!(
+                "Writes down the chain of #{:x} did not reach #{:x}, its tail.",
+                head, tail
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends writes to the next member of a chain, waiting for the `hops` members from it
+    /// to the tail to apply them.
+    async fn send_chain(
+        &self,
+        view: &View,
+        node: RingId,
+        write: ChainWrite,
+        hops: u32,
+    ) -> Result<()> {
+        let member = match view.members.get(&node) {
+            Some(member) if member.state == MemberState::Alive as i32 => member,
+            _ => return Err(Error::Internal("Member is not alive.".into())),
+        };
+        let mut client = self.client(member).await?;
+        let request = client.replicate_chain(Request::new(write));
+        match tokio::time::timeout(REPLICATE_TIMEOUT * hops, request).await {
+            Ok(Ok(_)) => Ok(()),
+            // The member applied the writes but they did not reach the tail after it.
+            Ok(Err(status)) if status.code() == Code::Unknown => {
+                Err(Error::PartiallyApplied(status.message().into()))
+            }
+            Ok(Err(status)) => Err(status.into()),
+            Err(_) => Err(Error::Internal("Write timed out.".into())),
+        }
+    }
+
+    /// Reads the key from the tail of the chain of `head`, the token owning it, which only
+    /// has writes every member applied. This node answers with its `local` copy, or the
+    /// copy it holds as a replica, if it is the tail. Without `head`, the chain is the one
+    /// of the key's owner.
+    pub async fn read_chain(
+        &self,
+        vnodes: &VirtualNodes,
+        key: RingId,
+        head: Option<RingId>,
+        local: Option<Versioned>,
+    ) -> Result<Option<Versioned>> {
+        let this = decode_id(&self.membership.this().await.id)?;
+        let view = self.view().await;
+        let head = match head {
+            Some(head) => head,
+            None => HashRing::closest_preceding(&view.ring, key)
+                .map(|(token, _)| token)
+                .ok_or(Error::Unavailable("No tokens on the ring.".into()))?,
+        };
+        let tail = self
+            .chain(vnodes, &view, head)
+            .await
+            .last()
+            .copied()
+            .unwrap_or(this);
+        if tail == this {
+            return Ok(match local {
+                Some(local) => Some(local),
+                None => self.store.get(&key).await,
+            });
+        }
+
+        let member = &view.members[&tail];
+        let client = self.client(member).await?;
+        match read_from(client, &member.addr, key).await {
+            Ok(result) => Ok(result.value.map(|value| Versioned {
+                value,
+                version: result.version,
+            })),
+            Err(err) => Err(Error::Unavailable(format!(
+                "Reading key {:x} from {}, the tail of its chain, failed: {}",
+                key, member.addr, err
+            ))),
+        }
+    }
+
+    /// Passes writes to keys this node owns down the chains of the virtual nodes owning
+    /// them, in batches, so that no member gets writes the members before it miss.
+    async fn write_chains(&self, vnodes: &VirtualNodes, entries: Vec<ReplicaEntry>) -> Result<()> {
+        let mut chains: HashMap<RingId, Vec<ReplicaEntry>> = HashMap::new();
+        for entry in entries {
+            if let Some(vnode) = owning_vnode(vnodes, decode_id(&entry.key)?).await {
+                chains.entry(vnode.id).or_default().push(entry);
+            }
+        }
+        for (head, entries) in chains {
+            for batch in entries.chunks(CHAIN_BATCH) {
+                let bytes = batch.iter().map(throttle::replica_weight).sum();
+                self.throttle.acquire(batch.len() as u64, bytes).await;
+                self.write_chain(vnodes, head, batch.to_vec()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the replicas whenever the members of the network or their tokens change,
+    /// and periodically repairs the replicas of the keys this node owns.
+    pub async fn run(&self, vnodes: Arc<VirtualNodes>) {
+        if self.factor == 1 {
+            return;
+        }
+        let mut interval = tokio::time::interval(SYNC_PERIOD);
+        let mut anti_entropy = tokio::time::interval(ANTI_ENTROPY_PERIOD);
+        anti_entropy.tick().await;
+        let mut last = Vec::new();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = anti_entropy.tick() => {
+                    self.anti_entropy(&vnodes).await;
+                    continue;
+                }
+            }
+            self.replay_hints().await;
+
+            let mut members: Vec<(Vec<u8>, Vec<Vec<u8>>)> = self
+                .membership
+                .members()
+                .await
+                .into_iter()
+                .map(|member| (member.id, member.tokens))
+                .collect();
+            members.sort();
+            if members == last {
+                continue;
+            }
+            last = members;
+
+            if let Err(err) = self.sync(&vnodes).await {
+                warn!("Rebuilding replicas failed: {}", err);
+            }
+            // A node that became the owner of keys collects the copies its successors hold.
+            self.anti_entropy(&vnodes).await;
+        }
+    }
+
+    /// Returns the copies this node holds of the keys the member owns.
+    pub async fn copies_of(&self, owner: RingId) -> Vec<(RingId, Versioned)> {
+        let view = self.view().await;
+        let owner = encode_id(owner);
+        self.store
+            .list()
+            .await
+            .into_iter()
+            .filter(|(key, _)| {
+                view.holders(self.placement, *key, self.factor)
+                    .first()
+                    .is_some_and(|holder| holder.id == owner)
+            })
+            .collect()
+    }
+
+    /// Compares the keys this node owns with the copies each member holding some of them
+    /// has, through Merkle trees over their versions, and exchanges only the keys of the
+    /// buckets that differ. The newest version wins on both sides: copies of keys this node
+    /// deleted since are deleted through their tombstones, while copies of keys it has no
+    /// trace of, as when it became their owner, are taken over. With chain replication, the
+    /// repairs go down the chains of the keys rather than to the member compared with.
+    pub async fn anti_entropy(&self, vnodes: &VirtualNodes) {
+        let this = self.membership.this().await;
+        let view = self.view().await;
+
+        let mut owned = Vec::new();
+        for vnode in vnodes.read().await.values() {
+            owned.extend(vnode.store.list().await);
+        }
+        let mut shared: HashMap<&[u8], Vec<(RingId, Versioned)>> = view
+            .members
+            .values()
+            .filter(|member| member.id != this.id && member.state == MemberState::Alive as i32)
+            .map(|member| (member.id.as_slice(), Vec::new()))
+            .collect();
+        for (key, value) in owned {
+            for member in view.holders(self.placement, key, self.factor) {
+                if let Some(entries) = shared.get_mut(member.id.as_slice()) {
+                    entries.push((key, value.clone()));
+                }
+            }
+        }
+
+        for member in view.members.values() {
+            let entries = match shared.remove(member.id.as_slice()) {
+                Some(entries) => entries,
+                None => continue,
+            };
+            if let Err(err) = self.exchange(vnodes, &this.id, member, entries).await {
+                warn!(
+                    "Comparing keys with replica {} failed: {}",
+                    member.addr, err
+                );
+            }
+        }
+    }
+
+    async fn exchange(
+        &self,
+        vnodes: &VirtualNodes,
+        owner: &[u8],
+        member: &Member,
+        entries: Vec<(RingId, Versioned)>,
+    ) -> Result<()> {
+        let ours = MerkleTree::build(entries.iter().map(|(key, value)| (*key, value.version)));
+        let mut client = self.client(member).await?;
+        let request = MerkleRequest {
+            owner: owner.to_vec(),
+            buckets: Vec::new(),
+        };
+        let theirs = client.get_merkle_tree(request).await?.into_inner();
+        let buckets = ours.diff(&MerkleTree::from_nodes(theirs.nodes)?);
+        if buckets.is_empty() {
+            return Ok(());
+        }
+
+        let request = MerkleRequest {
+            owner: owner.to_vec(),
+            buckets: buckets.iter().map(|bucket| *bucket as u32).collect(),
+        };
+        let mut stream = client.read_buckets(request).await?.into_inner();
+        let mut theirs = HashMap::new();
+        while let Some(entry) = stream.message().await? {
+            let key = decode_id(&entry.key)?;
+            if let Some(value) = entry.value {
+                let value = Versioned {
+                    value,
+                    version: entry.version,
+                };
+                theirs.insert(key, value.version);
+                // The tombstone of a key deleted here keeps its copy from being merged back.
+                if let Some(vnode) = owning_vnode(vnodes, key).await {
+                    vnode.store.merge(&key, value).await?;
+                }
+            }
+        }
+
+        // Keys are compared with what this node holds now, their copies having been merged
+        // into it and other writes possibly made since the keys were listed.
+        let mut keys: Vec<RingId> = entries
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| buckets.binary_search(&MerkleTree::bucket(*key)).is_ok())
+            .chain(theirs.keys().copied())
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut pushes = Vec::new();
+        for key in keys {
+            let (current, deleted) = match owning_vnode(vnodes, key).await {
+                Some(vnode) => (vnode.store.get(&key).await, vnode.store.deleted(&key).await),
+                None => (None, None),
+            };
+            let theirs = theirs.get(&key).copied();
+            if theirs.is_some() && theirs != current.as_ref().map(|current| current.version) {
+                if let Some(deleted) = &deleted {
+                    pushes.push(tombstone_entry(key, deleted));
+                }
+            }
+            if let Some(current) = current.filter(|current| theirs != Some(current.version)) {
+                pushes.push(replica_entry(key, Some(&current)));
+            }
+        }
+
+        info!(
+            "Repairing {} keys in {} buckets on replica {}",
+            pushes.len(),
+            buckets.len(),
+            member.addr
+        );
+        match self.mode {
+            ReplicationMode::Chain => self.write_chains(vnodes, pushes).await,
+            _ => self.copy(member, pushes).await,
+        }
+    }
+
+    /// Replays the writes kept for each replica the failure detector sees alive again.
+    /// Hints for members that left the network or were declared dead are dropped: their
+    /// keys are copied to the members replacing them, and to them again if they rejoin.
+    async fn replay_hints(&self) {
+        for target in self.hints.targets().await {
+            let member = match self.membership.member(target).await {
+                Some(member) => member,
+                None => continue,
+            };
+            let entries = self.hints.get(target).await;
+            let count = entries.len();
+            match MemberState::from_i32(member.state) {
+                Some(MemberState::Alive) => match self.push(&member, entries).await {
+                    Ok(()) => info!("Replayed {} hints on {}", count, member.addr),
+                    Err(err) => {
+                        warn!("Replaying hints on {} failed: {}", member.addr, err);
+                        continue;
+                    }
+                },
+                Some(MemberState::Left | MemberState::Dead) => {
+                    info!(
+                        "Dropping {} hints for {}, which is gone",
+                        count, member.addr
+                    )
+                }
+                _ => continue,
+            }
+
+            if let Err(err) = self.hints.remove(target, count).await {
+                warn!("Removing hints for {} failed: {}", member.addr, err);
+            }
+        }
+    }
+
+    /// Copies the keys this node owns to the members now holding them, down their chains
+    /// with chain replication, drops copies it no longer holds, and takes over the copies
+    /// of keys it became the owner of. Owners that did not hold a copy collect them from
+    /// their successors through anti-entropy.
+    async fn sync(&self, vnodes: &VirtualNodes) -> Result<()> {
+        let this = self.membership.this().await;
+        let view = self.view().await;
+
+        // Tombstones are copied along with the keys, so replicas that missed a deletion
+        // apply it.
+        let mut owned = Vec::new();
+        for vnode in vnodes.read().await.values() {
+            for (key, deleted) in vnode.store.list_deleted().await {
+                owned.push((key, tombstone_entry(key, &deleted)));
+            }
+            for (key, value) in vnode.store.list().await {
+                owned.push((key, replica_entry(key, Some(&value))));
+            }
+        }
+
+        // Copies are listed before any is dropped, since dropping a key also drops its
+        // tombstone.
+        let mut copies: Vec<_> = self
+            .store
+            .list_deleted()
+            .await
+            .into_iter()
+            .map(|(key, deleted)| (key, tombstone_entry(key, &deleted)))
+            .collect();
+        for (key, value) in self.store.list().await {
+            copies.push((key, replica_entry(key, Some(&value))));
+        }
+        for (key, entry) in copies {
+            let holders = view.holders(self.placement, key, self.factor);
+            match holders.iter().position(|member| member.id == this.id) {
+                None => {
+                    self.store.forget(&key).await;
+                }
+                Some(0) => {
+                    if let Some(vnode) = owning_vnode(vnodes, key).await {
+                        info!("Taking over key {:x} from a missing owner", key);
+                        let value = Versioned {
+                            value: entry.value.clone().unwrap_or_default(),
+                            version: entry.version,
+                        };
+                        if entry.deleted {
+                            vnode.store.merge_deleted(&key, value).await?;
+                            owned.push((key, entry));
+                        } else {
+                            vnode.store.merge(&key, value).await?;
+                            if let Some(value) = vnode.store.get(&key).await {
+                                owned.push((key, replica_entry(key, Some(&value))));
+                            }
+                        }
+                        self.store.forget(&key).await;
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        if self.mode == ReplicationMode::Chain {
+            let entries = owned.into_iter().map(|(_, entry)| entry).collect();
+            return self.write_chains(vnodes, entries).await;
+        }
+        let mut pushes: HashMap<RingId, Vec<ReplicaEntry>> = HashMap::new();
+        for (key, entry) in owned {
+            for member in view.holders(self.placement, key, self.factor) {
+                if member.id != this.id {
+                    pushes
+                        .entry(decode_id(&member.id)?)
+                        .or_default()
+                        .push(entry.clone());
+                }
+            }
+        }
+
+        for (id, entries) in pushes {
+            let member = &view.members[&id];
+            info!("Copying {} keys to replica {}", entries.len(), member.addr);
+            if let Err(err) = self.copy(member, entries).await {
+                warn!("Copying keys to replica {} failed: {}", member.addr, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_required() {
+    let node = Node {
+        id: encode_id(1),
+        addr: "http://0.0.0.0:50001".into(),
+    };
+    let membership = Arc::new(Membership::new(&node, vec![1], 1.0));
+
+    let replication = Replication::new(
+        3,
+        Placement::Ring,
+        ReplicationMode::Owner,
+        membership.clone(),
+        Versioning::Timestamp,
+        Hints::default(),
+        Arc::new(Throttle::new(Default::default())),
+    );
+    assert_eq!(replication.required(Consistency::One), 1);
+    assert_eq!(replication.required(Consistency::Quorum), 2);
+    assert_eq!(replication.required(Consistency::All), 3);
+
+    let replication = Replication::new(
+        4,
+        Placement::Ring,
+        ReplicationMode::Owner,
+        membership,
+        Versioning::Timestamp,
+        Hints::default(),
+        Arc::new(Throttle::new(Default::default())),
+    );
+    assert_eq!(replication.required(Consistency::Quorum), 3);
+}
+
+#[tokio::test]
+async fn test_chain_fails_without_tail() -> Result<()> {
+    let node = Node {
+        id: encode_id(1),
+        addr: "http://0.0.0.0:50001".into(),
+    };
+    let membership = Arc::new(Membership::new(&node, vec![0x10], 1.0));
+    let replication = Replication::new(
+        3,
+        Placement::Ring,
+        ReplicationMode::Chain,
+        membership.clone(),
+        Versioning::Timestamp,
+        Hints::default(),
+        Arc::new(Throttle::new(Default::default())),
+    );
+    let vnodes = VirtualNodes::default();
+    let entry = replica_entry(
+        0x20,
+        Some(&Versioned {
+            value: b"a".to_vec(),
+            version: 1,
+        }),
+    );
+
+    // A head alone on the ring is its own tail.
+    replication
+        .write_chain(&vnodes, 0x10, vec![entry.clone()])
+        .await?;
+
+    // A tail that cannot be reached fails the write, and is kept a hint for.
+    membership
+        .merge(vec![Member {
+            id: encode_id(2),
+            addr: "http://127.0.0.1:1".into(),
+            tokens: encode_ids(&[0x80]),
+            capacity: 1.0,
+            ..Default::default()
+        }])
+        .await;
+    let result = replication.write_chain(&vnodes, 0x10, vec![entry]).await;
+    assert!(matches!(result, Err(Error::PartiallyApplied(_))));
+    assert_eq!(replication.hints.targets().await, vec![2]);
+    assert!(matches!(
+        replication
+            .read_chain(&vnodes, 0x20, Some(0x10), None)
+            .await,
+        Err(Error::Unavailable(_))
+    ));
+    Ok(())
+}
@@ -1,11 +1,12 @@
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, sync::Arc};
 
 use crustyring::{
-    dht::service::DhtNodeService,
+    dht::{config::NodeConfig, service::DhtNodeService},
     error::{Error, Result},
     rpc::dht::dht_node_server::DhtNodeServer,
 };
-use log::info;
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
 use tonic::transport::Server;
 
 #[tokio::main]
@@ -20,14 +21,36 @@ async fn main() -> Result<()> {
     let hostname = std::env::var("NODE_HOSTNAME").unwrap_or("0.0.0.0".to_owned());
     let public_addr = format!("http://{}:{}", hostname, port);
 
+    let config = NodeConfig::from_env()?;
+
+    // Any further arguments are addresses of seed nodes to join through instead of the registry.
+    let seeds = &args[2..];
+
     info!("Initializing node on {}", public_addr);
-    let service = DhtNodeService::new(public_addr).await?;
+    let service = if seeds.is_empty() {
+        DhtNodeService::new(public_addr, config).await?
+    } else {
+        DhtNodeService::join(public_addr, seeds, config).await?
+    };
+    let service = Arc::new(service);
     let addr: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
-    
+    let mut sigterm = signal(SignalKind::terminate())?;
+
     Server::builder()
-        .add_service(DhtNodeServer::new(service))
-        .serve(addr)
+        .add_service(DhtNodeServer::from_arc(service.clone()))
+        .serve_with_shutdown(addr, async move {
+            tokio::select! {
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM");
+                    if let Err(err) = service.leave().await {
+                        error!("Failed to leave the network gracefully: {}", err);
+                    }
+                }
+                _ = service.wait_shutdown() => {}
+            }
+        })
         .await?;
 
+    info!("Node shut down.");
     Ok(())
 }
@@ -1,92 +1,470 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::error::{Error, Result};
-use crate::hash::generate_hash64;
+use crate::hash::{generate_id_hash, generate_tokens, KeyHash};
 use crate::registry::REGISTRY_PORT;
-use crate::rpc::registry::{ConnectionAddr, Node};
-use crate::HashRing;
+use crate::rpc::registry::{ClusterInfo, ConnectionAddr, Node};
+use crate::{
+    decode_id, decode_ids, encode_id, encode_ids, HashRing, Placement, Rendezvous, RingId,
+};
+
+use super::config::{check_cluster_info, NodeConfig, ReplicationMode};
 
 use log::{info, warn};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, Notify, RwLock};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::transport::Channel;
-use tonic::{Request, Response, Status};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Response, Status, Streaming};
 
 use crate::rpc::registry::registry_client::RegistryClient;
 
 use crate::rpc::dht::dht_node_client::DhtNodeClient;
 use crate::rpc::dht::dht_node_server::DhtNode;
 use crate::rpc::dht::{
-    EncodedQuery, KeyValueEntry, NeighborRegisterInfo, NeighborType, NodeId, OperationType,
-    PreviousNeighbors, Query, QueryResult,
+    AppendRequest, AppendResponse, ChainWrite, Consistency, EncodedQuery, Gossip, HandoffEntry,
+    Hop, IndirectPing, KeyBatch, KeyValueEntry, Member, Members, MerkleRequest, MerkleTree,
+    MigrationLimits, NeighborRegisterInfo, NeighborType, NodeId, NodeLoad, OperationType,
+    PreviousNeighbors, Query, QueryResult, RaftQueryRequest, ReplicaEntry, TakeRequest, TokenLoad,
+    TokenMove, TokenRange, VoteRequest, VoteResponse,
 };
 
-use super::store::Store;
+use super::hints::Hints;
+use super::membership::Membership;
+use super::merkle;
+use super::raft::Raft;
+use super::replication::{replica_entry, tombstone_entry, Replication};
+use super::store::Versioned;
+use super::throttle::{self, Throttle};
+use super::transfer;
+use super::versioning::Versioning;
+use super::vnode::{Migration, MigrationState, Neighbor, VirtualNode};
 
-#[derive(Debug)]
-pub struct Neighbor {
-    id: u64,
-    addr: String,
-    client: DhtNodeClient<Channel>,
+pub type VirtualNodes = RwLock<BTreeMap<RingId, Arc<VirtualNode>>>;
+
+/// Where a request for a key should go next.
+enum Route {
+    Local(Arc<VirtualNode>),
+    /// Forwarded by the virtual node `from` to one of its neighbors.
+    Remote {
+        from: RingId,
+        neighbor: Box<Neighbor>,
+    },
 }
 
-#[derive(Debug)]
-pub struct NeighborConnections {
-    prev: RwLock<Option<Neighbor>>,
-    next: RwLock<Option<Neighbor>>,
+/// Returns the ids and capacities of the members that keys are placed on by rendezvous hashing.
+fn rendezvous_nodes(members: &[Member]) -> Vec<(RingId, f64)> {
+    members
+        .iter()
+        .filter_map(|member| Some((decode_id(&member.id).ok()?, member.capacity)))
+        .collect()
+}
+
+fn key_not_present() -> Error {
+    Error::Value("Key not present in database.".into())
+}
+
+/// Builds the answer to a query from the value found, along with its version.
+fn query_result(result: Result<Option<Versioned>>) -> QueryResult {
+    let (value, version) = match &result {
+        Ok(Some(value)) => (Some(value.value.clone()), value.version),
+        _ => (None, 0),
+    };
+    QueryResult {
+        value,
+        error: result.err().map(|e| e.to_string()),
+        path: Vec::new(),
+        version,
+        siblings: Vec::new(),
+        context: None,
+    }
+}
+
+/// Builds the answer to a client's query. In vector clock mode the siblings stored for the
+/// key are listed, along with the context to write over all of them with.
+fn answer(versioning: Versioning, result: Result<Option<Versioned>>) -> QueryResult {
+    if versioning == Versioning::Timestamp {
+        return query_result(result);
+    }
+    let result = result.and_then(|value| match value {
+        Some(value) => Ok(Some((versioning.values(&value)?, value.version))),
+        None => Ok(None),
+    });
+    match result {
+        Ok(Some((values, version))) => QueryResult {
+            value: values.siblings.first().cloned(),
+            error: None,
+            path: Vec::new(),
+            version,
+            siblings: values.siblings,
+            context: values.context,
+        },
+        Ok(None) => query_result(Ok(None)),
+        Err(err) => query_result(Err(err)),
+    }
+}
+
+fn format_path(path: &[RingId]) -> String {
+    path.iter()
+        .map(|id| format!("#{:x}", id))
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// Records a request being forwarded by the virtual node `from`. Requests that already went
+/// through it, or took more than `max_hops` hops, are rejected: they would only go around
+/// the ring until the hops run out.
+fn forward(
+    req: &mut EncodedQuery,
+    path: &mut Vec<RingId>,
+    from: RingId,
+    max_hops: u32,
+) -> Result<()> {
+    let key = decode_id(&req.key)?;
+    if path.contains(&from) {
+        return Err(Error::RoutingLoop(format!(
+            "Request for key {:x} came back to #{:x}: {} -> #{:x}.",
+            key,
+            from,
+            format_path(path),
+            from
+        )));
+    }
+    path.push(from);
+    req.path = encode_ids(path);
+    req.hops += 1;
+    if req.hops > max_hops {
+        return Err(Error::TtlExceeded(format!(
+            "Request for key {:x} exceeded {} hops: {}.",
+            key,
+            max_hops,
+            format_path(path)
+        )));
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct DhtNodeService {
-    id: u64,
+    id: RingId,
     addr: String,
 
-    store: Arc<Store>,
-    neighbors: Arc<NeighborConnections>,
+    vnodes: Arc<VirtualNodes>,
+    membership: Arc<Membership>,
+    replication: Arc<Replication>,
+    replication_mode: ReplicationMode,
+    /// Raft groups this node is a member of, when keys are replicated through Raft.
+    raft: Option<Arc<Raft>>,
+    /// Limits the rate keys are sent to other nodes at when they move.
+    throttle: Arc<Throttle>,
+
+    registry: Option<RegistryClient<Channel>>,
+
+    /// Hops a request may take before it is rejected.
+    max_hops: u32,
 
-    registry: RegistryClient<Channel>,
+    /// Hash function placing keys on the ring, agreed on by the whole cluster.
+    key_hash: KeyHash,
+    placement: Placement,
+    versioning: Versioning,
+    cluster: ClusterInfo,
+    /// Settings the node was started with, used to persist its tokens when they move.
+    config: NodeConfig,
+
+    shutdown: Notify,
 }
 
 impl DhtNodeService {
-    pub async fn new(addr: String) -> Result<Self> {
+    pub async fn new(addr: String, config: NodeConfig) -> Result<Self> {
         let mut registry = Self::try_connect_registry().await?;
 
         info!("Registering on registry...");
+        let held_tokens = config.persisted_tokens()?.unwrap_or_default();
         let node_info = registry
-            .register_node(Request::new(ConnectionAddr { addr: addr.clone() }))
+            .register_node(Request::new(ConnectionAddr {
+                addr: addr.clone(),
+                tokens: config.tokens,
+                capacity: config.capacity,
+                id: config.identity()?.map(encode_id),
+                cluster: Some(config.cluster_info()?),
+                held_tokens: encode_ids(&held_tokens),
+            }))
             .await?;
         let node_info = node_info.get_ref().clone();
+        let id = decode_id(&node_info.id)?;
+        let tokens = decode_ids(&node_info.tokens)?;
+        config.persist_identity(id, &tokens)?;
+        info!("Registered as #{:x} with {} tokens", id, tokens.len());
+
+        let entries = node_info.neighbor.into_iter().map(|n| n.addr).collect();
+        Self::init(id, addr, tokens, &config, entries, Some(registry)).await
+    }
+
+    /// Joins the network without a registry by looking up the position of each token
+    /// through any of the seed nodes. If no seed other than the node itself is given,
+    /// a new ring is started.
+    pub async fn join(addr: String, seeds: &[String], config: NodeConfig) -> Result<Self> {
+        let id = match config.identity()? {
+            Some(id) => id,
+            None => generate_id_hash(&addr)?,
+        };
+        // Tokens held before a restart are kept, wherever the rebalancer moved them.
+        let tokens = match config.persisted_tokens()? {
+            Some(tokens) => tokens,
+            None => generate_tokens(
+                id,
+                HashRing::weighted_tokens(config.tokens.max(1), config.capacity),
+            )?,
+        };
+        info!("Joining as #{:x} with {} tokens", id, tokens.len());
+
+        let seeds = seeds
+            .iter()
+            .filter(|seed| **seed != addr)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Without a registry to reject the node, refuse to join if its settings differ
+        // from the cluster's or if any token is already taken on the ring.
+        if !seeds.is_empty() {
+            let cluster = Self::cluster_info_through_seeds(&seeds).await?;
+            check_cluster_info(&config.cluster_info()?, &cluster)?;
+        }
+        if !seeds.is_empty() && config.placement == Placement::Ring {
+            Self::check_tokens_free(&addr, &tokens, &seeds).await?;
+        }
+        config.persist_identity(id, &tokens)?;
+
+        Self::init(id, addr, tokens, &config, seeds, None).await
+    }
+
+    /// Fails if any of the tokens is held by a node on another address. The node itself may
+    /// still be known to hold them when it restarts.
+    async fn check_tokens_free(addr: &str, tokens: &[RingId], seeds: &[String]) -> Result<()> {
+        for token in tokens {
+            let owner = Self::find_owner_through_seeds(*token, seeds).await?;
+            if decode_id(&owner.id)? == *token && owner.addr != addr {
+                return Err(Error::Config(format!(
+                    "Token #{:x} is already taken by {}.",
+                    token, owner.addr
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets up the node's virtual nodes, either joining them to the ring through the
+    /// entry nodes or, if there are none, linking them to each other in a new ring.
+    /// With rendezvous placement the node has no tokens and a single virtual node on
+    /// its id stores its keys.
+    async fn init(
+        id: RingId,
+        addr: String,
+        tokens: Vec<RingId>,
+        config: &NodeConfig,
+        entries: Vec<String>,
+        registry: Option<RegistryClient<Channel>>,
+    ) -> Result<Self> {
         let node = Node {
-            id: node_info.id,
-            addr: addr.clone(),
+            id: encode_id(id),
+            addr,
+        };
+        let vnodes = Arc::new(RwLock::new(BTreeMap::new()));
+        let tokens = match config.placement {
+            Placement::Ring => tokens,
+            Placement::Rendezvous => Vec::new(),
         };
-        info!("Registered as #{:x}", node.id);
 
-        let neighbors = Arc::new(NeighborConnections {
-            prev: RwLock::new(None),
-            next: RwLock::new(None),
+        let membership = Arc::new(Membership::new(&node, tokens.clone(), config.capacity));
+        tokio::spawn({
+            let membership = membership.clone();
+            async move { membership.run().await }
         });
 
-        let store = Arc::new(Store::new());
+        let throttle = Arc::new(Throttle::new(config.migration_limits.clone()));
+        let replication = Arc::new(Replication::new(
+            config.replication,
+            config.placement,
+            config.replication_mode,
+            membership.clone(),
+            config.versioning,
+            Hints::load(config.hints_file.clone())?,
+            throttle.clone(),
+        ));
+        let raft = match config.replication_mode {
+            ReplicationMode::Owner | ReplicationMode::Chain => {
+                tokio::spawn({
+                    let replication = replication.clone();
+                    let vnodes = vnodes.clone();
+                    async move { replication.run(vnodes).await }
+                });
+                None
+            }
+            ReplicationMode::Raft => {
+                let raft = Arc::new(Raft::new(
+                    id,
+                    config.replication,
+                    membership.clone(),
+                    config.raft_file.as_deref(),
+                )?);
+                raft.start_groups(&tokens).await?;
+                tokio::spawn(raft.clone().run());
+                Some(raft)
+            }
+        };
 
-        if let Some(neighbor) = node_info.neighbor {
+        if config.placement == Placement::Rendezvous {
+            vnodes
+                .write()
+                .await
+                .insert(id, Arc::new(VirtualNode::new(id, config.versioning)));
+            if entries.is_empty() {
+                info!("No other nodes to join through, starting a new cluster.");
+            } else {
+                tokio::spawn(Self::join_members(
+                    node.clone(),
+                    vnodes.clone(),
+                    membership.clone(),
+                    entries,
+                ));
+            }
+        } else if entries.is_empty() {
+            info!("No other nodes to join through, starting a new ring.");
+            Self::start_ring(&node, &vnodes, tokens, config.versioning).await?;
+        } else {
             tokio::spawn(Self::setup_connections(
                 node.clone(),
-                store.clone(),
-                neighbors.clone(),
-                neighbor,
+                vnodes.clone(),
+                membership.clone(),
+                tokens,
+                config.versioning,
+                entries,
             ));
         }
 
         Ok(DhtNodeService {
-            id: node.id,
+            id,
             addr: node.addr,
-            store,
-            neighbors,
+            vnodes,
+            membership,
+            replication,
+            replication_mode: config.replication_mode,
+            raft,
+            throttle,
             registry,
+            max_hops: config.max_hops,
+            key_hash: config.key_hash.clone(),
+            placement: config.placement,
+            versioning: config.versioning,
+            cluster: config.cluster_info()?,
+            config: config.clone(),
+            shutdown: Notify::new(),
         })
     }
 
+    /// Links the virtual nodes to each other, forming a ring on their own.
+    async fn start_ring(
+        node: &Node,
+        vnodes: &VirtualNodes,
+        mut tokens: Vec<RingId>,
+        versioning: Versioning,
+    ) -> Result<()> {
+        tokens.sort_unstable();
+        let client = DhtNodeClient::new(Endpoint::from_shared(node.addr.clone())?.connect_lazy());
+        let neighbor = |id: RingId| Neighbor {
+            id,
+            addr: node.addr.clone(),
+            client: client.clone(),
+        };
+
+        let mut vnodes = vnodes.write().await;
+        for (i, token) in tokens.iter().enumerate() {
+            let vnode = VirtualNode::new(*token, versioning);
+            if tokens.len() > 1 {
+                let prev = tokens[(i + tokens.len() - 1) % tokens.len()];
+                let next = tokens[(i + 1) % tokens.len()];
+                *vnode.neighbors.prev.write().await = Some(neighbor(prev));
+                *vnode.neighbors.next.write().await = Some(neighbor(next));
+            }
+            vnodes.insert(*token, Arc::new(vnode));
+        }
+        Ok(())
+    }
+
+    /// Looks up the token owning the id through the first seed node that answers.
+    pub async fn find_owner_through_seeds(id: RingId, seeds: &[String]) -> Result<Node> {
+        for seed in seeds {
+            info!("Looking up #{:x} through seed {}...", id, seed);
+            let mut client = match DhtNodeClient::connect(seed.to_string()).await {
+                Ok(client) => client,
+                Err(_) => {
+                    warn!("Connection to seed {} failed.", seed);
+                    continue;
+                }
+            };
+
+            let request = Request::new(NodeId { id: encode_id(id) });
+            match client.find_owner(request).await {
+                Ok(node) => {
+                    let node = node.into_inner();
+                    info!("Found #{:x} on {}", decode_id(&node.id)?, node.addr);
+                    return Ok(node);
+                }
+                Err(err) => warn!("Lookup through seed {} failed: {}", seed, err),
+            }
+        }
+        Err(Error::Config(
+            "None of the seed nodes could be reached.".into(),
+        ))
+    }
+
+    /// Asks the first reachable seed node for the settings of the cluster.
+    async fn cluster_info_through_seeds(seeds: &[String]) -> Result<ClusterInfo> {
+        for seed in seeds {
+            let mut client = match DhtNodeClient::connect(seed.to_string()).await {
+                Ok(client) => client,
+                Err(_) => {
+                    warn!("Connection to seed {} failed.", seed);
+                    continue;
+                }
+            };
+
+            match client.get_cluster_info(Request::new(())).await {
+                Ok(cluster) => return Ok(cluster.into_inner()),
+                Err(err) => warn!(
+                    "Fetching cluster settings from seed {} failed: {}",
+                    seed, err
+                ),
+            }
+        }
+        Err(Error::Config(
+            "None of the seed nodes could be reached.".into(),
+        ))
+    }
+
+    /// Asks the first reachable seed node for the members of the network.
+    async fn members_through_seeds(seeds: &[String]) -> Result<Vec<Member>> {
+        for seed in seeds {
+            let mut client = match DhtNodeClient::connect(seed.to_string()).await {
+                Ok(client) => client,
+                Err(_) => {
+                    warn!("Connection to seed {} failed.", seed);
+                    continue;
+                }
+            };
+
+            match client.get_members(Request::new(())).await {
+                Ok(members) => return Ok(members.into_inner().members),
+                Err(err) => warn!("Fetching members from seed {} failed: {}", seed, err),
+            }
+        }
+        Err(Error::Config(
+            "None of the seed nodes could be reached.".into(),
+        ))
+    }
+
     pub async fn try_connect_registry() -> Result<RegistryClient<Channel>> {
         info!("Connecting to registry...");
         let hostname = std::env::var("REGISTRY_HOSTNAME").unwrap_or("0.0.0.0".to_owned());
@@ -111,11 +489,11 @@ impl DhtNodeService {
     }
 
     pub async fn try_connect_node(node: &Node) -> Result<DhtNodeClient<Channel>> {
-        info!("Connecting to node #{:x}...", node.id);
+        info!("Connecting to node {}...", node.addr);
         for attempt in 1..=5 {
             match DhtNodeClient::connect(node.addr.clone()).await {
                 Ok(client) => {
-                    info!("Connected to node #{:x}.", node.id);
+                    info!("Connected to node {}.", node.addr);
                     return Ok(client);
                 }
                 Err(_) => {
@@ -130,181 +508,687 @@ impl DhtNodeService {
         Err(Error::Internal("Connection to node failed.".into()))
     }
 
-    pub async fn get_node_info(node: &RwLock<Option<Neighbor>>) -> Option<Node> {
-        let node = node.read().await;
-        node.as_ref().map(|n| Node {
-            id: n.id,
-            addr: n.addr.clone(),
-        })
+    /// Waits for the node's own server to accept connections, since lookups made
+    /// while joining the remaining tokens may be routed back to it.
+    async fn wait_until_serving(addr: &str) {
+        while DhtNodeClient::connect(addr.to_owned()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
     }
 
     pub async fn setup_connections(
         node: Node,
-        store: Arc<Store>,
-        neighbors: Arc<NeighborConnections>,
-        prev_neighbor: Node,
+        vnodes: Arc<VirtualNodes>,
+        membership: Arc<Membership>,
+        tokens: Vec<RingId>,
+        versioning: Versioning,
+        entries: Vec<String>,
+    ) -> Result<()> {
+        Self::wait_until_serving(&node.addr).await;
+
+        for token in tokens {
+            let vnode = Arc::new(VirtualNode::new(token, versioning));
+            let prev_neighbor = Self::find_owner_through_seeds(token, &entries).await?;
+            let next_neighbor = Self::link(&node, &vnodes, &vnode, &prev_neighbor).await?;
+            Self::get_keys_from_neighbor(&vnode, &next_neighbor).await?;
+        }
+
+        // Members are asked to the entry nodes, as the neighbors may all be on this node.
+        membership
+            .merge(Self::members_through_seeds(&entries).await?)
+            .await;
+        Ok(())
+    }
+
+    /// Joins a cluster placing keys by rendezvous hashing: learns its members through the
+    /// entry nodes, then takes from each of them the keys this node now wins.
+    pub async fn join_members(
+        node: Node,
+        vnodes: Arc<VirtualNodes>,
+        membership: Arc<Membership>,
+        entries: Vec<String>,
     ) -> Result<()> {
-        Self::connect_to_neighbors(&node, &neighbors, &prev_neighbor).await?;
-        Self::get_keys_from_neighbor(&node, &neighbors, &store).await?;
+        Self::wait_until_serving(&node.addr).await;
+
+        let members = Self::members_through_seeds(&entries).await?;
+        membership.merge(members.clone()).await;
+
+        let id = decode_id(&node.id)?;
+        let vnode = vnodes
+            .read()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or(Error::Internal(format!("Node #{:x} has no store.", id)))?;
+        let this = membership.this().await;
+        for member in members.into_iter().filter(|member| member.id != node.id) {
+            let mut client = Self::try_connect_node(&Node {
+                id: member.id.clone(),
+                addr: member.addr.clone(),
+            })
+            .await?;
+            let count = transfer::take(&mut client, this.clone(), &vnode).await?;
+            info!("Took {} keys from {}", count, member.addr);
+        }
         Ok(())
     }
 
+    /// Adds a virtual node to this node and links it into the ring after `owner`, the token
+    /// owning its position until then, returning its next neighbor. The virtual node is
+    /// added first so that requests its neighbors send it once linked find it, and until it
+    /// is linked it sends requests on to the owner.
+    async fn link(
+        node: &Node,
+        vnodes: &VirtualNodes,
+        vnode: &Arc<VirtualNode>,
+        owner: &Node,
+    ) -> Result<Node> {
+        *vnode.neighbors.prev.write().await = Some(Neighbor {
+            id: decode_id(&owner.id)?,
+            addr: owner.addr.clone(),
+            client: DhtNodeClient::new(Endpoint::from_shared(owner.addr.clone())?.connect_lazy()),
+        });
+        vnodes.write().await.insert(vnode.id, vnode.clone());
+
+        let result = Self::connect_to_neighbors(node, vnode, owner).await;
+        if result.is_err() {
+            vnodes.write().await.remove(&vnode.id);
+        }
+        result
+    }
+
+    /// Links the virtual node between its previous neighbor and that neighbor's next one,
+    /// returning the latter.
     pub async fn connect_to_neighbors(
         node: &Node,
-        neighbors: &Arc<NeighborConnections>,
+        vnode: &VirtualNode,
         prev_neighbor: &Node,
-    ) -> Result<()> {
+    ) -> Result<Node> {
         let previous_neighbors =
-            Self::register_on_neighbor(&node, &neighbors, &prev_neighbor, NeighborType::Next)
-                .await?;
+            Self::register_on_neighbor(node, vnode, prev_neighbor, NeighborType::Next).await?;
         let next_neighbor = previous_neighbors.next.unwrap_or(prev_neighbor.clone());
+        // The previous neighbor already sends it the keys it now owns, which it serves
+        // from then on rather than sending them back.
+        *vnode.neighbors.next.write().await = Some(Neighbor {
+            id: decode_id(&next_neighbor.id)?,
+            addr: next_neighbor.addr.clone(),
+            client: DhtNodeClient::new(
+                Endpoint::from_shared(next_neighbor.addr.clone())?.connect_lazy(),
+            ),
+        });
         let _ =
-            Self::register_on_neighbor(&node, &neighbors, &next_neighbor, NeighborType::Previous)
-                .await?;
-        Ok(())
+            Self::register_on_neighbor(node, vnode, &next_neighbor, NeighborType::Previous).await?;
+        Ok(next_neighbor)
     }
 
-    pub async fn get_keys_from_neighbor(
-        node: &Node,
-        neighbors: &Arc<NeighborConnections>,
-        store: &Arc<Store>,
-    ) -> Result<()> {
-        let prev_neighbor = neighbors.prev.read().await;
-        if let Some(prev_neighbor) = (*prev_neighbor).as_ref() {
-            let mut stream = prev_neighbor
-                .client
-                .clone()
-                .transfer_keys(Request::new(NodeId { id: node.id }))
-                .await?
-                .into_inner();
-
-            while let Some(kv_entry) = stream.message().await? {
-                store.set(&kv_entry.key, &kv_entry.value).await;
-            }
+    pub async fn get_keys_from_neighbor(vnode: &VirtualNode, next_neighbor: &Node) -> Result<()> {
+        let prev_neighbor = vnode.neighbors.prev.read().await.clone();
+        if let Some(prev_neighbor) = prev_neighbor {
+            let end = decode_id(&next_neighbor.id)?;
+            let range = TokenRange {
+                token: encode_id(prev_neighbor.id),
+                start: encode_id(vnode.id),
+                end: next_neighbor.id.clone(),
+                after: None,
+            };
+            // Until every key is received, the previous owner answers for the missing ones.
+            let migration = Migration::new(
+                MigrationState::Importing,
+                vnode.id,
+                end,
+                prev_neighbor.clone(),
+            );
+            vnode.start_migration(migration).await;
+            let result = transfer::pull(&mut prev_neighbor.client.clone(), range, vnode).await;
+            vnode
+                .end_migration(MigrationState::Importing, vnode.id)
+                .await;
+            result?;
         }
         Ok(())
     }
 
     pub async fn register_on_neighbor(
         node: &Node,
-        neighbors: &Arc<NeighborConnections>,
+        vnode: &VirtualNode,
         neighbor: &Node,
         ty: NeighborType,
     ) -> Result<PreviousNeighbors> {
         let mut client = Self::try_connect_node(neighbor).await?;
+        let neighbor_id = decode_id(&neighbor.id)?;
 
         info!(
-            "Registering as {} on #{:x}...",
+            "Registering #{:x} as {} on #{:x}...",
+            vnode.id,
             match ty {
                 NeighborType::Next => "next",
                 NeighborType::Previous => "previous",
             },
-            neighbor.id
+            neighbor_id
         );
         let previous_neighbors = client
             .register_as_neighbor(Request::new(NeighborRegisterInfo {
                 ty: ty.into(),
-                id: node.id,
+                id: encode_id(vnode.id),
                 addr: node.addr.clone(),
+                target: neighbor.id.clone(),
             }))
             .await?;
 
         let mut guard = match ty {
-            NeighborType::Next => neighbors.prev.write().await,
-            NeighborType::Previous => neighbors.next.write().await,
+            NeighborType::Next => vnode.neighbors.prev.write().await,
+            NeighborType::Previous => vnode.neighbors.next.write().await,
         };
         *guard = Some(Neighbor {
-            id: neighbor.id,
+            id: neighbor_id,
             addr: neighbor.addr.clone(),
             client,
         });
 
-        info!("Registered on #{:x}", neighbor.id);
+        info!("Registered #{:x} on #{:x}", vnode.id, neighbor_id);
 
         Ok(previous_neighbors.get_ref().clone())
     }
 
-    pub async fn switch_neighbor(&self, info: &NeighborRegisterInfo) -> Result<()> {
-        let ty = NeighborType::from_i32(info.ty).ok_or(Error::Internal(format!(
-            "Neighbor type {} is not valid",
-            info.ty
-        )))?;
-        let neighbor = match ty {
-            NeighborType::Previous => &self.neighbors.prev,
-            NeighborType::Next => &self.neighbors.next,
+    async fn get_vnode(&self, token: RingId) -> Result<Arc<VirtualNode>> {
+        self.vnodes
+            .read()
+            .await
+            .get(&token)
+            .cloned()
+            .ok_or(Error::Value(format!(
+                "Token #{:x} is not on node #{:x}.",
+                token, self.id
+            )))
+    }
+
+    /// Leaves the network gracefully: for each of its tokens, hands the keys over to
+    /// the previous neighbor and links previous and next neighbors to each other. Then
+    /// deregisters from the registry and finally signals the server to shut down.
+    pub async fn leave(&self) -> Result<()> {
+        info!("Leaving the network...");
+
+        if self.placement == Placement::Rendezvous {
+            self.leave_members().await?;
+        } else {
+            let vnodes: Vec<Arc<VirtualNode>> =
+                self.vnodes.read().await.values().cloned().collect();
+            for vnode in vnodes {
+                self.leave_ring(&vnode).await?;
+            }
+        }
+
+        if let Some(registry) = &self.registry {
+            info!("Deregistering from registry...");
+            registry
+                .clone()
+                .deregister_node(Request::new(Node {
+                    id: encode_id(self.id),
+                    addr: self.addr.clone(),
+                }))
+                .await?;
+        }
+        self.membership.leave().await;
+        info!("Left the network.");
+
+        self.shutdown.notify_one();
+        Ok(())
+    }
+
+    /// Removes a virtual node from the ring, handing its keys over to its previous neighbor.
+    /// Writes are refused from then on, so the keys listed are all there is to hand over.
+    async fn leave_ring(&self, vnode: &VirtualNode) -> Result<()> {
+        vnode.close().await;
+        // Neighbors are cloned so no lock is held while they register each other on us.
+        let prev_neighbor = vnode.neighbors.prev.read().await.clone();
+        let next_neighbor = vnode.neighbors.next.read().await.clone();
+
+        match (prev_neighbor, next_neighbor) {
+            (Some(prev_neighbor), Some(next_neighbor)) => {
+                let entries = transfer::entries(&vnode.store, |_| true).await;
+                self.handoff_keys(vnode.id, entries, &prev_neighbor).await?;
+
+                info!(
+                    "Linking #{:x} and #{:x} to each other...",
+                    prev_neighbor.id, next_neighbor.id
+                );
+                prev_neighbor
+                    .client
+                    .clone()
+                    .register_as_neighbor(Request::new(NeighborRegisterInfo {
+                        ty: NeighborType::Next.into(),
+                        id: encode_id(next_neighbor.id),
+                        addr: next_neighbor.addr.clone(),
+                        target: encode_id(prev_neighbor.id),
+                    }))
+                    .await?;
+                next_neighbor
+                    .client
+                    .clone()
+                    .register_as_neighbor(Request::new(NeighborRegisterInfo {
+                        ty: NeighborType::Previous.into(),
+                        id: encode_id(prev_neighbor.id),
+                        addr: prev_neighbor.addr.clone(),
+                        target: encode_id(next_neighbor.id),
+                    }))
+                    .await?;
+            }
+            _ => warn!(
+                "No neighbors to hand keys of #{:x} over to, keys will be lost.",
+                vnode.id
+            ),
+        }
+
+        self.vnodes.write().await.remove(&vnode.id);
+        Ok(())
+    }
+
+    /// Hands each key over to the member that wins it once this node is gone.
+    async fn leave_members(&self) -> Result<()> {
+        let vnode = self.get_vnode(self.id).await?;
+        vnode.close().await;
+        let members: Vec<Member> = self
+            .membership
+            .members()
+            .await
+            .into_iter()
+            .filter(|member| member.id != encode_id(self.id))
+            .collect();
+        let nodes = rendezvous_nodes(&members);
+        if nodes.is_empty() {
+            warn!("No members to hand keys over to, keys will be lost.");
+            return Ok(());
+        }
+
+        let mut handoffs: HashMap<RingId, Vec<(RingId, KeyValueEntry)>> = HashMap::new();
+        for (key, entry) in transfer::entries(&vnode.store, |_| true).await {
+            if let Some(owner) = Rendezvous::owner(&nodes, key) {
+                handoffs.entry(owner).or_default().push((key, entry));
+            }
+        }
+
+        for member in members {
+            let id = decode_id(&member.id)?;
+            if let Some(entries) = handoffs.remove(&id) {
+                let neighbor = Neighbor {
+                    id,
+                    addr: member.addr.clone(),
+                    client: DhtNodeClient::new(Endpoint::from_shared(member.addr)?.connect_lazy()),
+                };
+                self.handoff_keys(self.id, entries, &neighbor).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the virtual node on the given token to a new position on the ring. The new
+    /// position is joined first, taking its keys from its previous neighbor, and only
+    /// then is the old one left, handing its keys over to its own previous neighbor.
+    pub async fn move_token(&self, token: RingId, target: RingId) -> Result<()> {
+        if self.placement != Placement::Ring {
+            return Err(Error::Value(
+                "Tokens are only used by ring placement.".into(),
+            ));
+        }
+        let vnode = self.get_vnode(token).await?;
+        let owner = self.find_owner(target).await?;
+        if decode_id(&owner.id)? == target {
+            return Err(Error::Value(format!(
+                "Token #{:x} is already taken by {}.",
+                target, owner.addr
+            )));
+        }
+
+        info!("Moving token #{:x} to #{:x}...", token, target);
+        let node = Node {
+            id: encode_id(self.id),
+            addr: self.addr.clone(),
         };
+        let new_vnode = Arc::new(VirtualNode::new(target, self.versioning));
+        if let Some(raft) = &self.raft {
+            raft.start_groups(&[target]).await?;
+        }
+        let next_neighbor = Self::link(&node, &self.vnodes, &new_vnode, &owner).await?;
+        Self::get_keys_from_neighbor(&new_vnode, &next_neighbor).await?;
 
-        let client = DhtNodeService::try_connect_node(&Node {
-            id: info.id,
-            addr: info.addr.clone(),
-        })
-        .await?;
-        let mut neighbor = neighbor.write().await;
-        *neighbor = Some(Neighbor {
-            id: info.id,
-            addr: info.addr.clone(),
-            client,
-        });
+        self.leave_ring(&vnode).await?;
 
+        let tokens: Vec<RingId> = self.vnodes.read().await.keys().copied().collect();
+        self.config.persist_identity(self.id, &tokens)?;
+        // The registration is renewed with the moved token, so the registry checks
+        // the tokens of joining nodes against where it is now.
+        if let Some(registry) = &self.registry {
+            registry
+                .clone()
+                .register_node(Request::new(ConnectionAddr {
+                    addr: self.addr.clone(),
+                    tokens: self.config.tokens,
+                    capacity: self.config.capacity,
+                    id: Some(encode_id(self.id)),
+                    cluster: Some(self.cluster.clone()),
+                    held_tokens: encode_ids(&tokens),
+                }))
+                .await?;
+        }
+        self.membership.set_tokens(tokens).await;
+        info!("Moved token #{:x} to #{:x}", token, target);
+        Ok(())
+    }
+
+    /// Returns the number of keys stored and queries executed by each virtual node.
+    pub async fn load(&self) -> NodeLoad {
+        let vnodes: Vec<Arc<VirtualNode>> = self.vnodes.read().await.values().cloned().collect();
+        let mut tokens = Vec::with_capacity(vnodes.len());
+        for vnode in vnodes {
+            tokens.push(TokenLoad {
+                token: encode_id(vnode.id),
+                keys: vnode.store.len().await as u64,
+                requests: vnode.requests.load(Ordering::Relaxed),
+            });
+        }
+
+        NodeLoad {
+            id: encode_id(self.id),
+            addr: self.addr.clone(),
+            capacity: self.membership.this().await.capacity,
+            tokens,
+        }
+    }
+
+    /// Resolves once the node has left the network and the server should shut down.
+    pub async fn wait_shutdown(&self) {
+        self.shutdown.notified().await
+    }
+
+    async fn handoff_keys(
+        &self,
+        from: RingId,
+        entries: Vec<(RingId, KeyValueEntry)>,
+        neighbor: &Neighbor,
+    ) -> Result<()> {
         info!(
-            "Replaced {} neighbor with #{:x}",
-            {
-                match ty {
-                    NeighborType::Previous => "previous",
-                    NeighborType::Next => "next",
-                }
-            },
-            info.id
+            "Handing {} keys of #{:x} over to #{:x}...",
+            entries.len(),
+            from,
+            neighbor.id
         );
 
+        let token = encode_id(neighbor.id);
+        let throttle = self.throttle.clone();
+        let (tx, rx) = mpsc::channel(throttle::BUFFER);
+        tokio::spawn(async move {
+            for (_, entry) in entries {
+                throttle.acquire(1, throttle::weight(&entry)).await;
+                let handoff_entry = HandoffEntry {
+                    token: token.clone(),
+                    entry: Some(entry),
+                };
+                if tx.send(handoff_entry).await.is_err() {
+                    break;
+                }
+            }
+        });
+        neighbor
+            .client
+            .clone()
+            .handoff_keys(ReceiverStream::new(rx))
+            .await?;
+
+        info!("Handed keys over to #{:x}", neighbor.id);
         Ok(())
     }
 
-    pub async fn execute_query(&self, query: &EncodedQuery) -> Result<Option<Vec<u8>>> {
-        let key = query.key;
+    /// Records this node as a hop of a traced request that arrived at `start`.
+    fn hop(&self, start: Instant) -> Hop {
+        Hop {
+            id: encode_id(self.id),
+            latency_micros: start.elapsed().as_micros() as u64,
+        }
+    }
 
-        info!("Executing query for key {:x}.", query.key);
+    /// Looks up the virtual node owning the id, wherever it is on the ring.
+    async fn find_owner(&self, id: RingId) -> Result<Node> {
+        match self.route(id).await? {
+            Route::Remote { neighbor, .. } => {
+                info!("Forwarding lookup for #{:x} to #{:x}", id, neighbor.id);
+                Ok(neighbor
+                    .client
+                    .clone()
+                    .find_owner(Request::new(NodeId { id: encode_id(id) }))
+                    .await?
+                    .into_inner())
+            }
+            Route::Local(vnode) => Ok(Node {
+                id: encode_id(vnode.id),
+                addr: self.addr.clone(),
+            }),
+        }
+    }
+
+    /// Finds where a request for the key should go. Only the closest virtual node
+    /// counter-clockwise from the key may own it, so routing starts from there.
+    async fn route(&self, key: RingId) -> Result<Route> {
+        if self.placement == Placement::Rendezvous {
+            return self.route_rendezvous(key).await;
+        }
 
-        match OperationType::from_i32(query.ty).unwrap() {
-            OperationType::Set => {
-                let value = query.value.clone();
-                match value {
-                    None => Err(Error::Value("Value not provided.".into())),
-                    Some(value) => Ok(self.store.set(&key, &value).await),
+        let vnodes = self.vnodes.read().await.clone();
+        let mut vnode = HashRing::closest_preceding(&vnodes, key)
+            .map(|(_, vnode)| vnode.clone())
+            .ok_or(Error::Internal(format!(
+                "Node #{:x} has no tokens on the ring.",
+                self.id
+            )))?;
+
+        // Hops to other virtual nodes of this node are taken here rather than sent back to it.
+        for _ in 0..vnodes.len() {
+            let neighbor = match vnode.next_hop(key).await? {
+                Some(neighbor) => neighbor,
+                None => return Ok(Route::Local(vnode)),
+            };
+            match vnodes.get(&neighbor.id) {
+                Some(local) if neighbor.addr == self.addr => vnode = local.clone(),
+                _ => {
+                    return Ok(Route::Remote {
+                        from: vnode.id,
+                        neighbor: Box::new(neighbor),
+                    })
                 }
             }
-            OperationType::Get => {
-                let result = self.store.get(&key).await;
-                match result {
-                    None => Err(Error::Value("Key not present in database.".into())),
-                    Some(_) => Ok(result),
+        }
+        Err(Error::Internal(format!(
+            "Routing key {:x} went round the tokens of node #{:x}.",
+            key, self.id
+        )))
+    }
+
+    /// Completes a query executed on the key's owner according to its consistency level:
+    /// writes are applied to the replicas, and reads confirmed by them, until as many
+    /// members as the level requires answered. The newest version read wins, and is
+    /// written back here if this node's copy was stale.
+    async fn coordinate(
+        &self,
+        key: RingId,
+        vnode: &VirtualNode,
+        req: &EncodedQuery,
+        result: Result<Option<Versioned>>,
+    ) -> Result<Option<Versioned>> {
+        if self.replication_mode == ReplicationMode::Chain {
+            return self.coordinate_chain(key, vnode, req, result).await;
+        }
+        let consistency = Consistency::from_i32(req.consistency).unwrap_or_default();
+        let required = self.replication.required(consistency);
+
+        match OperationType::from_i32(req.ty) {
+            Some(OperationType::Set) if result.is_ok() => {
+                let written = vnode.store.get(&key).await;
+                let entry = replica_entry(key, written.as_ref());
+                self.replication.replicate(key, entry, required).await?;
+                result
+            }
+            Some(OperationType::Delete) if result.is_ok() => {
+                if let Some(deleted) = vnode.store.deleted(&key).await {
+                    let entry = tombstone_entry(key, &deleted);
+                    self.replication.replicate(key, entry, required).await?;
                 }
+                result
+            }
+            Some(OperationType::Get) if required > 1 => {
+                let local = result.ok().flatten();
+                let newest = self
+                    .replication
+                    .read(key, required, Some(self.id), local)
+                    .await?
+                    .ok_or(key_not_present())?;
+                // Replicas that missed a deletion made here do not bring the key back.
+                let newest = vnode
+                    .store
+                    .surviving(&key, newest)
+                    .await?
+                    .ok_or(key_not_present())?;
+                vnode.store.merge(&key, newest.clone()).await?;
+                Ok(Some(newest))
             }
-            OperationType::Delete => {
-                let result = self.store.delete(&key).await;
-                match result {
-                    None => Err(Error::Value("Key not present in database.".into())),
-                    Some(_) => Ok(result),
+            _ => result,
+        }
+    }
+
+    /// Returns the nodes keys are placed on by rendezvous hashing once the member joined,
+    /// learning it first so that requests for the keys it takes are sent to it.
+    async fn nodes_with(&self, member: Member) -> Vec<(RingId, f64)> {
+        let id = decode_id(&member.id).ok();
+        let capacity = member.capacity;
+        self.membership.merge(vec![member]).await;
+        let mut nodes = rendezvous_nodes(&self.membership.members().await);
+        if let Some(id) = id.filter(|id| !nodes.iter().any(|(node, _)| node == id)) {
+            nodes.push((id, capacity));
+        }
+        nodes
+    }
+
+    fn raft(&self) -> Result<&Arc<Raft>> {
+        self.raft.as_ref().ok_or(Error::Config(
+            "Keys are not replicated through Raft on this node.".into(),
+        ))
+    }
+
+    /// Completes a query executed on the head of the key's chain: writes are passed down the
+    /// chain before they are acknowledged, and reads are answered by its tail.
+    async fn coordinate_chain(
+        &self,
+        key: RingId,
+        vnode: &VirtualNode,
+        req: &EncodedQuery,
+        result: Result<Option<Versioned>>,
+    ) -> Result<Option<Versioned>> {
+        match OperationType::from_i32(req.ty) {
+            Some(OperationType::Set) if result.is_ok() => {
+                let written = vnode.store.get(&key).await;
+                let entry = replica_entry(key, written.as_ref());
+                self.replication
+                    .write_chain(&self.vnodes, vnode.id, vec![entry])
+                    .await?;
+                result
+            }
+            Some(OperationType::Delete) if result.is_ok() => {
+                if let Some(deleted) = vnode.store.deleted(&key).await {
+                    let entry = tombstone_entry(key, &deleted);
+                    self.replication
+                        .write_chain(&self.vnodes, vnode.id, vec![entry])
+                        .await?;
                 }
+                result
             }
+            Some(OperationType::Get) => self
+                .replication
+                .read_chain(&self.vnodes, key, Some(vnode.id), result.ok().flatten())
+                .await?
+                .map(Some)
+                .ok_or(key_not_present()),
+            _ => result,
         }
     }
+
+    /// Executes a query whose owner cannot be reached on the rest of the key's chain: writes
+    /// enter at the first member that answers, reads are served by the tail.
+    async fn query_around(&self, key: RingId, req: &EncodedQuery) -> Result<Option<Versioned>> {
+        match OperationType::from_i32(req.ty) {
+            Some(OperationType::Set) => {
+                let value = req
+                    .value
+                    .clone()
+                    .ok_or(Error::Value("Value not provided.".into()))?;
+                let context = req.context.as_deref();
+                self.replication
+                    .write_around(&self.vnodes, key, Some(value), context)
+                    .await?;
+                Ok(None)
+            }
+            Some(OperationType::Delete) => {
+                self.replication
+                    .write_around(&self.vnodes, key, None, None)
+                    .await?;
+                Ok(None)
+            }
+            _ => self
+                .replication
+                .read_chain(&self.vnodes, key, None, None)
+                .await?
+                .map(Some)
+                .ok_or(key_not_present()),
+        }
+    }
+
+    /// Sends a request straight to the member winning the key by rendezvous hashing, among the
+    /// members not suspected of having failed.
+    async fn route_rendezvous(&self, key: RingId) -> Result<Route> {
+        let members = self.membership.alive_members().await;
+        let owner = Rendezvous::owner(&rendezvous_nodes(&members), key).unwrap_or(self.id);
+        if owner == self.id {
+            return Ok(Route::Local(self.get_vnode(self.id).await?));
+        }
+
+        let member = members
+            .into_iter()
+            .find(|member| member.id == encode_id(owner))
+            .ok_or(Error::Internal(format!("Member #{:x} not found.", owner)))?;
+        Ok(Route::Remote {
+            from: self.id,
+            neighbor: Box::new(Neighbor {
+                id: owner,
+                addr: member.addr.clone(),
+                client: DhtNodeClient::new(Endpoint::from_shared(member.addr)?.connect_lazy()),
+            }),
+        })
+    }
 }
 
 #[tonic::async_trait]
 impl DhtNode for DhtNodeService {
+    async fn find_owner(
+        &self,
+        request: Request<NodeId>,
+    ) -> std::result::Result<Response<Node>, Status> {
+        let id = decode_id(&request.get_ref().id)?;
+
+        info!("Received owner lookup for #{:x}", id);
+
+        Ok(Response::new(DhtNodeService::find_owner(self, id).await?))
+    }
+
     async fn register_as_neighbor(
         &self,
         request: Request<NeighborRegisterInfo>,
     ) -> std::result::Result<Response<PreviousNeighbors>, Status> {
         let register_info = request.into_inner();
+        let vnode = self.get_vnode(decode_id(&register_info.target)?).await?;
 
         let previous_neighbors = PreviousNeighbors {
-            prev: DhtNodeService::get_node_info(&self.neighbors.prev).await,
-            next: DhtNodeService::get_node_info(&self.neighbors.next).await,
+            prev: VirtualNode::get_node_info(&vnode.neighbors.prev).await,
+            next: VirtualNode::get_node_info(&vnode.neighbors.next).await,
         };
 
-        self.switch_neighbor(&register_info).await?;
+        vnode.switch_neighbor(&register_info).await?;
 
         Ok(Response::new(previous_neighbors))
     }
@@ -317,8 +1201,14 @@ impl DhtNode for DhtNodeService {
 
         self.forward_query(Request::new(EncodedQuery {
             ty: req.ty,
-            key: generate_hash64(&req.key)?,
+            key: encode_id(self.key_hash.hash(&req.key)?),
             value: req.value.clone(),
+            hops: 0,
+            entry: encode_id(self.id),
+            path: Vec::new(),
+            trace: req.trace,
+            consistency: req.consistency,
+            context: req.context.clone(),
         }))
         .await
     }
@@ -327,83 +1217,932 @@ impl DhtNode for DhtNodeService {
         &self,
         request: Request<EncodedQuery>,
     ) -> std::result::Result<Response<QueryResult>, Status> {
-        let req = request.get_ref();
+        let start = Instant::now();
+        let mut req = request.into_inner();
 
-        let key = req.key;
+        let key = decode_id(&req.key)?;
+        let entry = decode_id(&req.entry)?;
+        let mut path = decode_ids(&req.path)?;
+        let trace = req.trace;
 
         info!("Received request for key {:x}", key);
 
-        let next_neighbor = self.neighbors.next.read().await;
+        let forwarding_neighbor = match self.route(key).await? {
+            Route::Remote { from, neighbor } => {
+                if let Err(err) = forward(&mut req, &mut path, from, self.max_hops) {
+                    warn!("{} Entered on #{:x}.", err, entry);
+                    return Err(err.into());
+                }
+                neighbor
+            }
+            Route::Local(vnode) => match vnode.exporting_to(key).await {
+                // A request that raced with its range moving away follows it.
+                Some(peer) => {
+                    if let Err(err) = forward(&mut req, &mut path, vnode.id, self.max_hops) {
+                        warn!("{} Entered on #{:x}.", err, entry);
+                        return Err(err.into());
+                    }
+                    Box::new(peer)
+                }
+                None => {
+                    path.push(vnode.id);
+                    info!(
+                        "Request for key {:x} entered on #{:x} reached #{:x} after {} hops: {}",
+                        key,
+                        entry,
+                        vnode.id,
+                        req.hops,
+                        format_path(&path)
+                    );
+                    let result = match &self.raft {
+                        // The group of the owner's token executes the query wherever its
+                        // leader is.
+                        Some(raft) => raft.query(key, req).await,
+                        None => {
+                            let result = vnode.execute_query(&req).await;
+                            self.coordinate(key, &vnode, &req, result).await
+                        }
+                    };
+                    let mut query_result = answer(self.versioning, result);
+                    if trace {
+                        query_result.path.push(self.hop(start));
+                    }
 
-        let is_node_key = match next_neighbor.as_ref() {
-            Some(next_neighbor) => HashRing::is_node_key(self.id, next_neighbor.id, key),
-            None => true,
+                    return Ok(Response::new(query_result));
+                }
+            },
         };
 
-        if is_node_key {
-            let result = self.execute_query(&req).await;
-            let query_result = QueryResult {
-                value: result.clone().ok().flatten(),
-                error: result.err().map(|e| e.to_string()),
-            };
+        info!(
+            "Forwarding request for key {:x} to #{:x}",
+            key, forwarding_neighbor.id
+        );
+        let is_read = req.ty == OperationType::Get as i32;
+        let consistency = Consistency::from_i32(req.consistency).unwrap_or_default();
+        let query = req.clone();
+        let result = forwarding_neighbor
+            .client
+            .clone()
+            .forward_query(Request::new(req))
+            .await;
+        let mut query_result = match result {
+            Ok(query_result) => query_result.into_inner(),
+            // The group of the key is reached through another of its members.
+            Err(status) if status.code() == Code::Unavailable && self.raft.is_some() => {
+                warn!(
+                    "Forwarding request for key {:x} to #{:x} failed, going to its group: {}",
+                    key,
+                    forwarding_neighbor.id,
+                    status.message()
+                );
+                answer(self.versioning, self.raft()?.query(key, query).await)
+            }
+            // The chain of the key is reconfigured without the members that cannot be reached.
+            Err(status)
+                if status.code() == Code::Unavailable
+                    && self.replication_mode == ReplicationMode::Chain
+                    && self.replication.factor() > 1 =>
+            {
+                warn!(
+                    "Forwarding request for key {:x} to #{:x} failed, going around it: {}",
+                    key,
+                    forwarding_neighbor.id,
+                    status.message()
+                );
+                answer(self.versioning, self.query_around(key, &query).await)
+            }
+            // An unreachable owner is read around through the members holding its replicas.
+            Err(status)
+                if is_read
+                    && status.code() == Code::Unavailable
+                    && self.replication.factor() > 1 =>
+            {
+                warn!(
+                    "Forwarding request for key {:x} to #{:x} failed, reading a replica: {}",
+                    key,
+                    forwarding_neighbor.id,
+                    status.message()
+                );
+                let required = self.replication.required(consistency);
+                let result = self
+                    .replication
+                    .read(key, required, None, None)
+                    .await
+                    .and_then(|value| value.map(Some).ok_or(key_not_present()));
+                answer(self.versioning, result)
+            }
+            Err(status) => return Err(status),
+        };
+        // Hops are added on the way back, so each one goes before those further down the path.
+        if trace {
+            query_result.path.insert(0, self.hop(start));
+        }
+        Ok(Response::new(query_result))
+    }
 
-            return Ok(Response::new(query_result));
+    type TransferKeysStream = ReceiverStream<std::result::Result<KeyBatch, Status>>;
+
+    async fn transfer_keys(
+        &self,
+        request: Request<TokenRange>,
+    ) -> std::result::Result<Response<Self::TransferKeysStream>, Status> {
+        let range = request.into_inner();
+        let vnode = self.get_vnode(decode_id(&range.token)?).await?;
+        let start = decode_id(&range.start)?;
+        let end = decode_id(&range.end)?;
+        let after = range.after.as_deref().map(decode_id).transpose()?;
+
+        // Requests for the range are sent to the joining token until it commits the transfer.
+        let next = vnode.neighbors.next.read().await.clone();
+        if let Some(next) = next.filter(|next| next.id == start) {
+            let migration = Migration::new(MigrationState::Exporting, start, end, next);
+            vnode.start_migration(migration).await;
         }
 
-        let next_neighbor = next_neighbor.as_ref().ok_or(Error::Internal(format!(
-            "Missing next neighbor on node {:x}.",
-            self.id
-        )))?;
+        let (tx, rx) = mpsc::channel(throttle::BUFFER);
+        let throttle = self.throttle.clone();
 
-        let prev_neighbor = self.neighbors.prev.read().await;
-        let prev_neighbor = prev_neighbor.as_ref().ok_or(Error::Internal(format!(
-            "Missing previous neighbor on node {:x}.",
-            self.id
-        )))?;
+        tokio::spawn(async move {
+            let in_range = |key: RingId| HashRing::is_node_key(start, end, key);
+            let entries = transfer::entries(&vnode.store, in_range).await;
+            info!(
+                "Transferring keys of #{:x} from {:x} to {:x}",
+                vnode.id, start, end
+            );
+            // Keys are only copied, they are deleted once the whole range is committed.
+            for batch in transfer::batches(start, entries, after) {
+                let bytes = batch.entries.iter().map(throttle::weight).sum();
+                throttle.acquire(batch.entries.len() as u64, bytes).await;
+                if tx.send(Ok(batch)).await.is_err() {
+                    break;
+                }
+            }
+        });
 
-        // If counter_clockwise_distance from node to key
-        // < clockwise_distance from node to key
-        let forwarding_neighbor = if HashRing::counter_clockwise_distance(self.id, key)
-            < HashRing::counter_clockwise_distance(key, self.id)
-        {
-            prev_neighbor
-        } else {
-            next_neighbor
-        };
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 
+    async fn commit_transfer(
+        &self,
+        request: Request<TokenRange>,
+    ) -> std::result::Result<Response<()>, Status> {
+        let range = request.into_inner();
+        let vnode = self.get_vnode(decode_id(&range.token)?).await?;
+        let start = decode_id(&range.start)?;
+        let end = decode_id(&range.end)?;
+
+        let in_range = |key: RingId| HashRing::is_node_key(start, end, key);
+        let entries = transfer::entries(&vnode.store, in_range).await;
+        for (key, _) in &entries {
+            vnode.store.forget(key).await;
+        }
+        vnode.end_migration(MigrationState::Exporting, start).await;
         info!(
-            "Forwarding request for key {:x} to #{:x}",
-            key, forwarding_neighbor.id
+            "Deleted {} keys of #{:x} transferred from {:x} to {:x}",
+            entries.len(),
+            vnode.id,
+            start,
+            end
         );
-        forwarding_neighbor
-            .client
-            .clone()
-            .forward_query(request)
-            .await
+        Ok(Response::new(()))
     }
 
-    type TransferKeysStream = ReceiverStream<std::result::Result<KeyValueEntry, Status>>;
+    async fn handoff_keys(
+        &self,
+        request: Request<Streaming<HandoffEntry>>,
+    ) -> std::result::Result<Response<()>, Status> {
+        let mut stream = request.into_inner();
 
-    async fn transfer_keys(
+        info!("Receiving keys from a leaving neighbor...");
+        while let Some(handoff_entry) = stream.message().await? {
+            let vnode = self.get_vnode(decode_id(&handoff_entry.token)?).await?;
+            // Merged, so that neither writes made here nor deletions are overwritten.
+            if let Some(kv_entry) = handoff_entry.entry {
+                transfer::store_entry(&vnode.store, kv_entry).await?;
+            }
+        }
+        info!("Received keys from a leaving neighbor.");
+
+        Ok(Response::new(()))
+    }
+
+    async fn leave(&self, _request: Request<()>) -> std::result::Result<Response<()>, Status> {
+        DhtNodeService::leave(self).await?;
+        Ok(Response::new(()))
+    }
+
+    async fn ping(
+        &self,
+        request: Request<Gossip>,
+    ) -> std::result::Result<Response<Gossip>, Status> {
+        let gossip = self.membership.handle_ping(request.into_inner()).await;
+        Ok(Response::new(gossip))
+    }
+
+    async fn ping_req(
+        &self,
+        request: Request<IndirectPing>,
+    ) -> std::result::Result<Response<Gossip>, Status> {
+        let gossip = self
+            .membership
+            .handle_ping_req(request.into_inner())
+            .await?;
+        Ok(Response::new(gossip))
+    }
+
+    async fn get_members(
+        &self,
+        _request: Request<()>,
+    ) -> std::result::Result<Response<Members>, Status> {
+        Ok(Response::new(Members {
+            members: self.membership.members().await,
+        }))
+    }
+
+    async fn get_load(
+        &self,
+        _request: Request<()>,
+    ) -> std::result::Result<Response<NodeLoad>, Status> {
+        Ok(Response::new(self.load().await))
+    }
+
+    async fn move_token(
+        &self,
+        request: Request<TokenMove>,
+    ) -> std::result::Result<Response<()>, Status> {
+        let token_move = request.into_inner();
+        let token = decode_id(&token_move.token)?;
+        let target = decode_id(&token_move.target)?;
+        DhtNodeService::move_token(self, token, target).await?;
+        Ok(Response::new(()))
+    }
+
+    type TakeKeysStream = ReceiverStream<std::result::Result<KeyBatch, Status>>;
+
+    async fn take_keys(
+        &self,
+        request: Request<TakeRequest>,
+    ) -> std::result::Result<Response<Self::TakeKeysStream>, Status> {
+        let request = request.into_inner();
+        let member = request
+            .member
+            .ok_or(Error::Parse("Member not provided.".into()))?;
+        let after = request.after.as_deref().map(decode_id).transpose()?;
+        let id = decode_id(&member.id)?;
+        let vnode = self.get_vnode(self.id).await?;
+        let nodes = self.nodes_with(member).await;
+
+        let (tx, rx) = mpsc::channel(throttle::BUFFER);
+        let throttle = self.throttle.clone();
+
+        tokio::spawn(async move {
+            let won = |key: RingId| Rendezvous::owner(&nodes, key) == Some(id);
+            let entries = transfer::entries(&vnode.store, won).await;
+            info!("Transferring {} keys to #{:x}", entries.len(), id);
+            // Keys are only copied, they are deleted once the member committed taking them.
+            for batch in transfer::batches(0, entries, after) {
+                let bytes = batch.entries.iter().map(throttle::weight).sum();
+                throttle.acquire(batch.entries.len() as u64, bytes).await;
+                if tx.send(Ok(batch)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn commit_take(
+        &self,
+        request: Request<Member>,
+    ) -> std::result::Result<Response<()>, Status> {
+        let member = request.into_inner();
+        let id = decode_id(&member.id)?;
+        let vnode = self.get_vnode(self.id).await?;
+        let nodes = self.nodes_with(member).await;
+
+        let won = |key: RingId| Rendezvous::owner(&nodes, key) == Some(id);
+        let entries = transfer::entries(&vnode.store, won).await;
+        for (key, _) in &entries {
+            vnode.store.forget(key).await;
+        }
+        info!("Deleted {} keys taken by #{:x}", entries.len(), id);
+        Ok(Response::new(()))
+    }
+
+    async fn set_migration_limits(
+        &self,
+        request: Request<MigrationLimits>,
+    ) -> std::result::Result<Response<MigrationLimits>, Status> {
+        let limits = request.into_inner();
+        info!(
+            "Migrating keys at up to {} bytes/s and {} keys/s (0 is unlimited)",
+            limits.bytes_per_sec, limits.entries_per_sec
+        );
+        self.throttle.set_limits(limits).await;
+        Ok(Response::new(self.throttle.limits().await))
+    }
+
+    async fn replicate(
+        &self,
+        request: Request<Streaming<ReplicaEntry>>,
+    ) -> std::result::Result<Response<()>, Status> {
+        let mut stream = request.into_inner();
+        while let Some(entry) = stream.message().await? {
+            self.replication.apply(entry).await?;
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn replicate_chain(
+        &self,
+        request: Request<ChainWrite>,
+    ) -> std::result::Result<Response<()>, Status> {
+        self.replication
+            .apply_chain(&self.vnodes, request.into_inner())
+            .await?;
+        Ok(Response::new(()))
+    }
+
+    async fn read_replica(
         &self,
         request: Request<NodeId>,
-    ) -> std::result::Result<Response<Self::TransferKeysStream>, Status> {
-        let prev_id = self.id.clone();
-        let id = request.get_ref().id;
+    ) -> std::result::Result<Response<QueryResult>, Status> {
+        let key = decode_id(&request.get_ref().id)?;
+
+        // The key may have been taken over by this node since its owner went missing.
+        let mut value = self.replication.store.get(&key).await;
+        if value.is_none() {
+            for vnode in self.vnodes.read().await.values() {
+                value = vnode.store.get(&key).await;
+                if value.is_some() {
+                    break;
+                }
+            }
+        }
+
+        Ok(Response::new(query_result(
+            value.ok_or(key_not_present()).map(Some),
+        )))
+    }
+
+    async fn get_merkle_tree(
+        &self,
+        request: Request<MerkleRequest>,
+    ) -> std::result::Result<Response<MerkleTree>, Status> {
+        let owner = decode_id(&request.get_ref().owner)?;
+        let copies = self.replication.copies_of(owner).await;
+        let tree =
+            merkle::MerkleTree::build(copies.into_iter().map(|(key, value)| (key, value.version)));
+        Ok(Response::new(MerkleTree {
+            nodes: tree.nodes().to_vec(),
+        }))
+    }
+
+    type ReadBucketsStream = ReceiverStream<std::result::Result<ReplicaEntry, Status>>;
+
+    async fn read_buckets(
+        &self,
+        request: Request<MerkleRequest>,
+    ) -> std::result::Result<Response<Self::ReadBucketsStream>, Status> {
+        let request = request.into_inner();
+        let owner = decode_id(&request.owner)?;
+        let buckets: HashSet<usize> = request
+            .buckets
+            .into_iter()
+            .map(|bucket| bucket as usize)
+            .collect();
+        let copies = self.replication.copies_of(owner).await;
 
         let (tx, rx) = mpsc::channel(100);
 
-        let store = self.store.clone();
         tokio::spawn(async move {
-            let is_node_key = |key: u64| !HashRing::is_node_key(prev_id, id, key);
-            let entries = store.get_entries_satisfy(is_node_key).await;
-            info!("Transferring keys to {:x}", request.get_ref().id);
-            for (key, value) in entries {
-                store.delete(&key).await;
-                tx.send(Ok(KeyValueEntry { key, value })).await.unwrap();
+            for (key, value) in copies {
+                if buckets.contains(&merkle::MerkleTree::bucket(key)) {
+                    let entry = replica_entry(key, Some(&value));
+                    if tx.send(Ok(entry)).await.is_err() {
+                        break;
+                    }
+                }
             }
         });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn raft_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> std::result::Result<Response<VoteResponse>, Status> {
+        let raft = self.raft()?;
+        Ok(Response::new(raft.handle_vote(request.into_inner()).await?))
+    }
+
+    async fn raft_append(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> std::result::Result<Response<AppendResponse>, Status> {
+        let raft = self.raft()?;
+        Ok(Response::new(
+            raft.handle_append(request.into_inner()).await?,
+        ))
+    }
+
+    async fn raft_query(
+        &self,
+        request: Request<RaftQueryRequest>,
+    ) -> std::result::Result<Response<QueryResult>, Status> {
+        let raft = self.raft()?;
+        // Members that cannot serve the query let the sender try another one.
+        match raft.handle_query(request.into_inner()).await {
+            Err(err @ Error::Unavailable(_)) => Err(err.into()),
+            result => Ok(Response::new(query_result(result))),
+        }
+    }
+
+    async fn get_cluster_info(
+        &self,
+        _request: Request<()>,
+    ) -> std::result::Result<Response<ClusterInfo>, Status> {
+        Ok(Response::new(self.cluster.clone()))
+    }
+}
+
+/// Starts a node on a free local port, joining the ring through the seeds if there are any.
+#[cfg(test)]
+async fn spawn_node(seeds: &[String], config: NodeConfig) -> Result<Arc<DhtNodeService>> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let addr = format!("http://127.0.0.1:{}", port);
+    let service = Arc::new(DhtNodeService::join(addr, seeds, config).await?);
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(crate::rpc::dht::dht_node_server::DhtNodeServer::from_arc(
+                service.clone(),
+            ))
+            .serve(format!("127.0.0.1:{}", port).parse()?),
+    );
+    DhtNodeService::wait_until_serving(&service.addr).await;
+    Ok(service)
+}
+
+/// Waits for a node to know `count` members, the last thing it learns when joining.
+#[cfg(test)]
+async fn wait_members(service: &DhtNodeService, count: usize) {
+    while service.membership.members().await.len() < count {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+async fn query(
+    service: &DhtNodeService,
+    ty: OperationType,
+    key: &str,
+    value: Option<&str>,
+) -> Result<QueryResult> {
+    let request = Request::new(Query {
+        ty: ty.into(),
+        key: key.as_bytes().to_vec(),
+        value: value.map(|value| value.as_bytes().to_vec()),
+        trace: false,
+        consistency: Consistency::One.into(),
+        context: None,
+    });
+    Ok(service.query_dht(request).await?.into_inner())
+}
+
+#[tokio::test]
+async fn test_leave_hands_keys_over() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 2,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&second, 2).await;
+
+    for i in 0..64 {
+        let key = format!("key{}", i);
+        let result = query(&first, OperationType::Set, &key, Some(&key)).await?;
+        assert_eq!(result.error, None);
+    }
+    assert!(second
+        .load()
+        .await
+        .tokens
+        .iter()
+        .any(|token| token.keys > 0));
+
+    second.leave().await?;
+    assert!(second.vnodes.read().await.is_empty());
+    for i in 0..64 {
+        let key = format!("key{}", i);
+        let result = query(&first, OperationType::Get, &key, None).await?;
+        assert_eq!(result.value, Some(key.into_bytes()));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_owner() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 4,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&second, 2).await;
+
+    let mut tokens = BTreeMap::new();
+    for node in [&first, &second] {
+        for token in node.vnodes.read().await.keys() {
+            tokens.insert(*token, node.addr.clone());
+        }
+    }
+    // The owner of an id is the token at or before it, not the one after it.
+    for (token, addr) in &tokens {
+        for id in [*token, token.wrapping_add(1)] {
+            let owner = DhtNodeService::find_owner_through_seeds(id, &seeds).await?;
+            assert_eq!(decode_id(&owner.id)?, *token);
+            assert_eq!(&owner.addr, addr);
+        }
+    }
+    let (first_token, _) = tokens.first_key_value().unwrap();
+    let (last_token, _) = tokens.last_key_value().unwrap();
+    let owner =
+        DhtNodeService::find_owner_through_seeds(first_token.wrapping_sub(1), &seeds).await?;
+    assert_eq!(decode_id(&owner.id)?, *last_token);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_moves_keys_to_owning_tokens() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 4,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        query(&first, OperationType::Set, &key, Some(&key)).await?;
+    }
+
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&second, 2).await;
+
+    // Every key is kept once, by the virtual node owning it.
+    let mut vnodes = first.vnodes.read().await.clone();
+    vnodes.extend(second.vnodes.read().await.clone());
+    let mut count = 0;
+    for vnode in vnodes.values() {
+        for (key, _) in vnode.store.list().await {
+            assert_eq!(
+                HashRing::closest_preceding(&vnodes, key).unwrap().0,
+                vnode.id
+            );
+            count += 1;
+        }
+    }
+    assert_eq!(count, 100);
+
+    for node in [&first, &second] {
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            let result = query(node, OperationType::Get, &key, None).await?;
+            assert_eq!(result.value, Some(key.into_bytes()));
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_raft_serves_keys_through_groups() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 2,
+        replication: 2,
+        replication_mode: ReplicationMode::Raft,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    for i in 0..32 {
+        let key = format!("key{}", i);
+        let result = query(&first, OperationType::Set, &key, Some(&key)).await?;
+        assert_eq!(result.error, None);
+    }
+
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&second, 2).await;
+
+    // Keys past the tokens of the second node are moved to their groups, then every key
+    // is read through the group of its owner from either node.
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut missing = 32;
+    while missing > 0 && Instant::now() < deadline {
+        missing = 0;
+        for node in [&first, &second] {
+            for i in 0..32 {
+                let key = format!("key{}", i);
+                let result = query(node, OperationType::Get, &key, None).await?;
+                if result.value != Some(key.into_bytes()) {
+                    missing += 1;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(missing, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chain_replication() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 2,
+        replication: 3,
+        replication_mode: ReplicationMode::Chain,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config.clone()).await?;
+    let third = spawn_node(&seeds, config).await?;
+    for node in [&first, &second, &third] {
+        wait_members(node, 3).await;
+    }
+
+    for i in 0..16 {
+        let key = format!("key{}", i);
+        let result = query(&first, OperationType::Set, &key, Some(&key)).await?;
+        assert_eq!(result.error, None);
+    }
+    let result = query(&second, OperationType::Delete, "key0", None).await?;
+    assert_eq!(result.error, None);
+
+    // Every node holds the keys left, as the owner or down the chain, and reads are
+    // served by the tail wherever they enter.
+    for node in [&first, &second, &third] {
+        let mut count = node.replication.store.len().await;
+        for vnode in node.vnodes.read().await.values() {
+            count += vnode.store.len().await;
+        }
+        assert_eq!(count, 15);
+        for i in 0..16 {
+            let key = format!("key{}", i);
+            let result = query(node, OperationType::Get, &key, None).await?;
+            let expected = (i > 0).then(|| key.into_bytes());
+            assert_eq!(result.value, expected);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_importing_vnode_reads_missing_keys() -> Result<()> {
+    let owner = spawn_node(&[], NodeConfig::default()).await?;
+    let value = |value: &[u8]| Versioned {
+        value: value.to_vec(),
+        version: 1,
+    };
+    let store = &owner.get_vnode(owner.id).await?.store;
+    store.set(&10, value(b"a")).await;
+    store.set(&20, value(b"b")).await;
+
+    let peer = Neighbor {
+        id: owner.id,
+        addr: owner.addr.clone(),
+        client: DhtNodeClient::connect(owner.addr.clone()).await?,
+    };
+    let vnode = VirtualNode::new(5, Versioning::Timestamp);
+    vnode
+        .start_migration(Migration::new(MigrationState::Importing, 5, 50, peer))
+        .await;
+    let request = |ty: OperationType, key: RingId| EncodedQuery {
+        ty: ty.into(),
+        key: encode_id(key),
+        ..EncodedQuery::default()
+    };
+
+    // Keys not received yet are read from the previous owner.
+    let result = vnode
+        .execute_query(&request(OperationType::Get, 10))
+        .await?;
+    assert_eq!(result.map(|value| value.value), Some(b"a".to_vec()));
+
+    // Once deleted here, they are not read from it anymore, nor brought back by the transfer.
+    vnode
+        .execute_query(&request(OperationType::Delete, 20))
+        .await?;
+    let result = vnode.execute_query(&request(OperationType::Get, 20)).await;
+    assert!(matches!(result, Err(Error::Value(_))));
+    vnode.import(20, value(b"b"), false).await?;
+    assert!(vnode.store.get(&20).await.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rendezvous_join_takes_keys() -> Result<()> {
+    let config = NodeConfig {
+        placement: Placement::Rendezvous,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    for i in 0..64 {
+        let key = format!("key{}", i);
+        query(&first, OperationType::Set, &key, Some(&key)).await?;
+    }
+
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&second, 2).await;
+
+    // The keys the second node wins are copied to it, then deleted from the first.
+    let (first_vnode, second_vnode) = (
+        first.get_vnode(first.id).await?,
+        second.get_vnode(second.id).await?,
+    );
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while first_vnode.store.len().await + second_vnode.store.len().await != 64
+        || second_vnode.store.len().await == 0
+    {
+        assert!(Instant::now() < deadline);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for node in [&first, &second] {
+        for i in 0..64 {
+            let key = format!("key{}", i);
+            let result = query(node, OperationType::Get, &key, None).await?;
+            assert_eq!(result.value, Some(key.into_bytes()));
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tokens_taken_by_another_address() -> Result<()> {
+    let node = spawn_node(&[], NodeConfig::default()).await?;
+    let seeds = vec![node.addr.clone()];
+    let tokens: Vec<RingId> = node.vnodes.read().await.keys().copied().collect();
+
+    DhtNodeService::check_tokens_free(&node.addr, &tokens, &seeds).await?;
+    let result = DhtNodeService::check_tokens_free("http://127.0.0.1:1", &tokens, &seeds).await;
+    assert!(matches!(result, Err(Error::Config(_))));
+    let free: Vec<RingId> = tokens.iter().map(|token| token.wrapping_add(1)).collect();
+    DhtNodeService::check_tokens_free("http://127.0.0.1:1", &free, &seeds).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_move_token_keeps_keys() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 2,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&second, 2).await;
+    for i in 0..64 {
+        let key = format!("key{}", i);
+        query(&first, OperationType::Set, &key, Some(&key)).await?;
+    }
+
+    let token = *second.vnodes.read().await.keys().next().unwrap();
+    let target = token.wrapping_add(RingId::MAX / 3);
+    second.move_token(token, target).await?;
+
+    let tokens: Vec<RingId> = second.vnodes.read().await.keys().copied().collect();
+    assert!(tokens.contains(&target) && !tokens.contains(&token));
+    assert_eq!(decode_ids(&second.membership.this().await.tokens)?, tokens);
+    for i in 0..64 {
+        let key = format!("key{}", i);
+        let result = query(&first, OperationType::Get, &key, None).await?;
+        assert_eq!(result.value, Some(key.into_bytes()));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_forward_rejects_loops_and_long_paths() -> Result<()> {
+    let mut req = EncodedQuery {
+        key: encode_id(0x10),
+        ..EncodedQuery::default()
+    };
+    let mut path = Vec::new();
+    forward(&mut req, &mut path, 1, 3)?;
+    forward(&mut req, &mut path, 2, 3)?;
+    assert_eq!(req.hops, 2);
+    assert_eq!(decode_ids(&req.path)?, vec![1, 2]);
+
+    let looped = forward(&mut req.clone(), &mut path.clone(), 1, 3);
+    assert!(matches!(looped, Err(Error::RoutingLoop(_))));
+    forward(&mut req, &mut path, 3, 3)?;
+    let exceeded = forward(&mut req, &mut path, 4, 3);
+    assert!(matches!(exceeded, Err(Error::TtlExceeded(_))));
+    assert_eq!(Status::from(exceeded.unwrap_err()).code(), Code::Aborted);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_leave_keeps_deletes() -> Result<()> {
+    let config = NodeConfig {
+        replication: 3,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config.clone()).await?;
+    let third = spawn_node(&seeds, config).await?;
+    for service in [&first, &second, &third] {
+        wait_members(service, 3).await;
+    }
+
+    for i in 0..16 {
+        let key = format!("key{}", i);
+        query(&first, OperationType::Set, &key, Some("value")).await?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while first.replication.store.len().await + third.replication.store.len().await < 16 {
+        assert!(Instant::now() < deadline);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // The leaving node deletes its keys without the replicas hearing of it.
+    let mut deleted = Vec::new();
+    for vnode in second.vnodes.read().await.values() {
+        for (key, _) in vnode.store.list().await {
+            vnode.store.delete(&key).await?;
+            deleted.push(key);
+        }
+    }
+    assert!(!deleted.is_empty());
+    second.leave().await?;
+    for service in [&first, &third] {
+        while service.membership.members().await.len() > 2 {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    // The copies the replicas hold are not merged back into the new owner.
+    for service in [&first, &third] {
+        service.replication.anti_entropy(&service.vnodes).await;
+    }
+    for service in [&first, &third] {
+        for vnode in service.vnodes.read().await.values() {
+            for key in &deleted {
+                assert_eq!(vnode.store.get(key).await, None);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_anti_entropy_keeps_deletes() -> Result<()> {
+    let config = NodeConfig {
+        tokens: 2,
+        replication: 2,
+        ..NodeConfig::default()
+    };
+    let first = spawn_node(&[], config.clone()).await?;
+    let seeds = vec![first.addr.clone()];
+    let second = spawn_node(&seeds, config).await?;
+    wait_members(&first, 2).await;
+    wait_members(&second, 2).await;
+
+    for i in 0..16 {
+        let key = format!("key{}", i);
+        query(&first, OperationType::Set, &key, Some("value")).await?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while first.replication.store.len().await + second.replication.store.len().await < 16 {
+        assert!(Instant::now() < deadline);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Owners delete their keys without the replicas hearing of it.
+    for service in [&first, &second] {
+        for vnode in service.vnodes.read().await.values() {
+            for (key, _) in vnode.store.list().await {
+                vnode.store.delete(&key).await?;
+            }
+        }
+    }
+    for service in [&first, &second] {
+        service.replication.anti_entropy(&service.vnodes).await;
+    }
+
+    for service in [&first, &second] {
+        for vnode in service.vnodes.read().await.values() {
+            assert_eq!(vnode.store.len().await, 0);
+        }
+        assert_eq!(service.replication.store.len().await, 0);
+    }
+    Ok(())
 }
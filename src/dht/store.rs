@@ -1,53 +1,284 @@
-use std::{collections::HashMap, vec};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use tokio::sync::RwLock;
 
 use crate::error::Result;
+use crate::RingId;
 
-#[derive(Debug)]
+use super::versioning::Versioning;
+
+/// A value along with the version it was written at, later writes having higher versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned {
+    pub value: Vec<u8>,
+    pub version: u64,
+}
+
+impl Versioned {
+    /// Versions a value written over `previous`: the time of the write in microseconds,
+    /// or right after the previous version if the clock is behind it.
+    pub fn new(value: Vec<u8>, previous: Option<&Versioned>) -> Result<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        let version = match previous {
+            Some(previous) => now.max(previous.version + 1),
+            None => now,
+        };
+        Ok(Versioned { value, version })
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Store {
-    store: RwLock<HashMap<u64, Vec<u8>>>,
+    store: RwLock<HashMap<RingId, Versioned>>,
+    /// Tombstones of the deleted keys, so that copies of them written earlier are not
+    /// merged back. Locked after `store`.
+    tombstones: RwLock<HashMap<RingId, Versioned>>,
+    versioning: Versioning,
 }
 
 impl Store {
-    pub fn new() -> Self {
-        let store = RwLock::new(HashMap::new());
-        Store { store }
+    pub fn new(versioning: Versioning) -> Self {
+        Store {
+            store: RwLock::new(HashMap::new()),
+            tombstones: RwLock::new(HashMap::new()),
+            versioning,
+        }
     }
 
-    pub async fn get(&self, key: &u64) -> Option<Vec<u8>> {
+    pub fn versioning(&self) -> Versioning {
+        self.versioning
+    }
+
+    pub async fn get(&self, key: &RingId) -> Option<Versioned> {
         let store = self.store.read().await;
         (*store).get(key).cloned()
     }
 
-    pub async fn set(&self, key: &u64, value: &[u8]) -> Option<Vec<u8>> {
+    pub async fn set(&self, key: &RingId, value: Versioned) -> Option<Versioned> {
+        let mut store = self.store.write().await;
+        (*store).insert(*key, value)
+    }
+
+    /// Writes a value over the stored one, as a write made on `node` with the `context`
+    /// of an earlier read, returning the latter.
+    pub async fn write(
+        &self,
+        key: &RingId,
+        node: RingId,
+        value: Vec<u8>,
+        context: Option<&[u8]>,
+    ) -> Result<Option<Versioned>> {
         let mut store = self.store.write().await;
-        (*store).insert(*key, value.into())
+        let mut tombstones = self.tombstones.write().await;
+        let value = self.versioning.write(
+            node,
+            (*store).get(key),
+            (*tombstones).get(key),
+            value,
+            context,
+        )?;
+        // A later timestamp replaces the deletion, while vector clocks keep its clock.
+        if self.versioning == Versioning::Timestamp {
+            (*tombstones).remove(key);
+        }
+        Ok((*store).insert(*key, value))
     }
 
-    pub async fn delete(&self, key: &u64) -> Option<Vec<u8>> {
+    /// Merges a value received from another node into the stored one, leaving out what
+    /// the key was deleted over, returning whether the latter changed.
+    pub async fn merge(&self, key: &RingId, value: Versioned) -> Result<bool> {
         let mut store = self.store.write().await;
+        let mut tombstones = self.tombstones.write().await;
+        let value = match (*tombstones).get(key) {
+            Some(deleted) => match self.versioning.surviving(deleted, value)? {
+                Some(value) => value,
+                None => return Ok(false),
+            },
+            None => value,
+        };
+        match self.versioning.merge((*store).get(key), value)? {
+            Some(merged) => {
+                if self.versioning == Versioning::Timestamp {
+                    (*tombstones).remove(key);
+                }
+                (*store).insert(*key, merged);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Deletes a key, keeping a tombstone versioned after its value. Returns the value
+    /// deleted, if the key was present.
+    pub async fn delete(&self, key: &RingId) -> Result<Option<Versioned>> {
+        let mut store = self.store.write().await;
+        let mut tombstones = self.tombstones.write().await;
+        let current = match (*store).remove(key) {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+        let deleted = self
+            .versioning
+            .delete(Some(&current), (*tombstones).get(key))?;
+        (*tombstones).insert(*key, deleted);
+        Ok(Some(current))
+    }
+
+    /// Merges the tombstone a key was deleted with on another node into the stored one,
+    /// deleting what the stored value has of the writes it saw. Returns whether anything
+    /// changed.
+    pub async fn merge_deleted(&self, key: &RingId, deleted: Versioned) -> Result<bool> {
+        let mut store = self.store.write().await;
+        let mut tombstones = self.tombstones.write().await;
+        let deleted = match self
+            .versioning
+            .merge_deleted((*tombstones).get(key), deleted)?
+        {
+            Some(deleted) => deleted,
+            None => return Ok(false),
+        };
+        let surviving = match (*store).remove(key) {
+            Some(current) => self.versioning.surviving(&deleted, current)?,
+            None => None,
+        };
+        match surviving {
+            // A value written after the deletion with timestamps replaces it.
+            Some(value) if self.versioning == Versioning::Timestamp => {
+                (*store).insert(*key, value);
+                return Ok(false);
+            }
+            Some(value) => {
+                (*store).insert(*key, value);
+            }
+            None => {}
+        }
+        (*tombstones).insert(*key, deleted);
+        Ok(true)
+    }
+
+    /// Returns the tombstone the key was deleted with, if it was not written since.
+    pub async fn deleted(&self, key: &RingId) -> Option<Versioned> {
+        let tombstones = self.tombstones.read().await;
+        (*tombstones).get(key).cloned()
+    }
+
+    /// Returns what is left of a value read elsewhere once the writes the key was deleted
+    /// over here are removed from it.
+    pub async fn surviving(&self, key: &RingId, value: Versioned) -> Result<Option<Versioned>> {
+        match self.deleted(key).await {
+            Some(deleted) => self.versioning.surviving(&deleted, value),
+            None => Ok(Some(value)),
+        }
+    }
+
+    /// Forgets a key along with its tombstone, as when it moves to another node.
+    pub async fn forget(&self, key: &RingId) -> Option<Versioned> {
+        let mut store = self.store.write().await;
+        self.tombstones.write().await.remove(key);
         (*store).remove(key)
     }
 
-    pub async fn list(&self) -> Vec<(u64, Vec<u8>)> {
+    pub async fn list_deleted(&self) -> Vec<(RingId, Versioned)> {
+        let tombstones = self.tombstones.read().await;
+        (*tombstones).iter().map(|(k, v)| (*k, v.clone())).collect()
+    }
+
+    pub async fn len(&self) -> usize {
+        let store = self.store.read().await;
+        (*store).len()
+    }
+
+    pub async fn list(&self) -> Vec<(RingId, Versioned)> {
+        let store = self.store.read().await;
+        (*store).iter().map(|(k, v)| (*k, v.clone())).collect()
+    }
+
+    pub async fn get_entries_satisfy<F>(&self, f: F) -> Vec<(RingId, Versioned)>
+    where
+        F: Fn(RingId) -> bool,
+    {
         let store = self.store.read().await;
+
         (*store)
             .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .filter(|(key, _)| f(**key))
+            .map(|(k, v)| (*k, v.clone()))
             .collect()
     }
 
-    pub async fn get_entries_satisfy<F>(&self, f: F) -> Vec<(u64, Vec<u8>)>
+    pub async fn get_deleted_satisfy<F>(&self, f: F) -> Vec<(RingId, Versioned)>
     where
-        F: Fn(u64) -> bool,
+        F: Fn(RingId) -> bool,
     {
-        let store = self.store.read().await;
+        let tombstones = self.tombstones.read().await;
 
-        (*store)
+        (*tombstones)
             .iter()
-            .filter(|(&ref key, _)| f(*key))
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .filter(|(key, _)| f(**key))
+            .map(|(k, v)| (*k, v.clone()))
             .collect()
     }
 }
+
+#[tokio::test]
+async fn test_merge_newer() -> Result<()> {
+    let store = Store::new(Versioning::Timestamp);
+    let first = Versioned::new(b"a".to_vec(), None)?;
+    let second = Versioned::new(b"b".to_vec(), Some(&first))?;
+    assert!(second.version > first.version);
+
+    assert!(store.merge(&1, second.clone()).await?);
+    assert!(!store.merge(&1, first).await?);
+    assert_eq!(store.get(&1).await, Some(second));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_newer() -> Result<()> {
+    let store = Store::new(Versioning::Timestamp);
+    let old = Versioned::new(b"a".to_vec(), None)?;
+    store.write(&1, 1, b"b".to_vec(), None).await?;
+    store.delete(&1).await?;
+    let deleted = store.deleted(&1).await.unwrap();
+
+    // Copies written before the deletion are not merged back, later writes replace it.
+    assert!(!store.merge(&1, old.clone()).await?);
+    assert_eq!(store.get(&1).await, None);
+    let newer = Versioned::new(b"c".to_vec(), Some(&deleted))?;
+    assert!(store.merge(&1, newer.clone()).await?);
+    assert!(!store.merge_deleted(&1, deleted.clone()).await?);
+    assert_eq!(store.get(&1).await, Some(newer));
+    assert_eq!(store.deleted(&1).await, None);
+
+    // A replica that missed the deletion applies it.
+    let other = Store::new(Versioning::Timestamp);
+    other.merge(&1, old).await?;
+    assert!(other.merge_deleted(&1, deleted).await?);
+    assert_eq!(other.get(&1).await, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_vector_clock() -> Result<()> {
+    let store = Store::new(Versioning::VectorClock);
+    let versioning = Versioning::VectorClock;
+    let seen = versioning.write(1, None, None, b"a".to_vec(), None)?;
+    let concurrent = versioning.write(2, None, None, b"b".to_vec(), None)?;
+
+    store.merge(&1, seen.clone()).await?;
+    store.delete(&1).await?;
+    let deleted = store.deleted(&1).await.unwrap();
+
+    // The write the deletion saw stays deleted, the concurrent one is kept.
+    assert!(!store.merge(&1, seen.clone()).await?);
+    assert!(store.merge(&1, concurrent.clone()).await?);
+    assert_eq!(store.get(&1).await, Some(concurrent.clone()));
+
+    let other = Store::new(Versioning::VectorClock);
+    let both = versioning.merge(Some(&seen), concurrent.clone())?.unwrap();
+    other.merge(&1, both).await?;
+    assert!(other.merge_deleted(&1, deleted).await?);
+    assert_eq!(other.get(&1).await, Some(concurrent));
+    Ok(())
+}
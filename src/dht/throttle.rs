@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::rpc::dht::{KeyValueEntry, MigrationLimits, ReplicaEntry};
+
+/// Number of messages a migration stream queues before its sender waits for the receiver
+/// to catch up.
+pub const BUFFER: usize = 4;
+
+/// Bytes counted against the limit for sending a key.
+pub fn weight(entry: &KeyValueEntry) -> u64 {
+    (entry.key.len() + entry.value.len() + std::mem::size_of::<u64>()) as u64
+}
+
+/// Bytes counted against the limit for copying a key to a replica.
+pub fn replica_weight(entry: &ReplicaEntry) -> u64 {
+    let value = entry.value.as_ref().map_or(0, Vec::len);
+    (entry.key.len() + value + std::mem::size_of::<u64>()) as u64
+}
+
+/// Limits the rate keys are migrated between nodes, or copied to their replicas, at, so
+/// that transfers do not starve foreground requests. Each limit is a token bucket holding
+/// up to a second of its rate: senders take what they send from it and wait for it to
+/// refill when they run ahead. A rate of 0 is unlimited.
+#[derive(Debug)]
+pub struct Throttle {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    limits: MigrationLimits,
+    bytes: f64,
+    entries: f64,
+    updated: Instant,
+}
+
+/// Takes an amount from a bucket refilling at `rate` per second, returning how long to wait
+/// until the bucket is no longer in debt.
+fn take(available: &mut f64, rate: u64, amount: u64, elapsed: Duration) -> Duration {
+    if rate == 0 {
+        return Duration::ZERO;
+    }
+    let rate = rate as f64;
+    *available = (*available + elapsed.as_secs_f64() * rate).min(rate) - amount as f64;
+    match *available < 0.0 {
+        true => Duration::from_secs_f64(-*available / rate),
+        false => Duration::ZERO,
+    }
+}
+
+impl Throttle {
+    pub fn new(limits: MigrationLimits) -> Self {
+        Throttle {
+            state: Mutex::new(State {
+                bytes: limits.bytes_per_sec as f64,
+                entries: limits.entries_per_sec as f64,
+                limits,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn limits(&self) -> MigrationLimits {
+        self.state.lock().await.limits.clone()
+    }
+
+    /// Changes the limits, for transfers in progress too from what they send next: senders
+    /// already waiting for the bucket to refill wait as long as the old limits said.
+    pub async fn set_limits(&self, limits: MigrationLimits) {
+        let mut state = self.state.lock().await;
+        state.bytes = state.bytes.min(limits.bytes_per_sec as f64);
+        state.entries = state.entries.min(limits.entries_per_sec as f64);
+        state.limits = limits;
+    }
+
+    /// Waits until `entries` keys weighing `bytes` in total may be sent.
+    pub async fn acquire(&self, entries: u64, bytes: u64) {
+        let delay = {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+            let elapsed = now - state.updated;
+            state.updated = now;
+            let limits = state.limits.clone();
+            let bytes = take(&mut state.bytes, limits.bytes_per_sec, bytes, elapsed);
+            let entries = take(&mut state.entries, limits.entries_per_sec, entries, elapsed);
+            bytes.max(entries)
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_throttle() {
+    let throttle = Throttle::new(MigrationLimits {
+        bytes_per_sec: 0,
+        entries_per_sec: 1000,
+    });
+
+    // A second worth of entries goes through at once, the rest at the limit's pace.
+    let start = Instant::now();
+    throttle.acquire(1000, 1 << 20).await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    throttle.acquire(200, 0).await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200) && elapsed <= Duration::from_millis(201));
+
+    // The bucket refills while no entries are sent.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let start = Instant::now();
+    throttle.acquire(1000, 0).await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+
+    throttle
+        .set_limits(MigrationLimits {
+            bytes_per_sec: 0,
+            entries_per_sec: 0,
+        })
+        .await;
+    let start = Instant::now();
+    throttle.acquire(1_000_000, 1 << 30).await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
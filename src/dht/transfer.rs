@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use tonic::transport::Channel;
+use tonic::Request;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::{KeyBatch, KeyValueEntry, Member, TakeRequest, TokenRange};
+use crate::{decode_id, encode_id, HashRing, RingId};
+
+use super::store::{Store, Versioned};
+use super::vnode::VirtualNode;
+
+/// Number of keys sent in a batch.
+const BATCH_SIZE: usize = 128;
+/// Number of times a transfer that broke off is resumed before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+/// Time to wait before resuming a transfer, multiplied by the attempt number.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Checksum of the keys, versions and values of a batch, in order.
+pub fn checksum(entries: &[KeyValueEntry]) -> u64 {
+    let mut hasher = Xxh3::new();
+    for entry in entries {
+        hasher.update(&entry.key);
+        hasher.update(&entry.version.to_be_bytes());
+        hasher.update(&[entry.deleted as u8]);
+        hasher.update(&(entry.value.len() as u64).to_be_bytes());
+        hasher.update(&entry.value);
+    }
+    hasher.digest()
+}
+
+/// Lists the keys of the store satisfying `f` as entries to send to the node they move to,
+/// along with the tombstones of those deleted, so that copies the replicas still hold are
+/// not merged back into the new owner.
+pub async fn entries<F>(store: &Store, f: F) -> Vec<(RingId, KeyValueEntry)>
+where
+    F: Fn(RingId) -> bool,
+{
+    let entry = |key: RingId, value: Versioned, deleted: bool| {
+        let entry = KeyValueEntry {
+            key: encode_id(key),
+            value: value.value,
+            version: value.version,
+            deleted,
+        };
+        (key, entry)
+    };
+    let mut entries: Vec<_> = store
+        .get_deleted_satisfy(&f)
+        .await
+        .into_iter()
+        .map(|(key, value)| entry(key, value, true))
+        .collect();
+    for (key, value) in store.get_entries_satisfy(&f).await {
+        entries.push(entry(key, value, false));
+    }
+    entries
+}
+
+/// Stores a key, or the tombstone of a deleted key, moved from another node. Newer writes
+/// made here are kept.
+pub async fn store_entry(store: &Store, entry: KeyValueEntry) -> Result<()> {
+    let key = decode_id(&entry.key)?;
+    let value = Versioned {
+        value: entry.value,
+        version: entry.version,
+    };
+    match entry.deleted {
+        true => store.merge_deleted(&key, value).await?,
+        false => store.merge(&key, value).await?,
+    };
+    Ok(())
+}
+
+/// Splits the keys of a range starting at `start` into batches, in clockwise order from
+/// it, leaving out the keys up to the resume token `after` if given.
+pub fn batches(
+    start: RingId,
+    mut entries: Vec<(RingId, KeyValueEntry)>,
+    after: Option<RingId>,
+) -> Vec<KeyBatch> {
+    entries.sort_unstable_by_key(|(key, _)| HashRing::clockwise_distance(start, *key));
+    if let Some(after) = after {
+        let sent = HashRing::clockwise_distance(start, after);
+        entries.retain(|(key, _)| HashRing::clockwise_distance(start, *key) > sent);
+    }
+
+    entries
+        .chunks(BATCH_SIZE)
+        .map(|chunk| {
+            let entries: Vec<KeyValueEntry> =
+                chunk.iter().map(|(_, entry)| entry.clone()).collect();
+            KeyBatch {
+                checksum: checksum(&entries),
+                resume: entries
+                    .last()
+                    .map(|entry| entry.key.clone())
+                    .unwrap_or_default(),
+                entries,
+            }
+        })
+        .collect()
+}
+
+/// Copies the keys of a range from the node holding them into the virtual node, resuming from
+/// the last batch stored whenever the transfer breaks off or a batch does not match its
+/// checksum. Once the whole range is stored, the sender is told to delete its copy.
+/// Returns the number of keys copied.
+pub async fn pull(
+    client: &mut DhtNodeClient<Channel>,
+    mut range: TokenRange,
+    vnode: &VirtualNode,
+) -> Result<usize> {
+    let mut copied = 0;
+    let mut attempt = 0;
+    loop {
+        match pull_batches(client, &mut range, vnode, &mut copied).await {
+            Ok(()) => break,
+            Err(err) if attempt < MAX_ATTEMPTS => {
+                attempt += 1;
+                warn!(
+                    "Transfer of keys from #{:x} broke off after {} keys, resuming: {}",
+                    decode_id(&range.token)?,
+                    copied,
+                    err
+                );
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    range.after = None;
+    let mut attempt = 0;
+    while let Err(err) = client.commit_transfer(Request::new(range.clone())).await {
+        attempt += 1;
+        if attempt >= MAX_ATTEMPTS {
+            // The keys were copied, but the sender keeps answering for them with its copy.
+            return Err(Error::Internal(format!(
+                "Committing the transfer of {} keys from #{:x} failed: {}",
+                copied,
+                decode_id(&range.token)?,
+                err
+            )));
+        }
+        tokio::time::sleep(RETRY_DELAY * attempt).await;
+    }
+    info!(
+        "Copied {} keys from #{:x}",
+        copied,
+        decode_id(&range.token)?
+    );
+    Ok(copied)
+}
+
+/// Stores the batches of the range sent after its resume token, moving the token past
+/// each batch stored.
+async fn pull_batches(
+    client: &mut DhtNodeClient<Channel>,
+    range: &mut TokenRange,
+    vnode: &VirtualNode,
+    copied: &mut usize,
+) -> Result<()> {
+    let mut stream = client
+        .transfer_keys(Request::new(range.clone()))
+        .await?
+        .into_inner();
+    while let Some(batch) = stream.message().await? {
+        range.after = Some(store_batch(vnode, batch, copied).await?);
+    }
+    Ok(())
+}
+
+/// Stores the keys of a batch in the virtual node, failing if they do not match its
+/// checksum. Returns the resume token after the batch.
+async fn store_batch(vnode: &VirtualNode, batch: KeyBatch, copied: &mut usize) -> Result<Vec<u8>> {
+    if checksum(&batch.entries) != batch.checksum {
+        return Err(Error::Value(format!(
+            "Batch of {} keys does not match its checksum.",
+            batch.entries.len()
+        )));
+    }
+    for entry in batch.entries {
+        let value = Versioned {
+            value: entry.value,
+            version: entry.version,
+        };
+        // Writes made here since the transfer started are newer and kept.
+        vnode
+            .import(decode_id(&entry.key)?, value, entry.deleted)
+            .await?;
+        *copied += 1;
+    }
+    Ok(batch.resume)
+}
+
+/// Copies the keys the member, joining with rendezvous placement, wins from the node
+/// holding them into its virtual node, the same way as the keys of a range: in batches,
+/// resuming after the last one stored, before the sender is told to delete them. Returns
+/// the number of keys copied.
+pub async fn take(
+    client: &mut DhtNodeClient<Channel>,
+    member: Member,
+    vnode: &VirtualNode,
+) -> Result<usize> {
+    let mut request = TakeRequest {
+        member: Some(member.clone()),
+        after: None,
+    };
+    let mut copied = 0;
+    let mut attempt = 0;
+    loop {
+        match take_batches(client, &mut request, vnode, &mut copied).await {
+            Ok(()) => break,
+            Err(err) if attempt < MAX_ATTEMPTS => {
+                attempt += 1;
+                warn!(
+                    "Taking keys broke off after {} keys, resuming: {}",
+                    copied, err
+                );
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut attempt = 0;
+    while let Err(err) = client.commit_take(Request::new(member.clone())).await {
+        attempt += 1;
+        if attempt >= MAX_ATTEMPTS {
+            return Err(Error::Internal(format!(
+                "Committing the {} keys taken failed: {}",
+                copied, err
+            )));
+        }
+        tokio::time::sleep(RETRY_DELAY * attempt).await;
+    }
+    Ok(copied)
+}
+
+async fn take_batches(
+    client: &mut DhtNodeClient<Channel>,
+    request: &mut TakeRequest,
+    vnode: &VirtualNode,
+    copied: &mut usize,
+) -> Result<()> {
+    let mut stream = client
+        .take_keys(Request::new(request.clone()))
+        .await?
+        .into_inner();
+    while let Some(batch) = stream.message().await? {
+        request.after = Some(store_batch(vnode, batch, copied).await?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_batches_resume() -> Result<()> {
+    let start: RingId = RingId::MAX - 10;
+    let entries: Vec<(RingId, KeyValueEntry)> = (0..300)
+        .map(|i| {
+            let key = start.wrapping_add(i as RingId);
+            let entry = KeyValueEntry {
+                key: encode_id(key),
+                value: vec![i as u8],
+                version: 1,
+                deleted: i % 10 == 0,
+            };
+            (key, entry)
+        })
+        .rev()
+        .collect();
+
+    let all = batches(start, entries.clone(), None);
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[0].entries[0].key, encode_id(start));
+    assert!(all
+        .iter()
+        .all(|batch| checksum(&batch.entries) == batch.checksum));
+
+    // Resuming after the first batch sends the rest, across the end of the ring.
+    let rest = batches(start, entries, Some(decode_id(&all[0].resume)?));
+    assert_eq!(rest.len(), 2);
+    assert_eq!(rest[0].entries, all[1].entries);
+
+    let mut altered = all[2].entries.clone();
+    altered[0].value = vec![0xff];
+    assert_ne!(checksum(&altered), all[2].checksum);
+    let mut altered = all[2].entries.clone();
+    altered[0].deleted = !altered[0].deleted;
+    assert_ne!(checksum(&altered), all[2].checksum);
+    Ok(())
+}
@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+
+use prost::Message;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::error::{Error, Result};
+use crate::rpc::dht::{Clock, ClockEntry, Sibling, Siblings};
+use crate::{decode_id, encode_id, RingId};
+
+use super::store::Versioned;
+
+/// Values of a key as a client sees them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Values {
+    pub siblings: Vec<Vec<u8>>,
+    /// Context to write over all the siblings with, in vector clock mode.
+    pub context: Option<Vec<u8>>,
+}
+
+/// How writes to the same key are ordered, chosen when the cluster is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Versioning {
+    /// Last writer wins: each value is stamped with the time it was written and the
+    /// latest one replaces the others.
+    #[default]
+    Timestamp,
+    /// Each value records the writes it has seen. Writes that did not see each other are
+    /// kept side by side as siblings, until a write made with the context of a read that
+    /// returned them replaces them.
+    VectorClock,
+}
+
+impl Versioning {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "timestamp" => Ok(Versioning::Timestamp),
+            "vector-clock" => Ok(Versioning::VectorClock),
+            _ => Err(Error::Parse(format!("unknown versioning {}", name))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Versioning::Timestamp => "timestamp",
+            Versioning::VectorClock => "vector-clock",
+        }
+    }
+
+    /// Returns the value stored by a write made on `node` over the `current` one, and the
+    /// tombstone of the key if it was `deleted`, which the write must come after. With
+    /// vector clocks the write replaces the siblings seen by its `context`, and is kept
+    /// alongside the others.
+    pub fn write(
+        &self,
+        node: RingId,
+        current: Option<&Versioned>,
+        deleted: Option<&Versioned>,
+        value: Vec<u8>,
+        context: Option<&[u8]>,
+    ) -> Result<Versioned> {
+        match self {
+            Versioning::Timestamp => Versioned::new(value, latest(current, deleted)),
+            Versioning::VectorClock => {
+                let context = match context {
+                    Some(bytes) => decode_clock(bytes)?,
+                    None => BTreeMap::new(),
+                };
+                let siblings = siblings(current)?;
+
+                let mut counter = context.get(&node).copied().unwrap_or(0);
+                counter = counter.max(tombstone_clock(deleted)?.get(&node).copied().unwrap_or(0));
+                for sibling in &siblings {
+                    let seen = clock(sibling.context.as_ref())?;
+                    counter = counter.max(seen.get(&node).copied().unwrap_or(0));
+                    if decode_id(&sibling.node)? == node {
+                        counter = counter.max(sibling.counter);
+                    }
+                }
+
+                let mut kept = Vec::new();
+                for sibling in siblings {
+                    if !covers(&context, &sibling)? {
+                        kept.push(sibling);
+                    }
+                }
+                kept.push(Sibling {
+                    value,
+                    node: encode_id(node),
+                    counter: counter + 1,
+                    context: Some(encode_clock(&context)),
+                });
+                Ok(stored(kept))
+            }
+        }
+    }
+
+    /// Combines the stored value of a key with one received from another node, returning
+    /// what should be stored instead, or nothing if the stored value already has it. With
+    /// timestamps the latest value wins, with vector clocks the siblings of both are kept
+    /// unless one of them saw another.
+    pub fn merge(
+        &self,
+        current: Option<&Versioned>,
+        received: Versioned,
+    ) -> Result<Option<Versioned>> {
+        match self {
+            Versioning::Timestamp => Ok(match current {
+                Some(current) if current.version >= received.version => None,
+                _ => Some(received),
+            }),
+            Versioning::VectorClock => {
+                let mut all = siblings(current)?;
+                all.extend(siblings(Some(&received))?);
+                all.sort_by(|a, b| {
+                    (&a.node, a.counter, &a.value).cmp(&(&b.node, b.counter, &b.value))
+                });
+                all.dedup_by(|a, b| a.node == b.node && a.counter == b.counter);
+
+                let mut kept = Vec::new();
+                for sibling in &all {
+                    let mut seen = false;
+                    for other in &all {
+                        seen |= covers(&clock(other.context.as_ref())?, sibling)?;
+                    }
+                    if !seen {
+                        kept.push(sibling.clone());
+                    }
+                }
+
+                let merged = stored(kept);
+                Ok(match current {
+                    Some(current) if current.version == merged.version => None,
+                    _ => Some(merged),
+                })
+            }
+        }
+    }
+
+    /// Returns the tombstone of a key deleted over its `current` value and the tombstone it
+    /// was `deleted` with before, if any. With timestamps it is versioned after both, with
+    /// vector clocks it holds the clock of every write they saw.
+    pub fn delete(
+        &self,
+        current: Option<&Versioned>,
+        deleted: Option<&Versioned>,
+    ) -> Result<Versioned> {
+        match self {
+            Versioning::Timestamp => Versioned::new(Vec::new(), latest(current, deleted)),
+            Versioning::VectorClock => {
+                let mut seen = tombstone_clock(deleted)?;
+                for sibling in siblings(current)? {
+                    for (node, counter) in clock(sibling.context.as_ref())? {
+                        let entry = seen.entry(node).or_insert(0);
+                        *entry = counter.max(*entry);
+                    }
+                    let entry = seen.entry(decode_id(&sibling.node)?).or_insert(0);
+                    *entry = sibling.counter.max(*entry);
+                }
+                Ok(tombstone(&seen))
+            }
+        }
+    }
+
+    /// Combines the tombstone a key was `deleted` with with one received from another node,
+    /// returning what should be stored instead, or nothing if the stored one already has it.
+    pub fn merge_deleted(
+        &self,
+        deleted: Option<&Versioned>,
+        received: Versioned,
+    ) -> Result<Option<Versioned>> {
+        match self {
+            Versioning::Timestamp => self.merge(deleted, received),
+            Versioning::VectorClock => {
+                let mut seen = tombstone_clock(deleted)?;
+                for (node, counter) in tombstone_clock(Some(&received))? {
+                    let entry = seen.entry(node).or_insert(0);
+                    *entry = counter.max(*entry);
+                }
+                let merged = tombstone(&seen);
+                Ok(match deleted {
+                    Some(deleted) if deleted.version == merged.version => None,
+                    _ => Some(merged),
+                })
+            }
+        }
+    }
+
+    /// Returns what is left of a value once the writes its key was `deleted` over are
+    /// removed from it: with timestamps the value if it was written after the deletion,
+    /// with vector clocks the siblings the deletion did not see.
+    pub fn surviving(&self, deleted: &Versioned, value: Versioned) -> Result<Option<Versioned>> {
+        match self {
+            Versioning::Timestamp => Ok((value.version > deleted.version).then_some(value)),
+            Versioning::VectorClock => {
+                let seen = tombstone_clock(Some(deleted))?;
+                let mut kept = Vec::new();
+                for sibling in siblings(Some(&value))? {
+                    if !covers(&seen, &sibling)? {
+                        kept.push(sibling);
+                    }
+                }
+                Ok((!kept.is_empty()).then(|| stored(kept)))
+            }
+        }
+    }
+
+    /// Returns the values of a stored key as a client sees them.
+    pub fn values(&self, value: &Versioned) -> Result<Values> {
+        match self {
+            Versioning::Timestamp => Ok(Values {
+                siblings: vec![value.value.clone()],
+                context: None,
+            }),
+            Versioning::VectorClock => {
+                let siblings = siblings(Some(value))?;
+                let mut context = BTreeMap::new();
+                for sibling in &siblings {
+                    for (node, counter) in clock(sibling.context.as_ref())? {
+                        let entry = context.entry(node).or_insert(0);
+                        *entry = counter.max(*entry);
+                    }
+                    let entry = context.entry(decode_id(&sibling.node)?).or_insert(0);
+                    *entry = sibling.counter.max(*entry);
+                }
+                Ok(Values {
+                    siblings: siblings.into_iter().map(|sibling| sibling.value).collect(),
+                    context: Some(encode_clock(&context).encode_to_vec()),
+                })
+            }
+        }
+    }
+}
+
+/// Returns whichever of the value and tombstone of a key has the latest timestamp.
+fn latest<'a>(
+    current: Option<&'a Versioned>,
+    deleted: Option<&'a Versioned>,
+) -> Option<&'a Versioned> {
+    current
+        .into_iter()
+        .chain(deleted)
+        .max_by_key(|value| value.version)
+}
+
+fn siblings(value: Option<&Versioned>) -> Result<Vec<Sibling>> {
+    match value {
+        Some(value) => Ok(Siblings::decode(value.value.as_slice())
+            .map_err(|err| Error::Parse(format!("invalid siblings: {}", err)))?
+            .siblings),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Stores siblings in a canonical order, versioned by their hash so that nodes holding
+/// the same siblings agree on the version.
+fn stored(mut siblings: Vec<Sibling>) -> Versioned {
+    siblings.sort_by(|a, b| (&a.node, a.counter, &a.value).cmp(&(&b.node, b.counter, &b.value)));
+    let value = Siblings { siblings }.encode_to_vec();
+    let version = xxh3_64(&value);
+    Versioned { value, version }
+}
+
+/// Stores the clock of the writes a deletion saw as its tombstone, versioned by its hash.
+fn tombstone(seen: &BTreeMap<RingId, u64>) -> Versioned {
+    let value = encode_clock(seen).encode_to_vec();
+    let version = xxh3_64(&value);
+    Versioned { value, version }
+}
+
+fn tombstone_clock(deleted: Option<&Versioned>) -> Result<BTreeMap<RingId, u64>> {
+    match deleted {
+        Some(deleted) => decode_clock(&deleted.value),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Whether a write with the context has seen the sibling.
+fn covers(context: &BTreeMap<RingId, u64>, sibling: &Sibling) -> Result<bool> {
+    let node = decode_id(&sibling.node)?;
+    Ok(context
+        .get(&node)
+        .is_some_and(|counter| *counter >= sibling.counter))
+}
+
+fn clock(clock: Option<&Clock>) -> Result<BTreeMap<RingId, u64>> {
+    let mut counters = BTreeMap::new();
+    for entry in clock
+        .map(|clock| clock.entries.as_slice())
+        .unwrap_or_default()
+    {
+        counters.insert(decode_id(&entry.node)?, entry.counter);
+    }
+    Ok(counters)
+}
+
+fn decode_clock(bytes: &[u8]) -> Result<BTreeMap<RingId, u64>> {
+    let decoded =
+        Clock::decode(bytes).map_err(|err| Error::Parse(format!("invalid context: {}", err)))?;
+    clock(Some(&decoded))
+}
+
+fn encode_clock(counters: &BTreeMap<RingId, u64>) -> Clock {
+    Clock {
+        entries: counters
+            .iter()
+            .map(|(node, counter)| ClockEntry {
+                node: encode_id(*node),
+                counter: *counter,
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_vector_clock_siblings() -> Result<()> {
+    let versioning = Versioning::VectorClock;
+    let first = versioning.write(1, None, None, b"a".to_vec(), None)?;
+    let context = versioning.values(&first)?.context;
+
+    // Two writes made with the same context did not see each other.
+    let left = versioning.write(1, Some(&first), None, b"b".to_vec(), context.as_deref())?;
+    let right = versioning.write(2, Some(&first), None, b"c".to_vec(), context.as_deref())?;
+    let merged = versioning.merge(Some(&left), right.clone())?.unwrap();
+    assert_eq!(
+        versioning.merge(Some(&right), left.clone())?,
+        Some(merged.clone())
+    );
+    assert_eq!(versioning.merge(Some(&merged), left)?, None);
+    let values = versioning.values(&merged)?;
+    assert_eq!(values.siblings, vec![b"b".to_vec(), b"c".to_vec()]);
+    let context = values.context;
+
+    // A write with the context of both replaces them, a blind one is kept beside it.
+    let resolved = versioning.write(2, Some(&merged), None, b"d".to_vec(), context.as_deref())?;
+    assert_eq!(versioning.values(&resolved)?.siblings, vec![b"d".to_vec()]);
+    assert_eq!(
+        versioning.merge(Some(&merged), resolved.clone())?,
+        Some(resolved.clone())
+    );
+    let blind = versioning.write(2, Some(&resolved), None, b"e".to_vec(), None)?;
+    assert_eq!(
+        versioning.values(&blind)?.siblings,
+        vec![b"d".to_vec(), b"e".to_vec()]
+    );
+    Ok(())
+}
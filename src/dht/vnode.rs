@@ -0,0 +1,449 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{info, warn};
+use tokio::sync::RwLock;
+use tonic::transport::Channel;
+use tonic::Request;
+
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::{EncodedQuery, NeighborRegisterInfo, NeighborType, NodeId, OperationType};
+use crate::rpc::registry::Node;
+use crate::{decode_id, encode_id, HashRing, RingId, Step};
+
+use super::service::DhtNodeService;
+use super::store::{Store, Versioned};
+use super::versioning::Versioning;
+
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub id: RingId,
+    pub addr: String,
+    pub client: DhtNodeClient<Channel>,
+}
+
+#[derive(Debug, Default)]
+pub struct NeighborConnections {
+    pub prev: RwLock<Option<Neighbor>>,
+    pub next: RwLock<Option<Neighbor>>,
+}
+
+/// Which way the keys of a migrating range are going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationState {
+    /// The keys are copied to a token that joined inside this virtual node's arc.
+    Exporting,
+    /// The keys are copied from the previous owner of this virtual node's arc.
+    Importing,
+}
+
+/// A range of keys being transferred between two virtual nodes.
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub state: MigrationState,
+    pub start: RingId,
+    pub end: RingId,
+    /// The virtual node the keys go to when exporting, or come from when importing.
+    pub peer: Neighbor,
+    /// Keys deleted here while importing, which the transfer must not bring back.
+    pub deleted: HashSet<RingId>,
+}
+
+impl Migration {
+    pub fn new(state: MigrationState, start: RingId, end: RingId, peer: Neighbor) -> Self {
+        Migration {
+            state,
+            start,
+            end,
+            peer,
+            deleted: HashSet::new(),
+        }
+    }
+
+    fn covers(&self, key: RingId) -> bool {
+        self.start == self.end || HashRing::is_node_key(self.start, self.end, key)
+    }
+}
+
+/// One of the positions a node owns on the ring. Each virtual node keeps references to its
+/// own neighbors and stores the keys ranging from its id to its next neighbor.
+#[derive(Debug)]
+pub struct VirtualNode {
+    pub id: RingId,
+    pub store: Store,
+    pub neighbors: NeighborConnections,
+    /// Number of queries executed on this virtual node, used to gauge its load.
+    pub requests: AtomicU64,
+    /// Ranges of keys being transferred from or to this virtual node.
+    pub migrations: RwLock<Vec<Migration>>,
+    /// Set once the virtual node leaves the ring. Writes hold the lock while they run,
+    /// so none of them lands after the keys are listed for handoff.
+    closed: RwLock<bool>,
+}
+
+impl VirtualNode {
+    pub fn new(id: RingId, versioning: Versioning) -> Self {
+        VirtualNode {
+            id,
+            store: Store::new(versioning),
+            neighbors: NeighborConnections::default(),
+            requests: AtomicU64::new(0),
+            migrations: RwLock::new(Vec::new()),
+            closed: RwLock::new(false),
+        }
+    }
+
+    /// Stops accepting writes, waiting for those in progress to finish.
+    pub async fn close(&self) {
+        *self.closed.write().await = true;
+    }
+
+    /// Records a range starting to migrate, replacing the state of an earlier attempt.
+    pub async fn start_migration(&self, migration: Migration) {
+        let mut migrations = self.migrations.write().await;
+        migrations.retain(|m| !(m.state == migration.state && m.start == migration.start));
+        info!(
+            "Range {:x} to {:x} of #{:x} is {:?} with #{:x}",
+            migration.start, migration.end, self.id, migration.state, migration.peer.id
+        );
+        migrations.push(migration);
+    }
+
+    pub async fn end_migration(&self, state: MigrationState, start: RingId) {
+        self.migrations
+            .write()
+            .await
+            .retain(|m| !(m.state == state && m.start == start));
+    }
+
+    /// Returns the virtual node the key is being exported to, if it is still the next
+    /// neighbor: requests for the key that still reach this virtual node are sent after it.
+    pub async fn exporting_to(&self, key: RingId) -> Option<Neighbor> {
+        let next = self
+            .neighbors
+            .next
+            .read()
+            .await
+            .as_ref()
+            .map(|next| next.id);
+        self.migrations
+            .read()
+            .await
+            .iter()
+            .find(|m| m.state == MigrationState::Exporting && m.covers(key))
+            .filter(|m| Some(m.peer.id) == next)
+            .map(|m| m.peer.clone())
+    }
+
+    /// Returns the previous owner of the key while its range is imported, unless the key
+    /// was deleted here since: the previous owner may still hold the copy it had.
+    async fn importing_from(&self, key: RingId) -> Option<Neighbor> {
+        self.migrations
+            .read()
+            .await
+            .iter()
+            .find(|m| m.state == MigrationState::Importing && m.covers(key))
+            .filter(|m| !m.deleted.contains(&key))
+            .map(|m| m.peer.clone())
+    }
+
+    /// Stores a key received from the previous owner of the range, unless it was deleted
+    /// here since the transfer started, or the tombstone of a key it deleted.
+    pub async fn import(&self, key: RingId, value: Versioned, deleted: bool) -> Result<()> {
+        let migrations = self.migrations.read().await;
+        if deleted {
+            self.store.merge_deleted(&key, value).await?;
+        } else if !migrations.iter().any(|m| m.deleted.contains(&key)) {
+            self.store.merge(&key, value).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads a key not received yet from the previous owner of the range.
+    async fn read_from(peer: &Neighbor, key: RingId) -> Option<Versioned> {
+        let request = Request::new(NodeId { id: encode_id(key) });
+        match peer.client.clone().read_replica(request).await {
+            Ok(result) => {
+                let result = result.into_inner();
+                result.value.map(|value| Versioned {
+                    value,
+                    version: result.version,
+                })
+            }
+            Err(err) => {
+                warn!("Reading key {:x} from #{:x} failed: {}", key, peer.id, err);
+                None
+            }
+        }
+    }
+
+    /// Deletes a key, remembering it if its range is being imported so the transfer does
+    /// not bring it back. A key not received yet is looked up on the previous owner, and
+    /// deleted here so that it gets a tombstone.
+    async fn delete(&self, key: RingId) -> Result<Option<Versioned>> {
+        let peer = {
+            let mut migrations = self.migrations.write().await;
+            let importing = migrations
+                .iter_mut()
+                .find(|m| m.state == MigrationState::Importing && m.covers(key));
+            match importing {
+                Some(migration) => {
+                    migration.deleted.insert(key);
+                    Some(migration.peer.clone())
+                }
+                None => None,
+            }
+        };
+        match (self.store.delete(&key).await?, peer) {
+            (None, Some(peer)) => match Self::read_from(&peer, key).await {
+                Some(value) => {
+                    self.store.merge(&key, value.clone()).await?;
+                    self.store.delete(&key).await?;
+                    Ok(Some(value))
+                }
+                None => Ok(None),
+            },
+            (deleted, _) => Ok(deleted),
+        }
+    }
+
+    pub async fn get_node_info(node: &RwLock<Option<Neighbor>>) -> Option<Node> {
+        let node = node.read().await;
+        node.as_ref().map(|n| Node {
+            id: encode_id(n.id),
+            addr: n.addr.clone(),
+        })
+    }
+
+    pub async fn switch_neighbor(&self, info: &NeighborRegisterInfo) -> Result<()> {
+        let ty = NeighborType::from_i32(info.ty).ok_or(Error::Internal(format!(
+            "Neighbor type {} is not valid",
+            info.ty
+        )))?;
+        let neighbor = match ty {
+            NeighborType::Previous => &self.neighbors.prev,
+            NeighborType::Next => &self.neighbors.next,
+        };
+        let id = decode_id(&info.id)?;
+
+        // A token registering itself as our neighbor means we are left alone in the ring.
+        if id == self.id {
+            *neighbor.write().await = None;
+            info!(
+                "Removed {} neighbor of #{:x}",
+                match ty {
+                    NeighborType::Previous => "previous",
+                    NeighborType::Next => "next",
+                },
+                self.id
+            );
+            return Ok(());
+        }
+
+        let client = DhtNodeService::try_connect_node(&Node {
+            id: info.id.clone(),
+            addr: info.addr.clone(),
+        })
+        .await?;
+        let mut neighbor = neighbor.write().await;
+        *neighbor = Some(Neighbor {
+            id,
+            addr: info.addr.clone(),
+            client,
+        });
+
+        info!(
+            "Replaced {} neighbor of #{:x} with #{:x}",
+            {
+                match ty {
+                    NeighborType::Previous => "previous",
+                    NeighborType::Next => "next",
+                }
+            },
+            self.id,
+            id
+        );
+
+        Ok(())
+    }
+
+    /// Returns the neighbor a request for the key should be forwarded to,
+    /// or `None` if the key belongs to this virtual node.
+    pub async fn next_hop(&self, key: RingId) -> Result<Option<Neighbor>> {
+        // Without neighbors the virtual node is alone and owns the whole ring.
+        // Otherwise, it owns the keys from its id up to its next neighbor. A virtual node
+        // with no next neighbor yet is still joining: its previous neighbor owns its keys
+        // until it is linked in.
+        let next_neighbor = match self.neighbors.next.read().await.clone() {
+            Some(next_neighbor) if !HashRing::is_node_key(self.id, next_neighbor.id, key) => {
+                next_neighbor
+            }
+            Some(_) => return Ok(None),
+            None => return Ok(self.neighbors.prev.read().await.clone()),
+        };
+
+        let prev_neighbor = self
+            .neighbors
+            .prev
+            .read()
+            .await
+            .clone()
+            .ok_or(Error::Internal(format!(
+                "Missing previous neighbor on node {:x}.",
+                self.id
+            )))?;
+
+        Ok(
+            match HashRing::step(self.id, prev_neighbor.id, next_neighbor.id, key) {
+                Step::Owner => None,
+                Step::Previous => Some(prev_neighbor),
+                Step::Next => Some(next_neighbor),
+            },
+        )
+    }
+
+    pub async fn execute_query(&self, query: &EncodedQuery) -> Result<Option<Versioned>> {
+        let key = decode_id(&query.key)?;
+
+        info!("Executing query for key {:x} on #{:x}.", key, self.id);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+
+        let ty = OperationType::from_i32(query.ty).unwrap();
+        let closed = self.closed.read().await;
+        if *closed && ty != OperationType::Get {
+            return Err(Error::Unavailable(format!(
+                "#{:x} is leaving the ring.",
+                self.id
+            )));
+        }
+
+        match ty {
+            OperationType::Set => {
+                let value = query.value.clone();
+                match value {
+                    None => Err(Error::Value("Value not provided.".into())),
+                    // Writes are counted per token in vector clocks.
+                    Some(value) => {
+                        let context = query.context.as_deref();
+                        self.store.write(&key, self.id, value, context).await
+                    }
+                }
+            }
+            OperationType::Get => {
+                let mut result = self.store.get(&key).await;
+                if result.is_none() {
+                    if let Some(peer) = self.importing_from(key).await {
+                        result = Self::read_from(&peer, key).await;
+                    }
+                }
+                match result {
+                    None => Err(Error::Value("Key not present in database.".into())),
+                    Some(_) => Ok(result),
+                }
+            }
+            OperationType::Delete => {
+                let result = self.delete(key).await?;
+                match result {
+                    None => Err(Error::Value("Key not present in database.".into())),
+                    Some(_) => Ok(result),
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_closed_vnode_refuses_writes() -> Result<()> {
+    let vnode = VirtualNode::new(1, Versioning::Timestamp);
+    let query = |ty: OperationType, value: Option<&[u8]>| EncodedQuery {
+        ty: ty.into(),
+        key: encode_id(2),
+        value: value.map(<[u8]>::to_vec),
+        ..EncodedQuery::default()
+    };
+    vnode
+        .execute_query(&query(OperationType::Set, Some(b"a")))
+        .await?;
+
+    vnode.close().await;
+    let result = vnode
+        .execute_query(&query(OperationType::Set, Some(b"b")))
+        .await;
+    assert!(matches!(result, Err(Error::Unavailable(_))));
+    let result = vnode
+        .execute_query(&query(OperationType::Delete, None))
+        .await;
+    assert!(matches!(result, Err(Error::Unavailable(_))));
+    let value = vnode
+        .execute_query(&query(OperationType::Get, None))
+        .await?;
+    assert_eq!(value.map(|value| value.value), Some(b"a".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_joining_vnode_forwards_to_owner() -> Result<()> {
+    let client = DhtNodeClient::new(
+        tonic::transport::Endpoint::from_static("http://127.0.0.1:50001").connect_lazy(),
+    );
+    let neighbor = |id: RingId| Neighbor {
+        id,
+        addr: "http://127.0.0.1:50001".into(),
+        client: client.clone(),
+    };
+    let vnode = VirtualNode::new(100, Versioning::Timestamp);
+    assert!(vnode.next_hop(150).await?.is_none());
+
+    // Until it is linked, its keys are still owned by the token before it.
+    *vnode.neighbors.prev.write().await = Some(neighbor(50));
+    for key in [50, 100, 150] {
+        assert_eq!(vnode.next_hop(key).await?.map(|n| n.id), Some(50));
+    }
+
+    *vnode.neighbors.next.write().await = Some(neighbor(200));
+    assert!(vnode.next_hop(150).await?.is_none());
+    assert_eq!(vnode.next_hop(250).await?.map(|n| n.id), Some(200));
+    assert_eq!(vnode.next_hop(60).await?.map(|n| n.id), Some(50));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrations_forward_exported_keys() -> Result<()> {
+    let client = DhtNodeClient::new(
+        tonic::transport::Endpoint::from_static("http://127.0.0.1:50001").connect_lazy(),
+    );
+    let neighbor = |id: RingId| Neighbor {
+        id,
+        addr: "http://127.0.0.1:50001".into(),
+        client: client.clone(),
+    };
+    let vnode = VirtualNode::new(100, Versioning::Timestamp);
+    *vnode.neighbors.next.write().await = Some(neighbor(200));
+
+    // A later attempt replaces the state of the earlier one for the same range.
+    let migration = |peer| Migration::new(MigrationState::Exporting, 150, 200, neighbor(peer));
+    vnode.start_migration(migration(300)).await;
+    vnode.start_migration(migration(200)).await;
+    vnode
+        .start_migration(Migration::new(
+            MigrationState::Importing,
+            50,
+            100,
+            neighbor(50),
+        ))
+        .await;
+    assert_eq!(vnode.migrations.read().await.len(), 2);
+
+    // Keys of the exported range are forwarded only while the peer is the next neighbor.
+    assert_eq!(vnode.exporting_to(160).await.map(|n| n.id), Some(200));
+    assert!(vnode.exporting_to(120).await.is_none());
+    *vnode.neighbors.next.write().await = Some(neighbor(180));
+    assert!(vnode.exporting_to(160).await.is_none());
+    *vnode.neighbors.next.write().await = Some(neighbor(200));
+
+    vnode.end_migration(MigrationState::Exporting, 150).await;
+    assert!(vnode.exporting_to(160).await.is_none());
+    assert_eq!(vnode.migrations.read().await.len(), 1);
+    Ok(())
+}
@@ -14,6 +14,15 @@ pub enum Error {
     Config(String),
     Internal(String),
     Parse(String),
+    /// A request was forwarded more times than allowed.
+    TtlExceeded(String),
+    /// A request came back to a virtual node it was already forwarded by.
+    RoutingLoop(String),
+    /// Too few replicas answered to meet a request's consistency level.
+    Unavailable(String),
+    /// A write was applied on its owner but too few replicas acknowledged it to meet its
+    /// consistency level. It is not rolled back, so later reads may still see it.
+    PartiallyApplied(String),
     Value(String),
 }
 
@@ -22,9 +31,14 @@ impl std::error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Error::Config(s) | Error::Internal(s) | Error::Parse(s) | Error::Value(s) => {
-                write!(f, "{}", s)
-            }
+            Error::Config(s)
+            | Error::Internal(s)
+            | Error::Parse(s)
+            | Error::TtlExceeded(s)
+            | Error::RoutingLoop(s)
+            | Error::Unavailable(s)
+            | Error::PartiallyApplied(s)
+            | Error::Value(s) => write!(f, "{}", s),
             Error::Abort => write!(f, "Operation aborted"),
         }
     }
@@ -80,7 +94,17 @@ impl From<tokio::sync::oneshot::error::RecvError> for Error {
 
 impl From<Error> for tonic::Status {
     fn from(err: Error) -> Self {
-        tonic::Status::internal(err.to_string())
+        match err {
+            // The ring changed while the request was routed, so it may succeed if retried.
+            Error::TtlExceeded(_) | Error::RoutingLoop(_) => {
+                tonic::Status::aborted(err.to_string())
+            }
+            // Too few members answered, so the sender may try others.
+            Error::Unavailable(_) => tonic::Status::unavailable(err.to_string()),
+            // The write may or may not be seen, unlike writes failing as unavailable.
+            Error::PartiallyApplied(_) => tonic::Status::unknown(err.to_string()),
+            _ => tonic::Status::internal(err.to_string()),
+        }
     }
 }
 
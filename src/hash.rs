@@ -1,32 +1,102 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::RingId;
 use sha2::{Digest, Sha256};
+use siphasher::sip128::SipHasher24;
 use std::time::{SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::xxh3_128;
 
-pub fn generate_id_hash(id: &str) -> Result<u64> {
+/// Hash function placing keys on the ring. Every node of a cluster must use the same one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KeyHash {
+    #[default]
+    Sha256,
+    Xxh3,
+    /// SipHash-2-4 keyed with a secret shared by the cluster.
+    SipHash([u8; 16]),
+}
+
+impl KeyHash {
+    /// Parses a hash function by name, with the cluster key in hex for SipHash.
+    pub fn parse(name: &str, key: Option<&str>) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "sha256" => Ok(KeyHash::Sha256),
+            "xxh3" => Ok(KeyHash::Xxh3),
+            "siphash" => {
+                let key = key.ok_or(Error::Config("SipHash requires a cluster key".into()))?;
+                let key = u128::from_str_radix(key.trim(), 16)
+                    .map_err(|_| Error::Parse(format!("invalid SipHash key {}", key)))?;
+                Ok(KeyHash::SipHash(key.to_be_bytes()))
+            }
+            _ => Err(Error::Parse(format!("unknown hash function {}", name))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyHash::Sha256 => "sha256",
+            KeyHash::Xxh3 => "xxh3",
+            KeyHash::SipHash(_) => "siphash",
+        }
+    }
+
+    /// Identifies the hash settings, so that nodes can compare them without revealing
+    /// the SipHash key to each other.
+    pub fn fingerprint(&self) -> Result<Vec<u8>> {
+        match self {
+            KeyHash::SipHash(key) => Ok(generate_hash(key)?.to_be_bytes().to_vec()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    pub fn hash(&self, input: &[u8]) -> Result<RingId> {
+        match self {
+            KeyHash::Sha256 => generate_hash(input),
+            KeyHash::Xxh3 => Ok(xxh3_128(input) as RingId),
+            KeyHash::SipHash(key) => {
+                Ok(SipHasher24::new_with_key(key).hash(input).as_u128() as RingId)
+            }
+        }
+    }
+}
+
+pub fn generate_id_hash(id: &str) -> Result<RingId> {
     let current_time = SystemTime::now().duration_since(UNIX_EPOCH)?;
     let salt = current_time.as_nanos().to_string();
     let salted_id = format!("{}_{}", id, salt);
-    let hash = generate_hash64(salted_id.as_bytes())?;
+    let hash = generate_hash(salted_id.as_bytes())?;
 
     Ok(hash)
 }
 
-pub fn generate_hash64(input: &[u8]) -> Result<u64> {
+/// Generates the ring positions of a node's virtual nodes, the first one being the node's id.
+pub fn generate_tokens(id: RingId, count: u32) -> Result<Vec<RingId>> {
+    let mut tokens = vec![id];
+    for i in 1..count {
+        tokens.push(generate_hash(format!("{:x}_{}", id, i).as_bytes())?);
+    }
+
+    Ok(tokens)
+}
+
+/// Hashes the input onto the ring, truncating SHA-256 to the width of the ring.
+pub fn generate_hash(input: &[u8]) -> Result<RingId> {
+    const WIDTH: usize = std::mem::size_of::<RingId>();
+
     let mut hasher = Sha256::new();
     hasher.update(input);
     let hash = hasher.finalize();
-    let hash_bytes = &hash[..8];
-    let hash_array: [u8; 8] = hash_bytes.try_into()?;
-    let output = u64::from_be_bytes(hash_array);
+    let hash_bytes = &hash[..WIDTH];
+    let hash_array: [u8; WIDTH] = hash_bytes.try_into()?;
+    let output = RingId::from_be_bytes(hash_array);
 
     Ok(output)
 }
 
 #[test]
-fn test_generate_hash64() -> Result<()> {
+fn test_generate_hash() -> Result<()> {
     let key = "key".to_owned();
-    let hash = generate_hash64(key.as_bytes())?;
-    let hash_retry = generate_hash64(key.as_bytes())?;
+    let hash = generate_hash(key.as_bytes())?;
+    let hash_retry = generate_hash(key.as_bytes())?;
 
     assert_eq!(hash, hash_retry);
     Ok(())
@@ -41,3 +111,30 @@ fn test_generate_id_hash() -> Result<()> {
     assert_ne!(hash, hash_retry);
     Ok(())
 }
+
+#[test]
+fn test_generate_tokens() -> Result<()> {
+    let id = generate_id_hash("69")?;
+    let tokens = generate_tokens(id, 8)?;
+    let tokens_retry = generate_tokens(id, 8)?;
+
+    assert_eq!(tokens.len(), 8);
+    assert_eq!(tokens[0], id);
+    assert_eq!(tokens, tokens_retry);
+    Ok(())
+}
+
+#[test]
+fn test_key_hash() -> Result<()> {
+    let key = "key".as_bytes();
+    let siphash = KeyHash::parse("siphash", Some("69"))?;
+    let other_siphash = KeyHash::parse("siphash", Some("70"))?;
+
+    assert_eq!(KeyHash::Sha256.hash(key)?, generate_hash(key)?);
+    assert_eq!(KeyHash::Xxh3.hash(key)?, KeyHash::Xxh3.hash(key)?);
+    assert_ne!(siphash.hash(key)?, other_siphash.hash(key)?);
+    assert_ne!(siphash.fingerprint()?, other_siphash.fingerprint()?);
+    assert!(KeyHash::parse("siphash", None).is_err());
+    assert!(KeyHash::parse("md5", None).is_err());
+    Ok(())
+}
@@ -1,23 +1,68 @@
+use std::collections::BTreeMap;
+
 pub mod error;
 pub mod hash;
 
 pub mod rpc;
 
 pub mod dht;
+pub mod rebalancer;
 pub mod registry;
 
+/// Identifier of positions and keys on the ring. The ring is 64 bits wide unless the
+/// `ring128` feature widens it to 128 bits; nodes of different widths cannot talk to
+/// each other.
+#[cfg(not(feature = "ring128"))]
+pub type RingId = u64;
+#[cfg(feature = "ring128")]
+pub type RingId = u128;
+
+/// Encodes an id as the bytes sent over the wire.
+pub fn encode_id(id: RingId) -> Vec<u8> {
+    id.to_be_bytes().to_vec()
+}
+
+/// Decodes an id from the bytes sent over the wire, failing if they are not as wide as the ring.
+pub fn decode_id(bytes: &[u8]) -> error::Result<RingId> {
+    Ok(RingId::from_be_bytes(bytes.try_into().map_err(|_| {
+        error::Error::Parse(format!(
+            "expected a {}-bit id, got {} bytes",
+            RingId::BITS,
+            bytes.len()
+        ))
+    })?))
+}
+
+pub fn encode_ids(ids: &[RingId]) -> Vec<Vec<u8>> {
+    ids.iter().map(|id| encode_id(*id)).collect()
+}
+
+pub fn decode_ids(ids: &[Vec<u8>]) -> error::Result<Vec<RingId>> {
+    ids.iter().map(|id| decode_id(id)).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct NodeInfo {
-    id: u64,
+    id: RingId,
     addr: String,
+    tokens: Vec<RingId>,
+    capacity: f64,
 }
 
 pub struct HashRing {}
 
+/// Where a virtual node sends a request for a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Owner,
+    Previous,
+    Next,
+}
+
 impl HashRing {
-    pub fn distance(a: u64, b: u64) -> u64 {
-        let bigger: u64;
-        let smaller: u64;
+    pub fn distance(a: RingId, b: RingId) -> RingId {
+        let bigger: RingId;
+        let smaller: RingId;
 
         if a > b {
             bigger = a;
@@ -27,19 +72,320 @@ impl HashRing {
             smaller = a;
         }
 
-        std::cmp::min(bigger - smaller, (u64::MAX - bigger) + smaller)
+        std::cmp::min(bigger - smaller, (RingId::MAX - bigger) + smaller)
     }
 
-    pub fn counter_clockwise_distance(a: u64, b: u64) -> u64 {
+    pub fn counter_clockwise_distance(a: RingId, b: RingId) -> RingId {
         if a > b {
             a - b
         } else {
-            (u64::MAX - b) + a
+            (RingId::MAX - b) + a
         }
     }
 
-    pub fn is_node_key(id: u64, next_id: u64, key: u64) -> bool {
+    pub fn is_node_key(id: RingId, next_id: RingId, key: RingId) -> bool {
         (id < next_id && (id <= key && key < next_id))
             || (id > next_id && (id <= key || key < next_id))
     }
+
+    /// Distance travelled clockwise from a to b.
+    pub fn clockwise_distance(a: RingId, b: RingId) -> RingId {
+        b.wrapping_sub(a)
+    }
+
+    /// Of the tokens, returns the closest one counter-clockwise from the key along with
+    /// what it maps to, the token being the one owning the key if the tokens were the
+    /// whole ring.
+    pub fn closest_preceding<V>(tokens: &BTreeMap<RingId, V>, key: RingId) -> Option<(RingId, &V)> {
+        tokens
+            .range(..=key)
+            .next_back()
+            .or(tokens.iter().next_back())
+            .map(|(token, value)| (*token, value))
+    }
+
+    /// Returns the first `n` distinct nodes met going clockwise from the owner of the key,
+    /// given the node each token of the ring belongs to: the owner followed by its
+    /// successors, which hold the key's replicas.
+    pub fn preference_list(ring: &BTreeMap<RingId, RingId>, key: RingId, n: usize) -> Vec<RingId> {
+        let start = match HashRing::closest_preceding(ring, key) {
+            Some((token, _)) => token,
+            None => return Vec::new(),
+        };
+
+        let mut nodes = Vec::with_capacity(n);
+        let following = ring.range(start..);
+        let wrapped = ring.range(..start);
+        for node in following.chain(wrapped).map(|(_, node)| *node) {
+            if nodes.len() == n {
+                break;
+            }
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+
+    /// Decides where the virtual node `id`, between `prev` and `next`, sends a request for
+    /// the key. Requests only travel clockwise, each hop strictly shortening the clockwise
+    /// distance to the key, except for a last step back when the previous neighbor owns
+    /// it. Starting every node from its token closest counter-clockwise from the key never
+    /// lengthens that distance either, so a request reaches the owner in at most as many
+    /// hops as there are tokens on the ring.
+    pub fn step(id: RingId, prev: RingId, next: RingId, key: RingId) -> Step {
+        if HashRing::is_node_key(id, next, key) {
+            Step::Owner
+        } else if HashRing::is_node_key(prev, id, key) {
+            Step::Previous
+        } else {
+            Step::Next
+        }
+    }
+
+    /// Number of tokens a node of the given capacity gets, so that the share
+    /// of the ring it owns is proportional to its capacity.
+    pub fn weighted_tokens(tokens: u32, capacity: f64) -> u32 {
+        ((tokens as f64 * capacity).round() as u32).max(1)
+    }
+
+    /// Returns the length of the arc owned by each token, from it up to the next token.
+    pub fn arcs(tokens: &[RingId]) -> Vec<RingId> {
+        let mut sorted = tokens.to_vec();
+        sorted.sort_unstable();
+
+        tokens
+            .iter()
+            .map(|token| {
+                let i = sorted.binary_search(token).unwrap();
+                let next = sorted[(i + 1) % sorted.len()];
+                next.wrapping_sub(*token)
+            })
+            .collect()
+    }
+}
+
+/// How keys are assigned to nodes, chosen when the cluster is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// Consistent hashing: each token owns the keys up to the next token on the ring.
+    #[default]
+    Ring,
+    /// Rendezvous hashing: each key belongs to the member scoring highest for it.
+    Rendezvous,
+}
+
+impl Placement {
+    pub fn parse(name: &str) -> error::Result<Self> {
+        match name.to_lowercase().as_str() {
+            "ring" => Ok(Placement::Ring),
+            "rendezvous" => Ok(Placement::Rendezvous),
+            _ => Err(error::Error::Parse(format!(
+                "unknown placement strategy {}",
+                name
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placement::Ring => "ring",
+            Placement::Rendezvous => "rendezvous",
+        }
+    }
+}
+
+pub struct Rendezvous {}
+
+impl Rendezvous {
+    /// Weighted score of a node for a key: the hash of both mapped into (0, 1) and
+    /// scaled so that a node wins a share of the keys proportional to its capacity.
+    pub fn score(node: RingId, capacity: f64, key: RingId) -> f64 {
+        let mut input = encode_id(node);
+        input.extend(encode_id(key));
+        let hash = xxhash_rust::xxh3::xxh3_64(&input);
+        let unit = (hash as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+        -capacity / unit.ln()
+    }
+
+    /// Returns the `n` nodes scoring highest for the key, owner first. These hold the
+    /// key's replicas.
+    pub fn ranked(nodes: &[(RingId, f64)], key: RingId, n: usize) -> Vec<RingId> {
+        let mut scores: Vec<(f64, RingId)> = nodes
+            .iter()
+            .map(|(node, capacity)| (Rendezvous::score(*node, *capacity, key), *node))
+            .collect();
+        scores.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        scores.into_iter().take(n).map(|(_, node)| node).collect()
+    }
+
+    /// Returns the node owning the key among the given nodes and their capacities.
+    /// Adding a node only moves to it keys it now wins, and removing one only moves
+    /// its own keys, each to the node that scored second for it.
+    pub fn owner(nodes: &[(RingId, f64)], key: RingId) -> Option<RingId> {
+        nodes
+            .iter()
+            .map(|(node, capacity)| (Rendezvous::score(*node, *capacity, key), *node))
+            .max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(_, node)| node)
+    }
+}
+
+#[test]
+fn test_weighted_tokens() {
+    assert_eq!(HashRing::weighted_tokens(8, 1.0), 8);
+    assert_eq!(HashRing::weighted_tokens(8, 2.5), 20);
+    assert_eq!(HashRing::weighted_tokens(8, 0.01), 1);
+}
+
+#[test]
+fn test_ownership_proportional_to_capacity() -> error::Result<()> {
+    let capacities = [1.0, 1.0, 2.0, 4.0];
+    let mut tokens = Vec::new();
+    let mut owners = Vec::new();
+    for (node, capacity) in capacities.iter().enumerate() {
+        let id = hash::generate_hash(node.to_string().as_bytes())?;
+        let count = HashRing::weighted_tokens(64, *capacity);
+        for token in hash::generate_tokens(id, count)? {
+            tokens.push(token);
+            owners.push(node);
+        }
+    }
+
+    let mut owned = vec![0f64; capacities.len()];
+    for (owner, arc) in owners.iter().zip(HashRing::arcs(&tokens)) {
+        owned[*owner] += arc as f64 / RingId::MAX as f64;
+    }
+
+    let total_capacity: f64 = capacities.iter().sum();
+    for (owned, capacity) in owned.iter().zip(capacities) {
+        let expected = capacity / total_capacity;
+        assert!((owned - expected).abs() < expected * 0.35);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_step() {
+    assert_eq!(HashRing::step(100, 0, 200, 150), Step::Owner);
+    assert_eq!(HashRing::step(100, 0, 200, 50), Step::Previous);
+    assert_eq!(HashRing::step(100, 0, 200, 250), Step::Next);
+    assert_eq!(HashRing::step(RingId::MAX - 10, 100, 10, 5), Step::Owner);
+}
+
+#[test]
+fn test_routing_reaches_owner() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    for seed in 0..100 {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // A random ring of nodes owning a random number of tokens each.
+        let nodes: Vec<BTreeMap<RingId, ()>> = (0..rng.gen_range(1..20))
+            .map(|_| (0..rng.gen_range(1..8)).map(|_| (rng.gen(), ())).collect())
+            .collect();
+        let ring: BTreeMap<RingId, usize> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(node, tokens)| tokens.keys().map(move |token| (*token, node)))
+            .collect();
+        let tokens: std::collections::BTreeSet<RingId> = ring.keys().copied().collect();
+        let neighbors = |token: RingId| {
+            let prev = tokens
+                .range(..token)
+                .next_back()
+                .or(tokens.iter().next_back());
+            let next = tokens
+                .range((std::ops::Bound::Excluded(token), std::ops::Bound::Unbounded))
+                .next()
+                .or(tokens.iter().next());
+            (*prev.unwrap(), *next.unwrap())
+        };
+
+        for _ in 0..100 {
+            let key: RingId = rng.gen();
+            let mut node = rng.gen_range(0..nodes.len());
+            let mut hops = 0;
+            let owner = loop {
+                let (token, _) = HashRing::closest_preceding(&nodes[node], key).unwrap();
+                let (prev, next) = neighbors(token);
+                if tokens.len() == 1 {
+                    break token;
+                }
+                match HashRing::step(token, prev, next, key) {
+                    Step::Owner => break token,
+                    Step::Previous => node = ring[&prev],
+                    Step::Next => node = ring[&next],
+                }
+                hops += 1;
+                assert!(hops <= tokens.len(), "routing did not converge");
+            };
+
+            let (_, next) = neighbors(owner);
+            assert!(tokens.len() == 1 || HashRing::is_node_key(owner, next, key));
+            assert_eq!(HashRing::closest_preceding(&ring, key).unwrap().0, owner);
+        }
+    }
+}
+
+#[test]
+fn test_encode_id() -> error::Result<()> {
+    let id: RingId = 0x69;
+    assert_eq!(decode_id(&encode_id(id))?, id);
+    assert!(decode_id(&[0x69]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rendezvous_owner() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let nodes: Vec<(RingId, f64)> = vec![(1, 1.0), (2, 1.0), (3, 2.0)];
+    let keys: Vec<RingId> = (0..20000).map(|_| rng.gen()).collect();
+
+    let mut owned = [0f64; 3];
+    for key in &keys {
+        let owner = Rendezvous::owner(&nodes, *key).unwrap();
+        owned[owner as usize - 1] += 1.0 / keys.len() as f64;
+    }
+    for (owned, expected) in owned.iter().zip([0.25, 0.25, 0.5]) {
+        assert!((owned - expected).abs() < 0.02);
+    }
+
+    // A new node only takes keys over, it never shuffles them between the others.
+    let mut grown = nodes.clone();
+    grown.push((4, 1.0));
+    for key in &keys {
+        let before = Rendezvous::owner(&nodes, *key).unwrap();
+        let after = Rendezvous::owner(&grown, *key).unwrap();
+        assert!(after == before || after == 4);
+    }
+    assert_eq!(Rendezvous::owner(&[], 0), None);
+}
+
+#[test]
+fn test_preference_list() {
+    let ring = BTreeMap::from([(10, 1), (20, 1), (30, 2), (40, 3), (50, 2)]);
+
+    assert_eq!(HashRing::preference_list(&ring, 15, 3), vec![1, 2, 3]);
+    assert_eq!(HashRing::preference_list(&ring, 45, 2), vec![3, 2]);
+    assert_eq!(HashRing::preference_list(&ring, 35, 3), vec![2, 3, 1]);
+    assert_eq!(HashRing::preference_list(&ring, 5, 2), vec![2, 1]);
+    assert_eq!(HashRing::preference_list(&ring, 55, 3), vec![2, 1, 3]);
+    assert_eq!(HashRing::preference_list(&ring, 15, 5), vec![1, 2, 3]);
+    assert!(HashRing::preference_list(&BTreeMap::new(), 15, 3).is_empty());
+}
+
+#[test]
+fn test_rendezvous_ranked() {
+    let nodes: Vec<(RingId, f64)> = vec![(1, 1.0), (2, 1.0), (3, 1.0)];
+    for key in 0..100 {
+        let ranked = Rendezvous::ranked(&nodes, key, 2);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0], Rendezvous::owner(&nodes, key).unwrap());
+        assert_ne!(ranked[0], ranked[1]);
+    }
 }
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use tonic::Request;
+
+use crate::dht::service::DhtNodeService;
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::{MemberState, NodeLoad, TokenMove};
+use crate::rpc::registry::Node;
+use crate::{decode_id, encode_id, RingId};
+
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(60);
+/// How much more loaded than its fair share a node must be before it is relieved.
+pub const DEFAULT_THRESHOLD: f64 = 1.25;
+pub const DEFAULT_MOVES: usize = 1;
+
+#[derive(Debug, Clone)]
+pub struct RebalancerConfig {
+    /// Time between load samples (`REBALANCE_PERIOD`, in seconds).
+    pub period: Duration,
+    /// Load relative to its fair share above which a node is relieved (`REBALANCE_THRESHOLD`).
+    pub threshold: f64,
+    /// Maximum number of token moves per period (`REBALANCE_MOVES`).
+    pub moves: usize,
+    /// Only print the proposed moves instead of applying them.
+    pub dry_run: bool,
+}
+
+impl Default for RebalancerConfig {
+    fn default() -> Self {
+        RebalancerConfig {
+            period: DEFAULT_PERIOD,
+            threshold: DEFAULT_THRESHOLD,
+            moves: DEFAULT_MOVES,
+            dry_run: false,
+        }
+    }
+}
+
+impl RebalancerConfig {
+    pub fn from_env() -> Result<Self> {
+        let mut config = RebalancerConfig::default();
+
+        if let Ok(period) = std::env::var("REBALANCE_PERIOD") {
+            config.period =
+                Duration::from_secs(period.parse().map_err(|_| {
+                    Error::Parse(format!("invalid REBALANCE_PERIOD value {}", period))
+                })?);
+        }
+        if let Ok(threshold) = std::env::var("REBALANCE_THRESHOLD") {
+            config.threshold = threshold.parse().map_err(|_| {
+                Error::Parse(format!("invalid REBALANCE_THRESHOLD value {}", threshold))
+            })?;
+        }
+        if let Ok(moves) = std::env::var("REBALANCE_MOVES") {
+            config.moves = moves
+                .parse()
+                .map_err(|_| Error::Parse(format!("invalid REBALANCE_MOVES value {}", moves)))?;
+        }
+        if config.threshold < 1.0 {
+            return Err(Error::Config(
+                "REBALANCE_THRESHOLD must be at least 1".into(),
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Load of a single token as seen by the rebalancer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenSample {
+    pub token: RingId,
+    pub keys: u64,
+    /// Queries per second executed on the token since the previous sample.
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeSample {
+    pub addr: String,
+    pub capacity: f64,
+    pub tokens: Vec<TokenSample>,
+}
+
+/// Moves a node's token to a new position on the ring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Move {
+    pub addr: String,
+    pub token: RingId,
+    pub target: RingId,
+}
+
+/// A token along with the node owning it and its share of the cluster's load.
+struct Position {
+    token: RingId,
+    node: usize,
+    load: f64,
+}
+
+/// Proposes up to `max_moves` token moves evening out the load of the nodes relative
+/// to their capacity. A token's load is its share of the keys plus its share of the
+/// request rate. Each move takes the least loaded token of the least loaded node and
+/// places it halfway through the most loaded arc of the most loaded node, as long as
+/// that node is loaded beyond `threshold` times its fair share.
+pub fn plan(nodes: &[NodeSample], threshold: f64, max_moves: usize) -> Vec<Move> {
+    let total_keys: u64 = nodes.iter().flat_map(|n| &n.tokens).map(|t| t.keys).sum();
+    let total_rate: f64 = nodes.iter().flat_map(|n| &n.tokens).map(|t| t.rate).sum();
+    let total_capacity: f64 = nodes.iter().map(|n| n.capacity).sum();
+
+    let share = |part: f64, total: f64| if total > 0.0 { part / total } else { 0.0 };
+    let mut positions: Vec<Position> = nodes
+        .iter()
+        .enumerate()
+        .flat_map(|(i, node)| {
+            node.tokens.iter().map(move |t| Position {
+                token: t.token,
+                node: i,
+                load: share(t.keys as f64, total_keys as f64) + share(t.rate, total_rate),
+            })
+        })
+        .collect();
+    positions.sort_unstable_by_key(|p| p.token);
+
+    let total_load: f64 = positions.iter().map(|p| p.load).sum();
+    if nodes.len() < 2 || total_load <= 0.0 || total_capacity <= 0.0 {
+        return Vec::new();
+    }
+    let fair_share = total_load / total_capacity;
+
+    let mut moves = Vec::new();
+    while moves.len() < max_moves {
+        let mut node_loads = vec![0.0; nodes.len()];
+        for position in &positions {
+            node_loads[position.node] += position.load;
+        }
+        let relative = |i: usize| node_loads[i] / nodes[i].capacity;
+
+        let by_load = |a: &usize, b: &usize| relative(*a).total_cmp(&relative(*b));
+        let (Some(hot), Some(cold)) = (
+            (0..nodes.len()).max_by(by_load),
+            (0..nodes.len()).min_by(by_load),
+        ) else {
+            break;
+        };
+        if hot == cold || relative(hot) <= threshold * fair_share {
+            break;
+        }
+
+        let heaviest = |node: usize| {
+            (0..positions.len())
+                .filter(|i| positions[*i].node == node)
+                .max_by(|a, b| positions[*a].load.total_cmp(&positions[*b].load))
+        };
+        let lightest = |node: usize| {
+            (0..positions.len())
+                .filter(|i| positions[*i].node == node)
+                .min_by(|a, b| positions[*a].load.total_cmp(&positions[*b].load))
+        };
+        let (Some(donor), Some(mover)) = (heaviest(hot), lightest(cold)) else {
+            break;
+        };
+
+        // The moved token's load goes to its previous neighbor and it takes half of the
+        // donor's arc; stop if that leaves the cold node worse off than the hot one was.
+        let half = positions[donor].load / 2.0;
+        let cold_after = (node_loads[cold] - positions[mover].load + half) / nodes[cold].capacity;
+        if cold_after >= relative(hot) {
+            break;
+        }
+
+        // The moved token may be the donor's next one, in which case the donor's arc
+        // extends up to the token after it.
+        let mut next = (donor + 1) % positions.len();
+        if next == mover {
+            next = (next + 1) % positions.len();
+        }
+        let next = positions[next].token;
+        let arc = next.wrapping_sub(positions[donor].token);
+        if arc < 2 {
+            break;
+        }
+        let target = positions[donor].token.wrapping_add(arc / 2);
+
+        moves.push(Move {
+            addr: nodes[cold].addr.clone(),
+            token: positions[mover].token,
+            target,
+        });
+
+        let prev = (mover + positions.len() - 1) % positions.len();
+        positions[prev].load += positions[mover].load;
+        positions[donor].load -= half;
+        positions.remove(mover);
+        positions.push(Position {
+            token: target,
+            node: cold,
+            load: half,
+        });
+        positions.sort_unstable_by_key(|p| p.token);
+    }
+
+    moves
+}
+
+/// Where the rebalancer finds the nodes of the network.
+pub enum Discovery {
+    Registry,
+    Nodes(Vec<String>),
+}
+
+/// Periodically samples the load of every node and moves tokens to even it out.
+pub struct Rebalancer {
+    config: RebalancerConfig,
+    discovery: Discovery,
+    /// Request counters of each token at the previous sample, to compute rates.
+    last_requests: HashMap<RingId, u64>,
+    last_sample: Option<Instant>,
+}
+
+impl Rebalancer {
+    pub fn new(config: RebalancerConfig, discovery: Discovery) -> Self {
+        Rebalancer {
+            config,
+            discovery,
+            last_requests: HashMap::new(),
+            last_sample: None,
+        }
+    }
+
+    async fn nodes(&self) -> Result<Vec<String>> {
+        match &self.discovery {
+            Discovery::Registry => {
+                let nodes = DhtNodeService::try_connect_registry()
+                    .await?
+                    .get_connected_nodes(Request::new(()))
+                    .await?
+                    .into_inner()
+                    .nodes;
+                Ok(nodes.into_iter().map(|node| node.addr).collect())
+            }
+            Discovery::Nodes(addrs) => {
+                for addr in addrs {
+                    let members = match DhtNodeClient::connect(addr.clone()).await {
+                        Ok(mut client) => client.get_members(Request::new(())).await,
+                        Err(err) => {
+                            warn!("Connection to {} failed: {}", addr, err);
+                            continue;
+                        }
+                    };
+                    match members {
+                        Ok(members) => {
+                            return Ok(members
+                                .into_inner()
+                                .members
+                                .into_iter()
+                                .filter(|member| member.state == MemberState::Alive as i32)
+                                .map(|member| member.addr)
+                                .collect())
+                        }
+                        Err(err) => warn!("Getting members from {} failed: {}", addr, err),
+                    }
+                }
+                Err(Error::Config("None of the nodes could be reached.".into()))
+            }
+        }
+    }
+
+    /// Gathers the load of every node, turning request counters into rates since the
+    /// previous sample. Nodes that cannot be reached are left out of it.
+    pub async fn sample(&mut self) -> Result<Vec<NodeSample>> {
+        let mut loads: Vec<NodeLoad> = Vec::new();
+        for addr in self.nodes().await? {
+            let load = match DhtNodeClient::connect(addr.clone()).await {
+                Ok(mut client) => client.get_load(Request::new(())).await,
+                Err(err) => {
+                    warn!("Connection to {} failed: {}", addr, err);
+                    continue;
+                }
+            };
+            match load {
+                Ok(load) => loads.push(load.into_inner()),
+                Err(err) => warn!("Getting the load of {} failed: {}", addr, err),
+            }
+        }
+
+        let now = Instant::now();
+        let elapsed = self
+            .last_sample
+            .map(|last| now.duration_since(last).as_secs_f64());
+        self.last_sample = Some(now);
+
+        let mut requests = HashMap::new();
+        let mut samples = Vec::with_capacity(loads.len());
+        for load in loads {
+            let mut tokens = Vec::with_capacity(load.tokens.len());
+            for t in load.tokens {
+                let token = decode_id(&t.token)?;
+                requests.insert(token, t.requests);
+                let rate = match (elapsed, self.last_requests.get(&token)) {
+                    (Some(elapsed), Some(last)) if elapsed > 0.0 => {
+                        t.requests.saturating_sub(*last) as f64 / elapsed
+                    }
+                    _ => 0.0,
+                };
+                tokens.push(TokenSample {
+                    token,
+                    keys: t.keys,
+                    rate,
+                });
+            }
+            samples.push(NodeSample {
+                addr: load.addr,
+                capacity: load.capacity,
+                tokens,
+            });
+        }
+        self.last_requests = requests;
+
+        Ok(samples)
+    }
+
+    /// Samples the load, then proposes and, unless on a dry run, applies the moves.
+    pub async fn rebalance(&mut self) -> Result<Vec<Move>> {
+        let samples = self.sample().await?;
+        let moves = plan(&samples, self.config.threshold, self.config.moves);
+
+        for m in &moves {
+            if self.config.dry_run {
+                println!(
+                    "Would move token #{:x} of {} to #{:x}",
+                    m.token, m.addr, m.target
+                );
+                continue;
+            }
+
+            info!(
+                "Moving token #{:x} of {} to #{:x}",
+                m.token, m.addr, m.target
+            );
+            DhtNodeService::try_connect_node(&Node {
+                id: encode_id(m.token),
+                addr: m.addr.clone(),
+            })
+            .await?
+            .move_token(Request::new(TokenMove {
+                token: encode_id(m.token),
+                target: encode_id(m.target),
+            }))
+            .await?;
+        }
+        Ok(moves)
+    }
+
+    pub async fn run(&mut self) {
+        loop {
+            match self.rebalance().await {
+                Ok(moves) if moves.is_empty() => info!("Load is balanced."),
+                Ok(_) => {}
+                Err(err) => warn!("Rebalancing failed: {}", err),
+            }
+            tokio::time::sleep(self.config.period).await;
+        }
+    }
+}
+
+#[cfg(test)]
+fn node(addr: &str, capacity: f64, tokens: &[(RingId, u64)]) -> NodeSample {
+    NodeSample {
+        addr: addr.into(),
+        capacity,
+        tokens: tokens
+            .iter()
+            .map(|(token, keys)| TokenSample {
+                token: *token,
+                keys: *keys,
+                rate: 0.0,
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_plan_balanced() {
+    let nodes = [
+        node("a", 1.0, &[(0, 10), (200, 10)]),
+        node("b", 1.0, &[(100, 10), (300, 10)]),
+    ];
+    assert!(plan(&nodes, DEFAULT_THRESHOLD, 1).is_empty());
+}
+
+#[test]
+fn test_plan_splits_hottest_arc() {
+    let nodes = [
+        node("a", 1.0, &[(0, 100), (200, 10)]),
+        node("b", 1.0, &[(100, 6), (300, 5)]),
+    ];
+    assert_eq!(
+        plan(&nodes, DEFAULT_THRESHOLD, 1),
+        vec![Move {
+            addr: "b".into(),
+            token: 300,
+            target: 50,
+        }]
+    );
+}
+
+#[test]
+fn test_plan_accounts_for_capacity() {
+    let nodes = [
+        node("a", 3.0, &[(0, 30), (200, 30)]),
+        node("b", 1.0, &[(100, 10), (300, 10)]),
+    ];
+    assert!(plan(&nodes, DEFAULT_THRESHOLD, 1).is_empty());
+}
+
+#[tokio::test]
+async fn test_sample_skips_unreachable_nodes() -> Result<()> {
+    use crate::dht::config::NodeConfig;
+    use crate::rpc::dht::{Gossip, Member};
+    use crate::{encode_ids, RingId};
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let addr = format!("http://127.0.0.1:{}", port);
+    let node = DhtNodeService::join(addr.clone(), &[], NodeConfig::default()).await?;
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(crate::rpc::dht::dht_node_server::DhtNodeServer::new(node))
+            .serve(format!("127.0.0.1:{}", port).parse()?),
+    );
+    let mut client = loop {
+        if let Ok(client) = DhtNodeClient::connect(addr.clone()).await {
+            break client;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+
+    // The node sees a member on an address nothing listens on as alive.
+    let member = Member {
+        id: encode_id(2),
+        addr: "http://127.0.0.1:1".into(),
+        tokens: encode_ids(&[2 as RingId]),
+        capacity: 1.0,
+        ..Member::default()
+    };
+    let gossip = Gossip {
+        sender: Some(member),
+        updates: Vec::new(),
+    };
+    client.ping(Request::new(gossip)).await?;
+
+    let mut rebalancer = Rebalancer::new(
+        RebalancerConfig::default(),
+        Discovery::Nodes(vec![addr.clone()]),
+    );
+    let samples = rebalancer.sample().await?;
+    assert_eq!(samples.len(), 1);
+    assert_eq!(samples[0].addr, addr);
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::env;
+
+use crustyring::{
+    error::Result,
+    rebalancer::{Discovery, Rebalancer, RebalancerConfig},
+};
+use log::info;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut config = RebalancerConfig::from_env()?;
+
+    // With --dry-run the proposed moves are only printed.
+    if let Some(i) = args.iter().position(|arg| arg == "--dry-run") {
+        args.remove(i);
+        config.dry_run = true;
+    }
+
+    // Node addresses given as arguments are asked for the members of the network
+    // instead of the registry.
+    let discovery = if args.is_empty() {
+        Discovery::Registry
+    } else {
+        Discovery::Nodes(args)
+    };
+
+    let mut rebalancer = Rebalancer::new(config.clone(), discovery);
+    if config.dry_run {
+        // Two samples a period apart are needed to know the request rates.
+        info!("Sampling load for {:?}...", config.period);
+        rebalancer.sample().await?;
+        tokio::time::sleep(config.period).await;
+        if rebalancer.rebalance().await?.is_empty() {
+            println!("Load is balanced, no moves proposed.");
+        }
+        return Ok(());
+    }
+
+    info!("Rebalancing every {:?}", config.period);
+    rebalancer.run().await;
+    Ok(())
+}
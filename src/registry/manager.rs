@@ -1,43 +1,174 @@
+use std::path::PathBuf;
 use std::sync::Mutex;
 
-use log::info;
+use prost::Message;
 
-use crate::{error::Result, hash, HashRing, NodeInfo};
+use crate::{
+    decode_id, decode_ids,
+    dht::config::check_cluster_info,
+    encode_id, encode_ids,
+    error::{Error, Result},
+    hash,
+    rpc::registry::{ClusterInfo, RegisteredNode, RegistryState},
+    HashRing, NodeInfo, RingId,
+};
 
-#[derive(Debug)]
+/// Nodes registered in the cluster. If a file is given they are persisted to it along with
+/// the cluster settings, surviving restarts of the registry.
+#[derive(Debug, Default)]
 pub struct Manager {
     nodes: Mutex<Vec<NodeInfo>>,
+    /// Settings of the cluster, set by the first node to register.
+    cluster: Mutex<Option<ClusterInfo>>,
+    file: Option<PathBuf>,
 }
 
 impl Manager {
     pub fn new() -> Self {
         Manager {
             nodes: Mutex::new(Vec::new()),
+            cluster: Mutex::new(None),
+            file: None,
         }
     }
 
-    pub fn register_node(&self, addr: String) -> Result<u64> {
-        let hashed_id = hash::generate_id_hash(&addr)?;
-        let mut nodes = self.nodes.lock()?;
-        nodes.push(NodeInfo {
-            id: hashed_id,
+    /// Loads the nodes and cluster settings persisted to the file, if there is one.
+    pub fn load(file: Option<PathBuf>) -> Result<Self> {
+        let state = match &file {
+            Some(path) if path.exists() => {
+                let bytes = std::fs::read(path)?;
+                RegistryState::decode(bytes.as_slice())
+                    .map_err(|err| Error::Parse(format!("invalid registry file: {}", err)))?
+            }
+            _ => RegistryState::default(),
+        };
+        let nodes = state
+            .nodes
+            .into_iter()
+            .map(|node| {
+                Ok(NodeInfo {
+                    id: decode_id(&node.id)?,
+                    addr: node.addr,
+                    tokens: decode_ids(&node.tokens)?,
+                    capacity: node.capacity,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Manager {
+            nodes: Mutex::new(nodes),
+            cluster: Mutex::new(state.cluster),
+            file,
+        })
+    }
+
+    /// Checks that a joining node's settings match those of the cluster,
+    /// recording them as the cluster's if it is the first node.
+    pub fn check_cluster(&self, info: &ClusterInfo) -> Result<()> {
+        let nodes = self.nodes.lock()?;
+        let mut cluster = self.cluster.lock()?;
+        match cluster.as_ref() {
+            Some(cluster) => check_cluster_info(info, cluster),
+            None => {
+                *cluster = Some(info.clone());
+                self.persist(&nodes, cluster.as_ref())
+            }
+        }
+    }
+
+    /// Registers a node, giving it a number of tokens proportional to its capacity.
+    /// The node keeps the id it asks for, if any, and the tokens it already holds, if any,
+    /// as long as neither its id nor any of its tokens collide with those of another node.
+    /// A node registering again from the same address replaces its previous registration.
+    pub fn register_node(
+        &self,
+        addr: String,
+        id: Option<RingId>,
+        tokens: u32,
+        capacity: f64,
+        held_tokens: Vec<RingId>,
+    ) -> Result<NodeInfo> {
+        if capacity <= 0.0 {
+            return Err(Error::Value(format!(
+                "Capacity must be positive, got {}.",
+                capacity
+            )));
+        }
+
+        let id = match id {
+            Some(id) => id,
+            None => hash::generate_id_hash(&addr)?,
+        };
+        let tokens = match held_tokens.is_empty() {
+            true => hash::generate_tokens(id, HashRing::weighted_tokens(tokens.max(1), capacity))?,
+            false => held_tokens,
+        };
+        let node = NodeInfo {
+            id,
             addr,
-        });
+            tokens,
+            capacity,
+        };
 
-        Ok(hashed_id)
+        let mut nodes = self.nodes.lock()?;
+        if let Some(other) = nodes.iter().find(|other| {
+            other.addr != node.addr
+                && (other.id == node.id || other.tokens.iter().any(|t| node.tokens.contains(t)))
+        }) {
+            return Err(Error::Value(format!(
+                "Node #{:x} collides with node #{:x} on {}.",
+                node.id, other.id, other.addr
+            )));
+        }
+        nodes.retain(|other| other.addr != node.addr);
+        nodes.push(node.clone());
+        self.persist(&nodes, self.cluster.lock()?.as_ref())?;
+
+        Ok(node)
+    }
+
+    pub fn deregister_node(&self, id: RingId) -> Result<()> {
+        let mut nodes = self.nodes.lock()?;
+        let position = nodes
+            .iter()
+            .position(|node| node.id == id)
+            .ok_or(Error::Value(format!("Node #{:x} is not registered.", id)))?;
+        nodes.remove(position);
+        self.persist(&nodes, self.cluster.lock()?.as_ref())
+    }
+
+    /// Replaces the registration of a node found in the network with what it reported.
+    pub fn update_node(&self, node: NodeInfo) -> Result<()> {
+        let mut nodes = self.nodes.lock()?;
+        nodes.retain(|other| other.addr != node.addr && other.id != node.id);
+        nodes.push(node);
+        self.persist(&nodes, self.cluster.lock()?.as_ref())
+    }
+
+    /// Drops the node registered on the address, if any.
+    pub fn remove_node(&self, addr: &str) -> Result<()> {
+        let mut nodes = self.nodes.lock()?;
+        nodes.retain(|node| node.addr != addr);
+        self.persist(&nodes, self.cluster.lock()?.as_ref())
     }
 
-    pub fn find_closest_neighbor(&self, id: u64) -> Result<Option<NodeInfo>> {
-        let mut smallest_distance = std::u64::MAX;
+    /// Finds the node owning the closest token counter-clockwise from the given node's id.
+    pub fn find_closest_neighbor(&self, id: RingId) -> Result<Option<NodeInfo>> {
+        let mut smallest_distance = RingId::MAX;
         let mut result: Option<NodeInfo> = None;
 
         let nodes = self.nodes.lock()?;
 
         for node in nodes.as_slice() {
-            let distance = HashRing::counter_clockwise_distance(id, node.id);
-            if node.id != id && distance < smallest_distance {
-                smallest_distance = distance;
-                result = Some(node.clone());
+            if node.id == id {
+                continue;
+            }
+            for token in &node.tokens {
+                let distance = HashRing::counter_clockwise_distance(id, *token);
+                if distance < smallest_distance {
+                    smallest_distance = distance;
+                    result = Some(node.clone());
+                }
             }
         }
 
@@ -47,4 +178,188 @@ impl Manager {
     pub fn get_nodes(&self) -> Result<Vec<NodeInfo>> {
         Ok(self.nodes.lock()?.clone())
     }
+
+    fn persist(&self, nodes: &[NodeInfo], cluster: Option<&ClusterInfo>) -> Result<()> {
+        if let Some(path) = &self.file {
+            let state = RegistryState {
+                nodes: nodes
+                    .iter()
+                    .map(|node| RegisteredNode {
+                        id: encode_id(node.id),
+                        addr: node.addr.clone(),
+                        tokens: encode_ids(&node.tokens),
+                        capacity: node.capacity,
+                    })
+                    .collect(),
+                cluster: cluster.cloned(),
+            };
+            // Written aside and renamed over, so a crash never leaves a truncated file.
+            let written = path.with_extension("tmp");
+            std::fs::write(&written, state.encode_to_vec())?;
+            std::fs::rename(&written, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_register_node_rejects_collision() -> Result<()> {
+    let manager = Manager::new();
+    manager.register_node(
+        "http://0.0.0.0:50001".into(),
+        Some(0x69),
+        8,
+        1.0,
+        Vec::new(),
+    )?;
+
+    let result = manager.register_node(
+        "http://0.0.0.0:50002".into(),
+        Some(0x69),
+        8,
+        1.0,
+        Vec::new(),
+    );
+
+    assert!(matches!(result, Err(Error::Value(_))));
+    assert_eq!(manager.get_nodes()?.len(), 1);
+
+    // The same id is rejected from another address even with other tokens.
+    let result = manager.register_node(
+        "http://0.0.0.0:50002".into(),
+        Some(0x69),
+        8,
+        1.0,
+        vec![1, 2],
+    );
+    assert!(matches!(result, Err(Error::Value(_))));
+    assert_eq!(manager.get_nodes()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_register_node_again_from_same_address() -> Result<()> {
+    let manager = Manager::new();
+    manager.register_node(
+        "http://0.0.0.0:50001".into(),
+        Some(0x69),
+        8,
+        1.0,
+        Vec::new(),
+    )?;
+
+    let node = manager.register_node(
+        "http://0.0.0.0:50001".into(),
+        Some(0x69),
+        8,
+        1.0,
+        Vec::new(),
+    )?;
+
+    assert_eq!(node.id, 0x69);
+    assert_eq!(manager.get_nodes()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_check_cluster() -> Result<()> {
+    let manager = Manager::new();
+    let info = ClusterInfo {
+        ring_bits: RingId::BITS,
+        key_hash: "xxh3".into(),
+        key_hash_fingerprint: Vec::new(),
+        placement: "ring".into(),
+        replication: 1,
+        versioning: "timestamp".into(),
+        replication_mode: "owner".into(),
+    };
+    manager.check_cluster(&info)?;
+    manager.check_cluster(&info)?;
+
+    let result = manager.check_cluster(&ClusterInfo {
+        key_hash: "sha256".into(),
+        ..info
+    });
+
+    assert!(matches!(result, Err(Error::Config(_))));
+    Ok(())
+}
+
+#[test]
+fn test_registry_persisted() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("crustyring-registry-{}", std::process::id()));
+    let info = ClusterInfo {
+        ring_bits: RingId::BITS,
+        key_hash: "xxh3".into(),
+        key_hash_fingerprint: Vec::new(),
+        placement: "ring".into(),
+        replication: 1,
+        versioning: "timestamp".into(),
+        replication_mode: "owner".into(),
+    };
+
+    let manager = Manager::load(Some(path.clone()))?;
+    manager.check_cluster(&info)?;
+    manager.register_node(
+        "http://0.0.0.0:50001".into(),
+        Some(0x69),
+        8,
+        1.0,
+        Vec::new(),
+    )?;
+    manager.register_node(
+        "http://0.0.0.0:50002".into(),
+        Some(0x420),
+        8,
+        2.0,
+        Vec::new(),
+    )?;
+    manager.deregister_node(0x69)?;
+
+    let loaded = Manager::load(Some(path.clone()));
+    std::fs::remove_file(&path)?;
+    let loaded = loaded?;
+
+    let nodes = loaded.get_nodes()?;
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0].id, 0x420);
+    assert_eq!(nodes[0].tokens, manager.get_nodes()?[0].tokens);
+    assert_eq!(nodes[0].capacity, 2.0);
+    let result = loaded.check_cluster(&ClusterInfo {
+        key_hash: "sha256".into(),
+        ..info
+    });
+    assert!(matches!(result, Err(Error::Config(_))));
+    Ok(())
+}
+
+#[test]
+fn test_register_node_keeps_held_tokens() -> Result<()> {
+    let manager = Manager::new();
+    manager.register_node(
+        "http://0.0.0.0:50001".into(),
+        Some(0x69),
+        2,
+        1.0,
+        vec![1, 2],
+    )?;
+    let node = manager.register_node(
+        "http://0.0.0.0:50001".into(),
+        Some(0x69),
+        2,
+        1.0,
+        vec![1, 3],
+    )?;
+    assert_eq!(node.tokens, vec![1, 3]);
+    assert_eq!(manager.get_nodes()?[0].tokens, vec![1, 3]);
+
+    let result = manager.register_node(
+        "http://0.0.0.0:50002".into(),
+        Some(0x42),
+        2,
+        1.0,
+        vec![3, 4],
+    );
+    assert!(result.is_err());
+    Ok(())
 }
@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use crustyring::{
     error::Result,
@@ -13,7 +14,10 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     
-    let service = RegistryService::new();
+    // Nodes are persisted to REGISTRY_STATE_FILE if set, and probed again on restarts.
+    let file = std::env::var("REGISTRY_STATE_FILE").ok().map(PathBuf::from);
+    let service = RegistryService::load(file)?;
+    service.reconcile();
     let addr: SocketAddr = format!("0.0.0.0:{}", REGISTRY_PORT).parse()?;
     
     let hostname = std::env::var("REGISTRY_HOSTNAME").unwrap_or("0.0.0.0".to_owned());
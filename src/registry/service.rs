@@ -1,12 +1,26 @@
-use log::info;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
 use tonic::{Request, Response, Status};
 
 use super::manager::Manager;
+use crate::dht::config::DEFAULT_CAPACITY;
+use crate::error::{Error, Result};
+use crate::rpc::dht::dht_node_client::DhtNodeClient;
+use crate::rpc::dht::Member;
 use crate::rpc::registry::registry_server::Registry;
-use crate::rpc::registry::{ConnectionAddr, Node, Nodes, RegisterInfo};
+use crate::rpc::registry::{ClusterInfo, ConnectionAddr, Node, Nodes, RegisterInfo};
+use crate::{decode_id, decode_ids, encode_id, encode_ids, NodeInfo};
 
-#[derive(Debug)]
+/// Time a node is given to answer the registry probing it after a restart.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Interval between two probes of the nodes that did not answer after a restart.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
 pub struct RegistryService {
     manager: Arc<Manager>,
 }
@@ -17,6 +31,159 @@ impl RegistryService {
             manager: Arc::new(Manager::new()),
         }
     }
+
+    /// Creates the registry with the nodes persisted to the file, if there is one.
+    pub fn load(file: Option<PathBuf>) -> Result<Self> {
+        Ok(RegistryService {
+            manager: Arc::new(Manager::load(file)?),
+        })
+    }
+
+    /// Brings the nodes loaded on startup in line with the network in the background,
+    /// while the registry serves: each node is probed for the members it knows, and members
+    /// that joined or moved tokens while the registry was down are taken from the answers.
+    /// Nodes that do not answer are kept and probed again until they do, as long as the
+    /// nodes that answer still list them as active.
+    pub fn reconcile(&self) {
+        let manager = self.manager.clone();
+        tokio::spawn(async move { reconcile(manager).await });
+    }
+}
+
+async fn reconcile(manager: Arc<Manager>) {
+    let known = match manager.get_nodes() {
+        Ok(known) => known,
+        Err(err) => {
+            warn!("Listing the registered nodes failed: {}", err);
+            return;
+        }
+    };
+    if known.is_empty() {
+        return;
+    }
+    info!(
+        "Probing the {} nodes registered before the restart...",
+        known.len()
+    );
+
+    let mut pending: Vec<String> = known.into_iter().map(|node| node.addr).collect();
+    let mut answered: Vec<String> = Vec::new();
+    loop {
+        // The nodes that answered are asked again, for the members they now see active.
+        let mut probed = HashSet::new();
+        let mut probes = JoinSet::new();
+        for addr in pending.drain(..).chain(answered.drain(..)) {
+            if probed.insert(addr.clone()) {
+                probes.spawn(async move { (probe(&addr).await, addr) });
+            }
+        }
+
+        let mut unreachable = HashSet::new();
+        let mut active = HashSet::new();
+        while let Some(joined) = probes.join_next().await {
+            let (answer, addr) = match joined {
+                Ok(joined) => joined,
+                Err(err) => {
+                    warn!("Probing a node failed: {}", err);
+                    continue;
+                }
+            };
+            let (members, cluster) = match answer {
+                Ok(answer) => answer,
+                Err(err) => {
+                    warn!("Probing {} failed: {}", addr, err);
+                    unreachable.insert(addr);
+                    continue;
+                }
+            };
+            if let Err(err) = manager.check_cluster(&cluster) {
+                warn!("Dropping node on {}: {}", addr, err);
+                if let Err(err) = manager.remove_node(&addr) {
+                    warn!("Dropping node on {} failed: {}", addr, err);
+                }
+                continue;
+            }
+
+            // The members listed are those alive or suspected, the node itself included.
+            for member in members {
+                active.insert(member.addr.clone());
+                if member.addr == addr {
+                    let node = match decode_member(&member) {
+                        Ok(node) => node,
+                        Err(err) => {
+                            warn!("Node on {} answered an invalid member: {}", addr, err);
+                            continue;
+                        }
+                    };
+                    if let Err(err) = manager.update_node(node) {
+                        warn!("Updating node on {} failed: {}", addr, err);
+                    }
+                } else if probed.insert(member.addr.clone()) {
+                    let addr = member.addr;
+                    probes.spawn(async move { (probe(&addr).await, addr) });
+                }
+            }
+            answered.push(addr);
+        }
+
+        let registered: HashSet<String> = match manager.get_nodes() {
+            Ok(nodes) => nodes.into_iter().map(|node| node.addr).collect(),
+            Err(err) => {
+                warn!("Listing the registered nodes failed: {}", err);
+                return;
+            }
+        };
+        // Registered nodes that did not answer are dropped once the nodes that did no longer
+        // list them, as when they left or died while the registry was down. Without any
+        // answer, all of them are kept.
+        for addr in unreachable.iter().filter(|addr| registered.contains(*addr)) {
+            if !answered.is_empty() && !active.contains(addr) {
+                info!("Dropping node on {}, which left the network", addr);
+                if let Err(err) = manager.remove_node(addr) {
+                    warn!("Dropping node on {} failed: {}", addr, err);
+                }
+            }
+        }
+        // Only the nodes still registered are probed again: others were dropped, or were
+        // never registered and were only named by the nodes that answered.
+        pending = unreachable
+            .into_iter()
+            .filter(|addr| registered.contains(addr))
+            .filter(|addr| answered.is_empty() || active.contains(addr))
+            .collect();
+        if pending.is_empty() {
+            info!("All registered nodes were found in the network");
+            return;
+        }
+        info!("{} registered nodes did not answer yet", pending.len());
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+fn decode_member(member: &Member) -> Result<NodeInfo> {
+    Ok(NodeInfo {
+        id: decode_id(&member.id)?,
+        addr: member.addr.clone(),
+        tokens: decode_ids(&member.tokens)?,
+        capacity: member.capacity,
+    })
+}
+
+/// Asks a node for the members it knows, itself included, and the cluster's settings.
+async fn probe(addr: &str) -> Result<(Vec<Member>, ClusterInfo)> {
+    let request = async {
+        let mut client = DhtNodeClient::connect(addr.to_owned()).await?;
+        let members = client.get_members(Request::new(())).await?.into_inner();
+        let cluster = client
+            .get_cluster_info(Request::new(()))
+            .await?
+            .into_inner();
+        Ok((members.members, cluster))
+    };
+    match tokio::time::timeout(PROBE_TIMEOUT, request).await {
+        Ok(answer) => answer,
+        Err(_) => Err(Error::Internal(format!("Probing {} timed out.", addr))),
+    }
 }
 
 #[tonic::async_trait]
@@ -29,15 +196,61 @@ impl Registry for RegistryService {
         info!("Received join request from address {}", conn_addr);
 
         info!("Registering node on address {}", conn_addr);
-        let id = self.manager.register_node(conn_addr.to_owned())?;
-        info!("Registered {} as #{:x}", conn_addr, id);
+        // Nodes that do not declare a capacity get the default one.
+        let capacity = if request.get_ref().capacity == 0.0 {
+            DEFAULT_CAPACITY
+        } else {
+            request.get_ref().capacity
+        };
+        let cluster = request
+            .get_ref()
+            .cluster
+            .as_ref()
+            .ok_or(Error::Value("Cluster settings not provided.".into()))?;
+        self.manager.check_cluster(cluster)?;
+
+        let id = request.get_ref().id.as_deref().map(decode_id).transpose()?;
+        let node = self.manager.register_node(
+            conn_addr.to_owned(),
+            id,
+            request.get_ref().tokens,
+            capacity,
+            decode_ids(&request.get_ref().held_tokens)?,
+        )?;
+        info!(
+            "Registered {} as #{:x} with capacity {} and {} tokens",
+            conn_addr,
+            node.id,
+            node.capacity,
+            node.tokens.len()
+        );
+
+        let neighbor = self
+            .manager
+            .find_closest_neighbor(node.id)?
+            .map(|node| Node {
+                id: encode_id(node.id),
+                addr: node.addr,
+            });
+
+        Ok(Response::new(RegisterInfo {
+            id: encode_id(node.id),
+            neighbor,
+            tokens: encode_ids(&node.tokens),
+        }))
+    }
 
-        let neighbor = self.manager.find_closest_neighbor(id)?.map(|node| Node {
-            id: node.id,
-            addr: node.addr,
-        });
+    async fn deregister_node(
+        &self,
+        request: Request<Node>,
+    ) -> std::result::Result<Response<()>, Status> {
+        let node = request.get_ref();
+        let id = decode_id(&node.id)?;
+        info!("Deregistering #{:x} on address {}", id, node.addr);
+        self.manager.deregister_node(id)?;
+        info!("Deregistered #{:x}", id);
 
-        Ok(Response::new(RegisterInfo { id, neighbor }))
+        Ok(Response::new(()))
     }
 
     async fn get_connected_nodes(
@@ -49,7 +262,7 @@ impl Registry for RegistryService {
             .get_nodes()?
             .iter()
             .map(|node| Node {
-                id: node.id,
+                id: encode_id(node.id),
                 addr: node.addr.clone(),
             })
             .collect();
@@ -57,3 +270,75 @@ impl Registry for RegistryService {
         Ok(Response::new(Nodes { nodes }))
     }
 }
+
+#[tokio::test]
+async fn test_reconcile_keeps_active_nodes() -> Result<()> {
+    use crate::dht::config::NodeConfig;
+    use crate::dht::service::DhtNodeService;
+    use crate::rpc::dht::Gossip;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let addr = format!("http://127.0.0.1:{}", port);
+    let node = DhtNodeService::join(addr.clone(), &[], NodeConfig::default()).await?;
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(crate::rpc::dht::dht_node_server::DhtNodeServer::new(node))
+            .serve(format!("127.0.0.1:{}", port).parse()?),
+    );
+    let tokens = loop {
+        if let Ok((members, _)) = probe(&addr).await {
+            break decode_ids(&members[0].tokens)?;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+
+    // The node sees a member on an address nothing listens on as active, until it fails
+    // to ping it.
+    let unreachable = "http://127.0.0.1:1".to_owned();
+    let member = Member {
+        id: encode_id(2),
+        addr: unreachable.clone(),
+        tokens: encode_ids(&[2]),
+        capacity: 1.0,
+        ..Member::default()
+    };
+    let gossip = Gossip {
+        sender: Some(member),
+        updates: Vec::new(),
+    };
+    DhtNodeClient::connect(addr.clone())
+        .await?
+        .ping(Request::new(gossip))
+        .await?;
+
+    // Registered before a restart: the node with a token it moved since, the member it
+    // sees active and one on another address nothing listens on, gone since.
+    let service = RegistryService::new();
+    service
+        .manager
+        .register_node(addr.clone(), None, 1, 1.0, vec![1])?;
+    service
+        .manager
+        .register_node(unreachable.clone(), Some(2), 1, 1.0, vec![2])?;
+    let gone = "http://127.0.0.1:2".to_owned();
+    service
+        .manager
+        .register_node(gone.clone(), Some(3), 1, 1.0, vec![3])?;
+
+    service.reconcile();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let nodes = service.manager.get_nodes()?;
+        if nodes.iter().all(|n| n.addr != gone && n.tokens != [1]) {
+            break;
+        }
+        assert!(tokio::time::Instant::now() < deadline);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let nodes = service.manager.get_nodes()?;
+    assert!(nodes.iter().any(|n| n.addr == addr && n.tokens == tokens));
+    assert!(nodes.iter().any(|n| n.addr == unreachable));
+    Ok(())
+}